@@ -36,14 +36,35 @@
 //! - prefix scan with anchor to retrieve entries by anchor
 //! - prefix scan with (anchor, timestamp) to narrow down on the time period for a specific anchor
 //! - prefix scan with (anchor, timestamp, log index) to do pagination (with the key of the first entry not included in the previous set)
-use candid::{candid_method, CandidType, Deserialize, Principal};
+//!
+//! ### Pruning
+//! If a retention period is configured, expired entries are pruned periodically. Because the [Log]
+//! is append-only, the entries to keep are copied to a second set of memories (the other log
+//! generation) in multiple steps (see [PruningJob]). Once the copy has caught up with the active
+//! log, the generations are swapped and the anchor index rows referring to pruned entries are
+//! removed. Log indices are stable across pruning: the log keeps track of the index of its first
+//! entry.
+//!
+//! To keep the cost of copying proportional to the number of pruned entries, the log is only pruned
+//! once at least as many entries have expired as there are entries to keep (see
+//! [should_prune]).
+//!
+//! *Note:* the memory of the previous generation is not released (the memory manager does not support
+//! that), it is reused on the next pruning. Since at most half of the log is copied, the memory used by
+//! the log is bounded by twice the size of the largest log.
+//!
+//! ## Export
+//! The log can be downloaded in bulk on `/export?from=<index>&to=<index>&format=<jsonl|cbor>`. The
+//! response is split into chunks of `max_entries_per_call` entries which are streamed using the
+//! HTTP gateway streaming callback mechanism (see [http_request_streaming_callback]).
+use candid::{candid_method, CandidType, Deserialize, Func, Principal};
 use ic_cdk::api::call::CallResult;
 use ic_cdk::api::management_canister::main::{canister_status, CanisterIdRecord};
 use ic_cdk::api::stable::stable64_size;
-use ic_cdk::api::time;
+use ic_cdk::api::{performance_counter, time};
 use ic_cdk::{call, caller, id, print, trap};
 use ic_cdk_macros::{init, post_upgrade, query, update};
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk_timers::{set_timer, set_timer_interval};
 use ic_metrics_encoder::MetricsEncoder;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{
@@ -51,11 +72,13 @@ use ic_stable_structures::{
     RestrictedMemory, StableBTreeMap, Storable,
 };
 use internet_identity_interface::archive::types::*;
-use internet_identity_interface::http_gateway::{HttpRequest, HttpResponse};
+use internet_identity_interface::http_gateway::{
+    HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingStrategy,
+};
 use internet_identity_interface::internet_identity::types::*;
 use serde_bytes::ByteBuf;
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::time::Duration;
 
 #[cfg(test)]
@@ -80,6 +103,17 @@ const MAX_WASM_PAGES: u64 = MAX_STABLE_MEMORY_SIZE / WASM_PAGE_SIZE;
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
 const ANCHOR_ACCESS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+/// Memory ids of the second log generation (see [log_memory_ids]).
+const LOG_INDEX_GEN_1_MEMORY_ID: MemoryId = MemoryId::new(3);
+const LOG_DATA_GEN_1_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+/// Interval at which expired entries are pruned (if a retention period is configured).
+const PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Number of instructions after which a maintenance step (pruning, index check) yields to other
+/// messages.
+const MAINTENANCE_STEP_INSTRUCTIONS: u64 = 1_000_000_000;
+/// Number of anchor index rows processed between instruction checks when scanning the index.
+const INDEX_SCAN_BATCH_SIZE: usize = 100;
 
 thread_local! {
     /// Static configuration of the archive set by init() or post_upgrade().
@@ -90,9 +124,7 @@ thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<Memory>> = RefCell::new(MemoryManager::init_with_bucket_size(managed_memory(), 1024));
 
     /// Append-only list of candid encoded entries stored in stable memory.
-    static LOG: RefCell<StableLog> = with_memory_manager(|memory_manager| {
-        RefCell::new(Log::init(memory_manager.get(LOG_INDEX_MEMORY_ID), memory_manager.get(LOG_DATA_MEMORY_ID)).expect("failed to initialize stable log"))
-    });
+    static LOG: RefCell<StableLog> = RefCell::new(init_log(log_state().generation));
 
     /// Index to efficiently retrieve entries by anchor.
     static ANCHOR_INDEX: RefCell<AnchorIndex> = with_memory_manager(|memory_manager| {
@@ -101,6 +133,13 @@ thread_local! {
 
     /// Information about the calls the archive is making to II. Not persistent in stable memory.
     static CALL_INFO: RefCell<CallInfo> = RefCell::new(CallInfo::default());
+
+    /// Metrics about the entries written since the last upgrade. Not persistent in stable memory.
+    static ENTRY_METRICS: RefCell<EntryMetrics> = RefCell::new(EntryMetrics::default());
+
+    /// Whether the steps of a pruning job are currently scheduled. Not persistent in stable memory
+    /// (neither are the timers).
+    static PRUNING_SCHEDULED: Cell<bool> = Cell::new(false);
 }
 
 /// Reserve the first stable memory page for the configuration stable cell.
@@ -118,6 +157,38 @@ fn with_config<R>(f: impl FnOnce(&ArchiveConfig) -> R) -> R {
     CONFIG.with(|cell| f(cell.borrow().get().get()))
 }
 
+/// Memory ids (index, data) of the memories holding the log of the given generation.
+fn log_memory_ids(generation: u8) -> (MemoryId, MemoryId) {
+    match generation {
+        0 => (LOG_INDEX_MEMORY_ID, LOG_DATA_MEMORY_ID),
+        1 => (LOG_INDEX_GEN_1_MEMORY_ID, LOG_DATA_GEN_1_MEMORY_ID),
+        _ => trap(&format!("invalid log generation {generation}")),
+    }
+}
+
+/// Loads the log of the given generation from stable memory.
+fn init_log(generation: u8) -> StableLog {
+    let (index_memory_id, data_memory_id) = log_memory_ids(generation);
+    with_memory_manager(|memory_manager| {
+        Log::init(
+            memory_manager.get(index_memory_id),
+            memory_manager.get(data_memory_id),
+        )
+        .expect("failed to initialize stable log")
+    })
+}
+
+/// Creates a new empty log in the memories of the given generation (overwriting existing data).
+fn new_log(generation: u8) -> StableLog {
+    let (index_memory_id, data_memory_id) = log_memory_ids(generation);
+    with_memory_manager(|memory_manager| {
+        Log::new(
+            memory_manager.get(index_memory_id),
+            memory_manager.get(data_memory_id),
+        )
+    })
+}
+
 /// A helper function to access the memory manager.
 fn with_memory_manager<R>(f: impl FnOnce(&MemoryManager<Memory>) -> R) -> R {
     MEMORY_MANAGER.with(|cell| f(&cell.borrow()))
@@ -143,6 +214,65 @@ fn with_call_info_mut<R>(f: impl FnOnce(&mut CallInfo) -> R) -> R {
     CALL_INFO.with(|cell| f(&mut cell.borrow_mut()))
 }
 
+/// A helper function to mutate the entry metrics.
+fn with_entry_metrics_mut<R>(f: impl FnOnce(&mut EntryMetrics) -> R) -> R {
+    ENTRY_METRICS.with(|cell| f(&mut cell.borrow_mut()))
+}
+
+/// Upper bounds (in seconds) of the buckets of the archive lag histogram.
+const ARCHIVE_LAG_BUCKETS_SECONDS: [u64; 9] = [1, 5, 10, 30, 60, 300, 900, 3600, 86400];
+
+/// Metrics about the entries written to the archive.
+#[derive(Default)]
+struct EntryMetrics {
+    /// Number of written entries per operation type.
+    register_anchor: u64,
+    add_device: u64,
+    update_device: u64,
+    replace_device: u64,
+    remove_device: u64,
+    reject_tentative_device: u64,
+    import_anchor: u64,
+    register_anchor_with_temp_key: u64,
+    release_anchor: u64,
+    /// Number of written entries that could not be decoded.
+    unknown: u64,
+    /// Number of entries per lag bucket (non-cumulative), the last bucket counts all entries
+    /// exceeding the highest bound.
+    /// The lag is the time between the entry being created on II and it being written to the archive.
+    archive_lag_buckets: [u64; ARCHIVE_LAG_BUCKETS_SECONDS.len() + 1],
+    /// Sum of the lag of all entries (in seconds).
+    archive_lag_sum_seconds: f64,
+}
+
+impl EntryMetrics {
+    fn record(&mut self, entry: &[u8], lag_ns: u64) {
+        match candid::decode_one::<Entry>(entry) {
+            Ok(entry) => match entry.operation {
+                Operation::RegisterAnchor { .. } => self.register_anchor += 1,
+                Operation::AddDevice { .. } => self.add_device += 1,
+                Operation::UpdateDevice { .. } => self.update_device += 1,
+                Operation::ReplaceDevice { .. } => self.replace_device += 1,
+                Operation::RemoveDevice { .. } => self.remove_device += 1,
+                Operation::RejectTentativeDevice { .. } => self.reject_tentative_device += 1,
+                Operation::ImportAnchor { .. } => self.import_anchor += 1,
+                Operation::RegisterAnchorWithTempKey => self.register_anchor_with_temp_key += 1,
+                Operation::ReleaseAnchor => self.release_anchor += 1,
+            },
+            // the archive must accept entries regardless of their schema (see write_entry)
+            Err(_) => self.unknown += 1,
+        }
+
+        let lag = Duration::from_nanos(lag_ns);
+        let bucket = ARCHIVE_LAG_BUCKETS_SECONDS
+            .iter()
+            .position(|bound| lag <= Duration::from_secs(*bound))
+            .unwrap_or(ARCHIVE_LAG_BUCKETS_SECONDS.len());
+        self.archive_lag_buckets[bucket] += 1;
+        self.archive_lag_sum_seconds += lag.as_secs_f64();
+    }
+}
+
 /// Configuration state of the archive.
 enum ConfigState {
     Uninitialized, // This state is only used between wasm module initialization and init().
@@ -173,6 +303,74 @@ struct ArchiveConfig {
     error_buffer_limit: Option<u16>,
     /// Highest sequence number of any entry that was archived.
     highest_sequence_number: Option<u64>,
+    /// Whether entries are pulled from II or pushed by II.
+    archive_integration: Option<ArchiveIntegration>,
+    /// Entries older than this are pruned (in nanoseconds). Entries are kept forever if not set.
+    retention_period_ns: Option<u64>,
+    /// State of the log with regard to pruning. Not set if the log has never been pruned.
+    log_state: Option<LogState>,
+    /// State of the last anchor index check. Not set if the index has never been checked.
+    index_check: Option<IndexCheck>,
+}
+
+/// State of the log with regard to pruning.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct LogState {
+    /// Generation of the memories holding the active log (see [log_memory_ids]).
+    generation: u8,
+    /// Index of the first entry of the active log, i.e. the number of entries pruned so far.
+    first_index: LogIndex,
+    /// Timestamp before which entries have been pruned by the last completed pruning job, if any.
+    cutoff: Option<Timestamp>,
+    /// The pruning job in progress, if any.
+    pruning: Option<PruningJob>,
+}
+
+/// State of an anchor index check (see [verify_and_rebuild_index]).
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct IndexCheck {
+    report: IndexCheckReport,
+    /// The remaining work, if the check is still in progress.
+    job: Option<IndexCheckJob>,
+}
+
+/// Job to check the anchor index against the log. The job is executed in multiple steps
+/// (see [index_check_step]) and moves through the following phases.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum IndexCheckJob {
+    /// Checking that every (decodable) log entry has an index row, adding the missing ones.
+    CheckLog { next_index: LogIndex },
+    /// Checking that every index row refers to a matching log entry, removing the others.
+    CheckIndex {
+        /// Serialized [AnchorIndexKey] of the next row to check, if any.
+        next_key: Option<ByteBuf>,
+    },
+}
+
+/// Job to prune the entries created before `cutoff`. The job is executed in multiple steps
+/// (see [prune_step]) and moves through the following phases.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum PruningJob {
+    /// Looking for the first entry to keep. Entries that cannot be decoded are only pruned if they
+    /// are followed by an expired entry.
+    Scan {
+        cutoff: Timestamp,
+        next_index: LogIndex,
+        /// Index following the last expired entry found so far.
+        prune_until: LogIndex,
+    },
+    /// Copying the entries to keep to the log of the inactive generation.
+    Copy {
+        cutoff: Timestamp,
+        /// Index of the first entry to keep.
+        first_kept: LogIndex,
+        next_index: LogIndex,
+    },
+    /// Removing the anchor index rows referring to pruned entries.
+    CleanIndex {
+        /// Serialized [AnchorIndexKey] of the next row to check, if any.
+        next_key: Option<ByteBuf>,
+    },
 }
 
 impl Storable for ConfigState {
@@ -255,6 +453,22 @@ fn write_entry(anchor_number: AnchorNumber, timestamp: Timestamp, entry: ByteBuf
     write_entry_internal(anchor_number, timestamp, entry)
 }
 
+/// Archives a batch of entries pushed by II (if II is configured to push entries).
+/// Entries that have already been archived (i.e. on retries by II) are skipped.
+#[update]
+#[candid_method]
+fn write_entries(entries: Vec<BufferedEntry>) {
+    with_config(|config| {
+        if config.ii_canister != caller() {
+            trap(&format!(
+                "Only {} is allowed to write entries.",
+                config.ii_canister
+            ))
+        }
+    });
+    archive_entries(entries);
+}
+
 /// Fetches, archives and acknowledges a batch of entries.
 /// *Note:* Must be written in a way that nothing breaks on overlapping executions of [fetch_entries].
 async fn fetch_entries() {
@@ -295,35 +509,9 @@ async fn fetch_entries() {
     }
 
     let entries_count = entries.len();
-    let lowest_seq_nr = entries.first().unwrap().sequence_number;
     let highest_seq_nr = entries.last().unwrap().sequence_number;
-    // For the very first fetch the sequence number is not known thus we default on the one we get back from II.
-    let expected_seq_nr = highest_archived_sequence_number()
-        .map(|seq_nr| seq_nr + 1)
-        .unwrap_or(lowest_seq_nr);
-
-    if lowest_seq_nr > expected_seq_nr {
-        // Unfortunately there is nothing further we can do as the missing entries have already been
-        // pruned on the II side.
-        print(format!(
-            "Gap in archive entries: entries {} to {} were never archived!",
-            expected_seq_nr,
-            lowest_seq_nr - 1
-        ))
-    }
-
-    // If this condition is false, all entries have already been archived by another invocation of fetch_entries.
-    // This can happen if the fetch interval is too short or on call failures, e.g. if the last acknowledge message got rejected.
-    // In such cases just the acknowledge is sent again.
-    if highest_seq_nr >= expected_seq_nr {
-        entries
-            .into_iter()
-            // due to the overlapping calls, also just parts of the entries could already have been archived
-            // --> filter those out
-            .filter(|e| e.sequence_number >= expected_seq_nr)
-            .for_each(|e| write_entry_internal(e.anchor_number, e.timestamp, e.entry));
-        set_highest_archived_sequence_number(highest_seq_nr);
-    }
+    // If all entries have already been archived, just the acknowledge is sent again.
+    archive_entries(entries);
 
     let call_time = time();
     let result: CallResult<()> =
@@ -352,10 +540,52 @@ async fn fetch_entries() {
     };
 }
 
+/// Archives the given entries (sorted by sequence number) skipping those that have already been archived.
+fn archive_entries(entries: Vec<BufferedEntry>) {
+    let (Some(lowest), Some(highest)) = (entries.first(), entries.last()) else {
+        // nothing to archive
+        return;
+    };
+    let lowest_seq_nr = lowest.sequence_number;
+    let highest_seq_nr = highest.sequence_number;
+    // For the very first batch the sequence number is not known thus we default on the one we get from II.
+    let expected_seq_nr = highest_archived_sequence_number()
+        .map(|seq_nr| seq_nr + 1)
+        .unwrap_or(lowest_seq_nr);
+
+    if lowest_seq_nr > expected_seq_nr {
+        // Unfortunately there is nothing further we can do as the missing entries have already been
+        // pruned on the II side.
+        print(format!(
+            "Gap in archive entries: entries {} to {} were never archived!",
+            expected_seq_nr,
+            lowest_seq_nr - 1
+        ))
+    }
+
+    // If this condition is false, all entries have already been archived by a previous invocation.
+    // This can happen if the fetch interval is too short or on call failures, e.g. if the last acknowledge
+    // message got rejected or II did not receive the reply to a push.
+    if highest_seq_nr >= expected_seq_nr {
+        entries
+            .into_iter()
+            // due to the overlapping calls, also just parts of the entries could already have been archived
+            // --> filter those out
+            .filter(|e| e.sequence_number >= expected_seq_nr)
+            .for_each(|e| write_entry_internal(e.anchor_number, e.timestamp, e.entry));
+        set_highest_archived_sequence_number(highest_seq_nr);
+    }
+}
+
 fn write_entry_internal(anchor: AnchorNumber, timestamp: Timestamp, entry: ByteBuf) {
+    with_entry_metrics_mut(|metrics| metrics.record(&entry, time().saturating_sub(timestamp)));
+
+    let first_index = log_state().first_index;
     let idx = with_log(|log| {
-        log.append(&entry.into_vec())
-            .expect("failed to append log entry")
+        first_index
+            + log
+                .append(&entry.into_vec())
+                .expect("failed to append log entry")
     });
 
     with_anchor_index_mut(|index| {
@@ -385,16 +615,19 @@ fn store_call_error(call_error: CallErrorInfo) {
 fn get_entries(index: Option<u64>, limit: Option<u16>) -> Entries {
     let limit = limit_or_default(limit);
 
+    let first_index = log_state().first_index;
     with_log(|log| {
-        let length = log.len();
+        let end_idx = first_index + log.len();
+        // pruned entries are skipped
         let start_idx = match index {
-            None => length.saturating_sub(limit as u64),
+            None => end_idx.saturating_sub(limit as u64),
             Some(idx) => idx,
-        };
+        }
+        .max(first_index);
 
         let mut entries = Vec::with_capacity(limit);
         for idx in start_idx..start_idx + limit as u64 {
-            let entry = match log.get(idx) {
+            let entry = match log.get(idx - first_index) {
                 None => break,
                 Some(entry) => entry,
             };
@@ -457,15 +690,18 @@ fn get_anchor_entries(
             timestamp: 0,
             log_index: 0,
         };
+        let first_index = log_state().first_index;
         with_log(|log| {
             // Take one too many from the iterator to extract the cursor. This avoids having to
             // iterate twice or use next explicitly.
             let mut entries: Vec<(AnchorIndexKey, Vec<u8>)> = index
                 .range(start_key..end_key)
+                // skip the rows of pruned entries that have not been cleaned up yet
+                .filter(|(anchor_key, _)| anchor_key.log_index >= first_index)
                 .take(limit + 1)
                 .map(|(anchor_key, _)| {
                     let entry = log
-                        .get(anchor_key.log_index)
+                        .get(anchor_key.log_index - first_index)
                         .expect("bug: index to non-existing entry");
                     (anchor_key, entry)
                 })
@@ -521,11 +757,349 @@ fn initialize(arg: ArchiveInit) {
         polling_interval_ns: Some(arg.polling_interval_ns),
         error_buffer_limit: Some(arg.error_buffer_limit),
         highest_sequence_number: highest_archived_sequence_number(),
+        archive_integration: arg.archive_integration.clone(),
+        retention_period_ns: arg.retention_period_ns,
+        log_state: Some(log_state()),
+        index_check: index_check(),
     });
 
-    set_timer_interval(Duration::from_nanos(arg.polling_interval_ns), || {
-        ic_cdk::spawn(fetch_entries())
+    // In push mode II sends the entries on its own, so there is nothing to poll.
+    if arg.archive_integration != Some(ArchiveIntegration::Push) {
+        set_timer_interval(Duration::from_nanos(arg.polling_interval_ns), || {
+            ic_cdk::spawn(fetch_entries())
+        });
+    }
+
+    if arg.retention_period_ns.is_some() {
+        set_timer_interval(PRUNING_INTERVAL, start_pruning);
+    }
+
+    // resume the index check interrupted by the upgrade (if any)
+    if matches!(index_check(), Some(IndexCheck { job: Some(_), .. })) {
+        set_timer(Duration::ZERO, index_check_step);
+    }
+}
+
+/// Returns the state of the log (the default state if the log has never been pruned).
+fn log_state() -> LogState {
+    CONFIG.with(|config| match config.borrow().get() {
+        ConfigState::Uninitialized => LogState::default(),
+        ConfigState::Initialized(config) => config.log_state.clone().unwrap_or_default(),
+    })
+}
+
+fn set_log_state(log_state: LogState) {
+    // stable cell does not allow modifying values in place --> copy and swap
+    let mut config = with_config(|config| config.clone());
+    config.log_state = Some(log_state);
+    write_config(config);
+}
+
+/// Starts a job to prune the expired entries, unless the previous job is still running.
+/// If a job has been interrupted by an upgrade, it is resumed instead.
+fn start_pruning() {
+    let Some(retention_period_ns) = with_config(|config| config.retention_period_ns) else {
+        return;
+    };
+    if PRUNING_SCHEDULED.with(|scheduled| scheduled.replace(true)) {
+        return;
+    }
+
+    let mut state = log_state();
+    if state.pruning.is_none() {
+        state.pruning = Some(PruningJob::Scan {
+            cutoff: time().saturating_sub(retention_period_ns),
+            next_index: state.first_index,
+            prune_until: state.first_index,
+        });
+        set_log_state(state);
+    }
+    prune_step();
+}
+
+/// Advances the current pruning job until it is complete or the instruction limit is reached.
+/// In the latter case, the next step is scheduled.
+fn prune_step() {
+    let mut state = log_state();
+    while let Some(job) = state.pruning.take() {
+        state.pruning = advance_pruning_job(&mut state, job);
+        if step_instruction_limit_reached() {
+            break;
+        }
+    }
+
+    let complete = state.pruning.is_none();
+    set_log_state(state);
+    if complete {
+        PRUNING_SCHEDULED.with(|scheduled| scheduled.set(false));
+    } else {
+        set_timer(Duration::ZERO, prune_step);
+    }
+}
+
+/// Executes the given phase of a pruning job (or parts of it, if the instruction limit is reached)
+/// and returns the remaining job, if any.
+fn advance_pruning_job(state: &mut LogState, job: PruningJob) -> Option<PruningJob> {
+    match job {
+        PruningJob::Scan {
+            cutoff,
+            mut next_index,
+            mut prune_until,
+        } => {
+            let end_index = with_log(|log| state.first_index + log.len());
+            while next_index < end_index {
+                if step_instruction_limit_reached() {
+                    return Some(PruningJob::Scan {
+                        cutoff,
+                        next_index,
+                        prune_until,
+                    });
+                }
+                let entry = with_log(|log| log.get(next_index - state.first_index))
+                    .and_then(|entry| candid::decode_one::<Entry>(&entry).ok());
+                match entry {
+                    Some(entry) if entry.timestamp >= cutoff => break,
+                    Some(_) => prune_until = next_index + 1,
+                    None => {}
+                }
+                next_index += 1;
+            }
+
+            if prune_until == state.first_index {
+                // nothing to prune
+                state.cutoff = Some(cutoff);
+                return None;
+            }
+            if !should_prune(prune_until - state.first_index, end_index - prune_until) {
+                // not worth copying the log yet, the expired entries are pruned on a later run
+                return None;
+            }
+            new_log(1 - state.generation);
+            Some(PruningJob::Copy {
+                cutoff,
+                first_kept: prune_until,
+                next_index: prune_until,
+            })
+        }
+        PruningJob::Copy {
+            cutoff,
+            first_kept,
+            mut next_index,
+        } => {
+            let target = init_log(1 - state.generation);
+            let end_index = with_log(|log| {
+                let end_index = state.first_index + log.len();
+                while next_index < end_index {
+                    if step_instruction_limit_reached() {
+                        break;
+                    }
+                    let entry = log
+                        .get(next_index - state.first_index)
+                        .expect("bug: failed to read log entry");
+                    target.append(&entry).expect("failed to append log entry");
+                    next_index += 1;
+                }
+                end_index
+            });
+            if next_index < end_index {
+                return Some(PruningJob::Copy {
+                    cutoff,
+                    first_kept,
+                    next_index,
+                });
+            }
+
+            // the copy has caught up with the active log --> swap
+            LOG.with(|cell| *cell.borrow_mut() = target);
+            state.generation = 1 - state.generation;
+            state.first_index = first_kept;
+            state.cutoff = Some(cutoff);
+            Some(PruningJob::CleanIndex { next_key: None })
+        }
+        PruningJob::CleanIndex { next_key } => {
+            let first_index = state.first_index;
+            retain_index_rows(next_key, |key| key.log_index >= first_index).map(|next_key| {
+                PruningJob::CleanIndex {
+                    next_key: Some(next_key),
+                }
+            })
+        }
+    }
+}
+
+/// Returns whether the log should be pruned, given the number of expired and of kept entries.
+fn should_prune(expired_entries: u64, kept_entries: u64) -> bool {
+    expired_entries >= kept_entries
+}
+
+/// Removes the anchor index rows not satisfying `keep`, starting at the serialized [AnchorIndexKey]
+/// `start_key` (or the beginning of the index). Returns the serialized key to continue from, if the
+/// instruction limit was reached before the end of the index.
+fn retain_index_rows(
+    start_key: Option<ByteBuf>,
+    mut keep: impl FnMut(&AnchorIndexKey) -> bool,
+) -> Option<ByteBuf> {
+    let mut next_key = start_key
+        .map(|key| AnchorIndexKey::from_bytes(Cow::Owned(key.into_vec())))
+        .unwrap_or(AnchorIndexKey {
+            anchor: 0,
+            timestamp: 0,
+            log_index: 0,
+        });
+    with_anchor_index_mut(|index| loop {
+        // Take one too many to know where to continue.
+        let mut keys: Vec<AnchorIndexKey> = index
+            .range(next_key.clone()..)
+            .take(INDEX_SCAN_BATCH_SIZE + 1)
+            .map(|(key, _)| key)
+            .collect();
+        let continuation = if keys.len() > INDEX_SCAN_BATCH_SIZE {
+            keys.pop()
+        } else {
+            None
+        };
+        for key in keys {
+            if !keep(&key) {
+                index.remove(&key);
+            }
+        }
+
+        let Some(continuation) = continuation else {
+            // all rows have been checked
+            return None;
+        };
+        next_key = continuation;
+        if step_instruction_limit_reached() {
+            return Some(ByteBuf::from(next_key.to_bytes().into_owned()));
+        }
+    })
+}
+
+/// Starts a check of the anchor index against the log (see [IndexCheckJob]). The check is executed
+/// in the background, the result is reported in the [status].
+#[update]
+#[candid_method]
+async fn verify_and_rebuild_index() {
+    trap_if_not_controller().await;
+    if matches!(index_check(), Some(IndexCheck { job: Some(_), .. })) {
+        trap("An index check is already in progress.");
+    }
+
+    set_index_check(IndexCheck {
+        report: IndexCheckReport {
+            started: time(),
+            ..IndexCheckReport::default()
+        },
+        job: Some(IndexCheckJob::CheckLog {
+            next_index: log_state().first_index,
+        }),
     });
+    set_timer(Duration::ZERO, index_check_step);
+}
+
+async fn trap_if_not_controller() {
+    let caller = caller();
+    let (status,) = canister_status(CanisterIdRecord { canister_id: id() })
+        .await
+        .unwrap_or_else(|(code, message)| {
+            trap(&format!(
+                "failed to retrieve controllers: {code:?}, {message}"
+            ))
+        });
+    if !status.settings.controllers.contains(&caller) {
+        trap(&format!("{caller} is not a controller of the archive."))
+    }
+}
+
+fn index_check() -> Option<IndexCheck> {
+    CONFIG.with(|config| match config.borrow().get() {
+        ConfigState::Uninitialized => None,
+        ConfigState::Initialized(config) => config.index_check.clone(),
+    })
+}
+
+fn set_index_check(index_check: IndexCheck) {
+    // stable cell does not allow modifying values in place --> copy and swap
+    let mut config = with_config(|config| config.clone());
+    config.index_check = Some(index_check);
+    write_config(config);
+}
+
+/// Advances the index check in progress until it is complete or the instruction limit is reached.
+/// In the latter case, the next step is scheduled.
+fn index_check_step() {
+    let Some(mut check) = index_check() else {
+        return;
+    };
+    while let Some(job) = check.job.take() {
+        check.job = advance_index_check(&mut check.report, job);
+        if step_instruction_limit_reached() {
+            break;
+        }
+    }
+
+    if check.job.is_none() {
+        check.report.completed = Some(time());
+    } else {
+        set_timer(Duration::ZERO, index_check_step);
+    }
+    set_index_check(check);
+}
+
+/// Executes the given phase of an index check (or parts of it, if the instruction limit is reached)
+/// and returns the remaining job, if any.
+fn advance_index_check(report: &mut IndexCheckReport, job: IndexCheckJob) -> Option<IndexCheckJob> {
+    // entries may have been pruned since the last step
+    let first_index = log_state().first_index;
+    match job {
+        IndexCheckJob::CheckLog { next_index } => {
+            let mut next_index = next_index.max(first_index);
+            let end_index = with_log(|log| first_index + log.len());
+            while next_index < end_index {
+                if step_instruction_limit_reached() {
+                    return Some(IndexCheckJob::CheckLog { next_index });
+                }
+                let entry = with_log(|log| log.get(next_index - first_index))
+                    .and_then(|entry| candid::decode_one::<Entry>(&entry).ok());
+                match entry {
+                    None => report.undecodable_entries += 1,
+                    Some(entry) => {
+                        let key = AnchorIndexKey {
+                            anchor: entry.anchor,
+                            timestamp: entry.timestamp,
+                            log_index: next_index,
+                        };
+                        if with_anchor_index_mut(|index| index.insert(key, ())).is_none() {
+                            report.missing_index_rows += 1;
+                        }
+                    }
+                }
+                next_index += 1;
+            }
+            Some(IndexCheckJob::CheckIndex { next_key: None })
+        }
+        IndexCheckJob::CheckIndex { next_key } => retain_index_rows(next_key, |key| {
+            let valid = key.log_index >= first_index
+                && with_log(|log| log.get(key.log_index - first_index))
+                    .map(|entry| match candid::decode_one::<Entry>(&entry) {
+                        Ok(entry) => entry.anchor == key.anchor && entry.timestamp == key.timestamp,
+                        // rows of undecodable entries cannot be verified
+                        Err(_) => true,
+                    })
+                    .unwrap_or(false);
+            if !valid {
+                report.dangling_index_rows += 1;
+            }
+            valid
+        })
+        .map(|next_key| IndexCheckJob::CheckIndex {
+            next_key: Some(next_key),
+        }),
+    }
+}
+
+fn step_instruction_limit_reached() -> bool {
+    performance_counter(0) > MAINTENANCE_STEP_INSTRUCTIONS
 }
 
 fn write_config(config: ArchiveConfig) {
@@ -538,7 +1112,7 @@ fn write_config(config: ArchiveConfig) {
 
 #[query]
 #[candid_method(query)]
-fn http_request(req: HttpRequest) -> HttpResponse {
+fn http_request(req: HttpRequest) -> HttpResponse<ExportToken> {
     let parts: Vec<&str> = req.url.split('?').collect();
     match parts[0] {
         "/metrics" => {
@@ -567,6 +1141,16 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                 },
             }
         }
+        "/export" => match parse_export_request(parts.get(1).copied().unwrap_or_default()) {
+            Ok(token) => export_response(token),
+            Err(err) => HttpResponse {
+                status_code: 400,
+                headers: vec![],
+                body: ByteBuf::from(err),
+                upgrade: None,
+                streaming_strategy: None,
+            },
+        },
         path => HttpResponse {
             status_code: 404,
             headers: vec![],
@@ -577,6 +1161,117 @@ fn http_request(req: HttpRequest) -> HttpResponse {
     }
 }
 
+/// Returns the next chunk of an export started on `/export`.
+#[query]
+#[candid_method(query)]
+fn http_request_streaming_callback(
+    token: ExportToken,
+) -> StreamingCallbackHttpResponse<ExportToken> {
+    let (body, token) = export_chunk(token);
+    StreamingCallbackHttpResponse {
+        body: ByteBuf::from(body),
+        token,
+    }
+}
+
+/// Parses the query string of an export request, e.g. `from=10&to=20&format=cbor`.
+/// All parameters are optional: by default the whole log is exported as JSON Lines.
+/// The `to` index is exclusive.
+fn parse_export_request(query: &str) -> Result<ExportToken, String> {
+    let first_index = log_state().first_index;
+    let end_index = with_log(|log| first_index + log.len());
+    let mut token = ExportToken {
+        next_index: first_index,
+        end_index,
+        format: ExportFormat::JsonLines,
+    };
+
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match key {
+            "from" => {
+                token.next_index = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for parameter from: {value}"))?
+            }
+            "to" => {
+                let to: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for parameter to: {value}"))?;
+                token.end_index = to.min(end_index)
+            }
+            "format" => {
+                token.format = match value {
+                    "jsonl" => ExportFormat::JsonLines,
+                    "cbor" => ExportFormat::Cbor,
+                    _ => return Err(format!("Unsupported export format: {value}")),
+                }
+            }
+            _ => return Err(format!("Unknown parameter: {key}")),
+        }
+    }
+    Ok(token)
+}
+
+fn export_response(token: ExportToken) -> HttpResponse<ExportToken> {
+    let content_type = match token.format {
+        ExportFormat::JsonLines => "application/jsonl",
+        ExportFormat::Cbor => "application/cbor-seq",
+    };
+    let (body, next_token) = export_chunk(token);
+    HttpResponse {
+        status_code: 200,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body: ByteBuf::from(body),
+        upgrade: None,
+        streaming_strategy: next_token.map(|token| StreamingStrategy::Callback {
+            callback: Func {
+                principal: id(),
+                method: "http_request_streaming_callback".to_string(),
+            },
+            token,
+        }),
+    }
+}
+
+/// Encodes the entries of the chunk starting at `token.next_index` and returns them together with
+/// the token for the next chunk, if the export is not complete yet.
+fn export_chunk(token: ExportToken) -> (Vec<u8>, Option<ExportToken>) {
+    let chunk_size = with_config(|config| config.max_entries_per_call) as u64;
+    let chunk_end = token
+        .end_index
+        .min(token.next_index.saturating_add(chunk_size));
+
+    let first_index = log_state().first_index;
+
+    let mut body = vec![];
+    with_log(|log| {
+        // entries pruned since the start of the export are skipped
+        for idx in token.next_index.max(first_index)..chunk_end {
+            // Entries that cannot be decoded (e.g. because they were written by a newer version of II)
+            // are exported as null rather than failing the whole export.
+            let entry: Option<Entry> = log
+                .get(idx - first_index)
+                .and_then(|entry| candid::decode_one(&entry).ok());
+            match token.format {
+                ExportFormat::JsonLines => {
+                    serde_json::to_writer(&mut body, &entry).expect("failed to encode entry");
+                    body.push(b'\n');
+                }
+                ExportFormat::Cbor => {
+                    serde_cbor::to_writer(&mut body, &entry).expect("failed to encode entry")
+                }
+            }
+        }
+    });
+
+    let next_token = (chunk_end < token.end_index).then(|| ExportToken {
+        next_index: chunk_end,
+        ..token
+    });
+    (body, next_token)
+}
+
 fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
     with_config(|config| {
         w.encode_gauge(
@@ -610,6 +1305,7 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             .unwrap()
             .value(&[("type", "index")], log.index_size_bytes() as f64)
     })?;
+    let (log_index_memory_id, log_data_memory_id) = log_memory_ids(log_state().generation);
     MEMORY_MANAGER.with(|cell| {
         let manager = cell.borrow();
         w.gauge_vec(
@@ -619,12 +1315,12 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         .unwrap()
         .value(
             &[("kind", "log_index")],
-            manager.get(LOG_INDEX_MEMORY_ID).size() as f64,
+            manager.get(log_index_memory_id).size() as f64,
         )
         .unwrap()
         .value(
             &[("kind", "log_data")],
-            manager.get(LOG_DATA_MEMORY_ID).size() as f64,
+            manager.get(log_data_memory_id).size() as f64,
         )
         .unwrap()
         .value(
@@ -632,6 +1328,42 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             manager.get(ANCHOR_ACCESS_INDEX_MEMORY_ID).size() as f64,
         )
     })?;
+    ENTRY_METRICS.with(|cell| {
+        let metrics = cell.borrow();
+        w.counter_vec(
+            "ii_archive_operation_count",
+            "Number of entries written since the last upgrade by operation type.",
+        )?
+        .value(
+            &[("operation", "register_anchor")],
+            metrics.register_anchor as f64,
+        )?
+        .value(&[("operation", "add_device")], metrics.add_device as f64)?
+        .value(&[("operation", "update_device")], metrics.update_device as f64)?
+        .value(&[("operation", "replace_device")], metrics.replace_device as f64)?
+        .value(&[("operation", "remove_device")], metrics.remove_device as f64)?
+        .value(
+            &[("operation", "reject_tentative_device")],
+            metrics.reject_tentative_device as f64,
+        )?
+        .value(&[("operation", "import_anchor")], metrics.import_anchor as f64)?
+        .value(
+            &[("operation", "register_anchor_with_temp_key")],
+            metrics.register_anchor_with_temp_key as f64,
+        )?
+        .value(&[("operation", "release_anchor")], metrics.release_anchor as f64)?
+        .value(&[("operation", "unknown")], metrics.unknown as f64)?;
+        w.encode_histogram(
+            "ii_archive_lag_seconds",
+            ARCHIVE_LAG_BUCKETS_SECONDS
+                .iter()
+                .map(|bound| *bound as f64)
+                .chain(std::iter::once(f64::INFINITY))
+                .zip(metrics.archive_lag_buckets.iter().map(|count| *count as f64)),
+            metrics.archive_lag_sum_seconds,
+            "Time between the creation of an entry on II and it being written to the archive since the last upgrade.",
+        )
+    })?;
     w.encode_gauge(
         "ii_archive_stable_memory_pages",
         stable64_size() as f64,
@@ -672,12 +1404,16 @@ async fn status() -> ArchiveStatus {
         // --> unwrap is safe to call
         polling_interval_ns: config.polling_interval_ns.unwrap(),
         error_buffer_limit: config.error_buffer_limit.unwrap(),
+        archive_integration: config.archive_integration.clone(),
+        retention_period_ns: config.retention_period_ns,
     });
     let call_info = with_call_info(|info| info.clone());
     ArchiveStatus {
         canister_status,
         call_info,
         init: config,
+        retention_cutoff: log_state().cutoff,
+        index_check: index_check().map(|check| check.report),
     }
 }
 
@@ -182,6 +182,64 @@ mod write_tests {
             Regex::new("Only [\\w-]+ is allowed to write entries\\.").unwrap(),
         );
     }
+
+    /// Verifies that batches of entries can be written to the canister and that entries already
+    /// archived are skipped.
+    #[test]
+    fn should_write_entries_skipping_duplicates() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let buffered_entry = |sequence_number: u64| BufferedEntry {
+            anchor_number: ANCHOR_NUMBER_1,
+            timestamp: TIMESTAMP_1,
+            entry: ByteBuf::from(
+                candid::encode_one(log_entry(sequence_number, TIMESTAMP_1, ANCHOR_NUMBER_1))
+                    .expect("failed to encode entry"),
+            ),
+            sequence_number,
+        };
+
+        api::write_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(0), buffered_entry(1)],
+        )?;
+        // the first two entries are sent again, e.g. because the reply to the first call got lost
+        api::write_entries(
+            &env,
+            canister_id,
+            principal_1(),
+            vec![buffered_entry(0), buffered_entry(1), buffered_entry(2)],
+        )?;
+
+        let entries = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(entries.entries.len(), 3);
+        for (i, entry) in entries.entries.iter().enumerate() {
+            assert_eq!(entry.as_ref().unwrap().sequence_number, i as u64);
+        }
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_highest_sequence_number",
+            2f64,
+        );
+        Ok(())
+    }
+
+    /// Verifies that only the configured ii_canister principal can write batches of entries.
+    #[test]
+    fn should_reject_write_entries_by_wrong_principal() {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let result = api::write_entries(&env, canister_id, principal_2(), vec![]);
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("Only [\\w-]+ is allowed to write entries\\.").unwrap(),
+        );
+    }
 }
 
 /// Verifies the read functionality of the archive canister.
@@ -457,6 +515,8 @@ mod read_tests {
             max_entries_per_call: 1000,
             polling_interval_ns: Duration::from_secs(1).as_nanos() as u64,
             error_buffer_limit: 1,
+            archive_integration: None,
+            retention_period_ns: None,
         })
         .unwrap();
         let canister_id = env.create_canister();
@@ -493,6 +553,135 @@ mod read_tests {
     }
 }
 
+/// Verifies the bulk export of the archive log via HTTP.
+#[cfg(test)]
+mod export_tests {
+    use super::*;
+    use canister_tests::api::archive::{http_request, http_request_streaming_callback};
+    use ic_cdk::api::management_canister::main::CanisterId;
+    use ic_test_state_machine_client::StateMachine;
+    use internet_identity_interface::http_gateway::{HttpRequest, StreamingStrategy};
+
+    fn export_request(query: &str) -> HttpRequest {
+        HttpRequest {
+            method: "GET".to_string(),
+            url: format!("/export?{query}"),
+            headers: vec![],
+            body: ByteBuf::new(),
+        }
+    }
+
+    fn add_entries(env: &StateMachine, canister_id: CanisterId, count: u64) -> Vec<Entry> {
+        (0..count)
+            .map(|i| {
+                let entry = log_entry(i, TIMESTAMP_1, ANCHOR_NUMBER_1);
+                api::add_entry(
+                    env,
+                    canister_id,
+                    principal_1(),
+                    ANCHOR_NUMBER_1,
+                    TIMESTAMP_1,
+                    candid::encode_one(&entry).expect("failed to encode entry"),
+                )
+                .expect("failed to add entry");
+                entry
+            })
+            .collect()
+    }
+
+    fn parse_json_lines(body: &[u8]) -> Vec<Option<Entry>> {
+        String::from_utf8(body.to_vec())
+            .expect("export is not valid UTF-8")
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("failed to decode JSON entry"))
+            .collect()
+    }
+
+    /// Verifies that the whole log is streamed in chunks as JSON Lines by default.
+    #[test]
+    fn should_stream_export_as_json_lines() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        // the test archive is configured with 10 entries per call
+        let entries = add_entries(&env, canister_id, 15);
+
+        let response = http_request(&env, canister_id, export_request(""))?;
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .contains(&("Content-Type".to_string(), "application/jsonl".to_string())));
+        let mut exported = parse_json_lines(&response.body);
+        assert_eq!(exported.len(), 10);
+
+        let Some(StreamingStrategy::Callback { token, .. }) = response.streaming_strategy else {
+            panic!("expected the export to be streamed");
+        };
+        assert_eq!(
+            token,
+            ExportToken {
+                next_index: 10,
+                end_index: 15,
+                format: ExportFormat::JsonLines,
+            }
+        );
+
+        let chunk = http_request_streaming_callback(&env, canister_id, token)?;
+        assert!(chunk.token.is_none());
+        exported.extend(parse_json_lines(&chunk.body));
+
+        assert_eq!(exported, entries.into_iter().map(Some).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    /// Verifies that a range of the log can be exported as CBOR.
+    #[test]
+    fn should_export_range_as_cbor() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        let entries = add_entries(&env, canister_id, 5);
+        // entries are not decoded on write, so the log may contain entries of an unknown format
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_1,
+            vec![1, 2, 3],
+        )?;
+
+        let response = http_request(
+            &env,
+            canister_id,
+            export_request("from=3&to=100&format=cbor"),
+        )?;
+        assert_eq!(response.status_code, 200);
+        assert!(response.streaming_strategy.is_none());
+
+        let exported: Vec<Option<Entry>> = serde_cbor::Deserializer::from_slice(&response.body)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .expect("failed to decode CBOR entries");
+        assert_eq!(
+            exported,
+            vec![Some(entries[3].clone()), Some(entries[4].clone()), None]
+        );
+        Ok(())
+    }
+
+    /// Verifies that malformed export requests are rejected.
+    #[test]
+    fn should_reject_invalid_export_request() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        for query in ["format=xml", "from=abc", "limit=10"] {
+            let response = http_request(&env, canister_id, export_request(query))?;
+            assert_eq!(response.status_code, 400);
+        }
+        Ok(())
+    }
+}
+
 /// Tests the metrics exposed via for the HTTP.
 #[cfg(test)]
 mod metrics_tests {
@@ -511,6 +700,8 @@ mod metrics_tests {
             "ii_archive_virtual_memory_pages{kind=\"log_data\"}",
             "ii_archive_virtual_memory_pages{kind=\"anchor_index\"}",
             "ii_archive_stable_memory_pages",
+            "ii_archive_operation_count{operation=\"register_anchor\"}",
+            "ii_archive_lag_seconds_count",
             // The metrics
             //   * ii_archive_last_successful_fetch_timestamp_seconds
             //   * ii_archive_last_successful_fetch_entries_count
@@ -594,6 +785,129 @@ mod metrics_tests {
         Ok(())
     }
 
+    /// Verifies that the entries are counted by operation type.
+    #[test]
+    fn should_update_operation_counts() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        assert_metric(
+            &get_metrics(&env, canister_id),
+            "ii_archive_operation_count{operation=\"register_anchor\"}",
+            0f64,
+        );
+
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry_1()).expect("failed to encode entry"),
+        )?;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_2,
+            candid::encode_one(log_entry(2, TIMESTAMP_2, ANCHOR_NUMBER_1))
+                .expect("failed to encode entry"),
+        )?;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_3,
+            candid::encode_one(Entry {
+                operation: Operation::ImportAnchor { devices: vec![] },
+                ..log_entry(3, TIMESTAMP_3, ANCHOR_NUMBER_1)
+            })
+            .expect("failed to encode entry"),
+        )?;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_3,
+            candid::encode_one(Entry {
+                operation: Operation::ReleaseAnchor,
+                ..log_entry(4, TIMESTAMP_3, ANCHOR_NUMBER_1)
+            })
+            .expect("failed to encode entry"),
+        )?;
+        // entries are not decoded on write, so the archive also accepts unknown formats
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_3,
+            vec![1, 2, 3],
+        )?;
+
+        let metrics = get_metrics(&env, canister_id);
+        for (operation, count) in [
+            ("register_anchor", 1f64),
+            ("add_device", 0f64),
+            ("update_device", 1f64),
+            ("replace_device", 0f64),
+            ("remove_device", 0f64),
+            ("reject_tentative_device", 0f64),
+            ("import_anchor", 1f64),
+            ("register_anchor_with_temp_key", 0f64),
+            ("release_anchor", 1f64),
+            ("unknown", 1f64),
+        ] {
+            assert_metric(
+                &metrics,
+                &format!("ii_archive_operation_count{{operation=\"{operation}\"}}"),
+                count,
+            );
+        }
+        Ok(())
+    }
+
+    /// Verifies that the archive lag histogram is updated correctly.
+    #[test]
+    fn should_update_archive_lag_histogram() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        // entry created 3 seconds before being archived
+        let timestamp = time(&env) - Duration::from_secs(3).as_nanos() as u64;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            timestamp,
+            candid::encode_one(log_entry(1, timestamp, ANCHOR_NUMBER_1))
+                .expect("failed to encode entry"),
+        )?;
+        // entry created long before being archived
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry_1()).expect("failed to encode entry"),
+        )?;
+
+        let metrics = get_metrics(&env, canister_id);
+        assert_metric(&metrics, "ii_archive_lag_seconds_bucket{le=\"1\"}", 0f64);
+        assert_metric(&metrics, "ii_archive_lag_seconds_bucket{le=\"5\"}", 1f64);
+        assert_metric(
+            &metrics,
+            "ii_archive_lag_seconds_bucket{le=\"86400\"}",
+            1f64,
+        );
+        assert_metric(&metrics, "ii_archive_lag_seconds_count", 2f64);
+        Ok(())
+    }
+
     /// Verifies that the log sizes are updated correctly.
     #[test]
     fn should_update_log_size_metrics() -> Result<(), CallError> {
@@ -702,6 +1016,237 @@ mod metrics_tests {
     }
 }
 
+/// Verifies that expired entries are pruned if a retention period is configured.
+#[cfg(test)]
+mod retention_tests {
+    use super::*;
+
+    const DAY_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+    const PRUNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+    /// Verifies that expired entries and their index rows are removed while log indices stay stable.
+    #[test]
+    fn should_prune_expired_entries() -> Result<(), CallError> {
+        let env = env();
+        let mut arg = archive_init_arg(principal_1());
+        arg.retention_period_ns = Some(DAY_NS);
+        let canister_id = install_archive_canister_with_arg(&env, ARCHIVE_WASM.clone(), arg);
+
+        let expired_timestamp = time(&env) - 2 * DAY_NS;
+        let recent_timestamp = time(&env);
+        for (i, (anchor, timestamp)) in [
+            (ANCHOR_NUMBER_1, expired_timestamp),
+            (ANCHOR_NUMBER_2, expired_timestamp),
+            (ANCHOR_NUMBER_1, expired_timestamp),
+            (ANCHOR_NUMBER_1, recent_timestamp),
+            (ANCHOR_NUMBER_2, recent_timestamp),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                anchor,
+                timestamp,
+                candid::encode_one(log_entry(i as u64, timestamp, anchor))
+                    .expect("failed to encode entry"),
+            )?;
+        }
+
+        env.advance_time(PRUNING_INTERVAL);
+        env.tick();
+
+        let entries = api::get_entries(&env, canister_id, Some(0), None)?;
+        assert_eq!(
+            entries.entries,
+            vec![
+                Some(log_entry(3, recent_timestamp, ANCHOR_NUMBER_1)),
+                Some(log_entry(4, recent_timestamp, ANCHOR_NUMBER_2))
+            ]
+        );
+        let anchor_entries =
+            api::get_anchor_entries(&env, canister_id, ANCHOR_NUMBER_1, None, None)?;
+        assert_eq!(
+            anchor_entries.entries,
+            vec![Some(log_entry(3, recent_timestamp, ANCHOR_NUMBER_1))]
+        );
+        let metrics = get_metrics(&env, canister_id);
+        assert_metric(&metrics, "ii_archive_entries_count{source=\"log\"}", 2f64);
+        assert_metric(
+            &metrics,
+            "ii_archive_entries_count{source=\"anchor_index\"}",
+            2f64,
+        );
+
+        let cutoff = api::status(&env, canister_id)?
+            .retention_cutoff
+            .expect("cutoff not reported");
+        assert!(cutoff > expired_timestamp && cutoff <= recent_timestamp);
+
+        // new entries are appended with the next index, also after an upgrade
+        upgrade_archive_canister(&env, canister_id, ARCHIVE_WASM.clone());
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            recent_timestamp,
+            candid::encode_one(log_entry(5, recent_timestamp, ANCHOR_NUMBER_1))
+                .expect("failed to encode entry"),
+        )?;
+        let entries = api::get_entries(&env, canister_id, Some(4), None)?;
+        assert_eq!(
+            entries.entries,
+            vec![
+                Some(log_entry(4, recent_timestamp, ANCHOR_NUMBER_2)),
+                Some(log_entry(5, recent_timestamp, ANCHOR_NUMBER_1))
+            ]
+        );
+        Ok(())
+    }
+
+    /// Verifies that the log is not copied as long as fewer entries have expired than are kept.
+    #[test]
+    fn should_not_prune_few_expired_entries() -> Result<(), CallError> {
+        let env = env();
+        let mut arg = archive_init_arg(principal_1());
+        arg.retention_period_ns = Some(DAY_NS);
+        let canister_id = install_archive_canister_with_arg(&env, ARCHIVE_WASM.clone(), arg);
+
+        let expired_timestamp = time(&env) - 2 * DAY_NS;
+        let recent_timestamp = time(&env);
+        for (i, timestamp) in [expired_timestamp, recent_timestamp, recent_timestamp]
+            .into_iter()
+            .enumerate()
+        {
+            api::add_entry(
+                &env,
+                canister_id,
+                principal_1(),
+                ANCHOR_NUMBER_1,
+                timestamp,
+                candid::encode_one(log_entry(i as u64, timestamp, ANCHOR_NUMBER_1))
+                    .expect("failed to encode entry"),
+            )?;
+        }
+
+        env.advance_time(PRUNING_INTERVAL);
+        env.tick();
+
+        let entries = api::get_entries(&env, canister_id, Some(0), None)?;
+        assert_eq!(entries.entries.len(), 3);
+        assert_eq!(api::status(&env, canister_id)?.retention_cutoff, None);
+        Ok(())
+    }
+
+    /// Verifies that entries are kept forever if no retention period is configured.
+    #[test]
+    fn should_not_prune_without_retention_period() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry_1()).expect("failed to encode entry"),
+        )?;
+
+        env.advance_time(PRUNING_INTERVAL);
+        env.tick();
+
+        let entries = api::get_entries(&env, canister_id, None, None)?;
+        assert_eq!(entries.entries, vec![Some(log_entry_1())]);
+        assert_eq!(api::status(&env, canister_id)?.retention_cutoff, None);
+        Ok(())
+    }
+}
+
+/// Verifies the consistency check of the anchor index.
+#[cfg(test)]
+mod index_check_tests {
+    use super::*;
+    use candid::Principal;
+
+    /// Verifies that inconsistencies between the log and the index are reported and repaired.
+    #[test]
+    fn should_repair_inconsistent_index() -> Result<(), CallError> {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_1,
+            candid::encode_one(log_entry_1()).expect("failed to encode entry"),
+        )?;
+        // The index row is created using the given timestamp which does not match the one of the
+        // entry: the row is dangling and the row matching the entry is missing.
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_3,
+            candid::encode_one(log_entry(1, TIMESTAMP_2, ANCHOR_NUMBER_1))
+                .expect("failed to encode entry"),
+        )?;
+        api::add_entry(
+            &env,
+            canister_id,
+            principal_1(),
+            ANCHOR_NUMBER_1,
+            TIMESTAMP_3,
+            vec![1, 2, 3], // not candid
+        )?;
+
+        // the test environment creates canisters with the anonymous principal as controller
+        api::verify_and_rebuild_index(&env, canister_id, Principal::anonymous())?;
+        env.tick();
+
+        let report = api::status(&env, canister_id)?
+            .index_check
+            .expect("index check not reported");
+        assert!(report.completed.is_some());
+        assert_eq!(report.undecodable_entries, 1);
+        assert_eq!(report.missing_index_rows, 1);
+        assert_eq!(report.dangling_index_rows, 1);
+
+        let entries = api::get_anchor_entries(
+            &env,
+            canister_id,
+            ANCHOR_NUMBER_1,
+            Some(Cursor::Timestamp {
+                timestamp: TIMESTAMP_2,
+            }),
+            Some(1), // the next entry (written with TIMESTAMP_3) cannot be decoded
+        )?;
+        assert_eq!(
+            entries.entries.get(0).unwrap().as_ref().unwrap(),
+            &log_entry(1, TIMESTAMP_2, ANCHOR_NUMBER_1)
+        );
+        Ok(())
+    }
+
+    /// Verifies that only controllers can start an index check.
+    #[test]
+    fn should_reject_index_check_by_non_controller() {
+        let env = env();
+        let canister_id = install_archive_canister(&env, ARCHIVE_WASM.clone());
+
+        let result = api::verify_and_rebuild_index(&env, canister_id, principal_2());
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("[\\w-]+ is not a controller of the archive\\.").unwrap(),
+        );
+    }
+}
+
 /// Verifies that the archive is compatible to stable memory backups.
 #[cfg(test)]
 mod stable_memory_tests {
@@ -4,6 +4,9 @@ use ic_test_state_machine_client::{
     call_candid, call_candid_as, query_candid, CallError, StateMachine,
 };
 use internet_identity_interface::archive::types::*;
+use internet_identity_interface::http_gateway::{
+    HttpRequest, HttpResponse, StreamingCallbackHttpResponse,
+};
 use internet_identity_interface::internet_identity::types::*;
 
 pub fn add_entry(
@@ -23,6 +26,15 @@ pub fn add_entry(
     )
 }
 
+pub fn write_entries(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    entries: Vec<BufferedEntry>,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "write_entries", (entries,))
+}
+
 pub fn get_entries(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -52,5 +64,35 @@ pub fn status(env: &StateMachine, canister_id: CanisterId) -> Result<ArchiveStat
     call_candid(env, canister_id, "status", ()).map(|(x,)| x)
 }
 
+pub fn verify_and_rebuild_index(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "verify_and_rebuild_index", ())
+}
+
+pub fn http_request(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    http_request: HttpRequest,
+) -> Result<HttpResponse<ExportToken>, CallError> {
+    query_candid(env, canister_id, "http_request", (http_request,)).map(|(x,)| x)
+}
+
+pub fn http_request_streaming_callback(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    token: ExportToken,
+) -> Result<StreamingCallbackHttpResponse<ExportToken>, CallError> {
+    query_candid(
+        env,
+        canister_id,
+        "http_request_streaming_callback",
+        (token,),
+    )
+    .map(|(x,)| x)
+}
+
 /// A "compatibility" module for the previous version of the archive to handle API changes.
 pub mod compat {}
@@ -25,6 +25,29 @@ pub fn create_challenge(
     call_candid(env, canister_id, "create_challenge", ()).map(|(x,)| x)
 }
 
+pub fn create_challenge_as(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<types::Challenge, CallError> {
+    call_candid_as(env, canister_id, sender, "create_challenge", ()).map(|(x,)| x)
+}
+
+pub fn create_audio_challenge(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<types::Challenge, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "create_challenge",
+        (Some(types::ChallengeKind::Audio),),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn register(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -42,6 +65,41 @@ pub fn register(
     .map(|(x,)| x)
 }
 
+pub fn register_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    authentication_device: &types::DeviceData,
+    recovery_device: Option<&types::DeviceData>,
+    challenge_attempt: types::ChallengeAttempt,
+) -> Result<types::RegisterV2Response, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "register_v2",
+        (authentication_device, recovery_device, challenge_attempt),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn register_with_temp_key(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    temp_key: types::DeviceKey,
+    challenge_attempt: types::ChallengeAttempt,
+) -> Result<types::RegisterWithTempKeyResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "register_with_temp_key",
+        (temp_key, challenge_attempt),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn prepare_delegation(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -121,6 +179,36 @@ pub fn get_anchor_credentials(
     query_candid(env, canister_id, "get_anchor_credentials", (anchor_number,)).map(|(x,)| x)
 }
 
+pub fn lookup_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    anchor_number: types::AnchorNumber,
+) -> Result<types::LookupResponse, CallError> {
+    query_candid(env, canister_id, "lookup_v2", (anchor_number,)).map(|(x,)| x)
+}
+
+pub fn get_anchor_credentials_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    anchor_number: types::AnchorNumber,
+) -> Result<types::GetAnchorCredentialsResponse, CallError> {
+    query_candid(
+        env,
+        canister_id,
+        "get_anchor_credentials_v2",
+        (anchor_number,),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn route(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    anchor_number: types::AnchorNumber,
+) -> Result<types::RouteResponse, CallError> {
+    query_candid(env, canister_id, "route", (anchor_number,)).map(|(x,)| x)
+}
+
 pub fn add(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -137,6 +225,111 @@ pub fn add(
     )
 }
 
+pub fn add_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_data: types::DeviceData,
+) -> Result<types::AddResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "add_v2",
+        (anchor_number, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn update_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::PublicKey,
+    device_data: types::DeviceData,
+) -> Result<types::UpdateResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "update_v2",
+        (anchor_number, device_key, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn update_device_fields(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::PublicKey,
+    patch: types::DeviceDataPatch,
+) -> Result<types::UpdateResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "update_device_fields",
+        (anchor_number, device_key, patch),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn replace_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::PublicKey,
+    device_data: types::DeviceData,
+) -> Result<types::ReplaceResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "replace_v2",
+        (anchor_number, device_key, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn remove_v2(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::PublicKey,
+) -> Result<types::RemoveResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "remove_v2",
+        (anchor_number, device_key),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn apply_operations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    ops: Vec<types::AnchorOp>,
+) -> Result<types::ApplyOperationsResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "apply_operations",
+        (anchor_number, ops),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn update(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -203,6 +396,22 @@ pub fn get_anchor_info(
     .map(|(x,)| x)
 }
 
+pub fn get_security_events(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+) -> Result<Vec<types::SecurityEvent>, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "get_security_events",
+        (anchor_number,),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn enter_device_registration_mode(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -268,6 +477,57 @@ pub fn verify_tentative_device(
     .map(|(x,)| x)
 }
 
+pub fn prove_tentative_device_possession(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    nonce: ByteBuf,
+) -> Result<types::ProveDevicePossessionResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "prove_tentative_device_possession",
+        (anchor_number, nonce),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn confirm_tentative_device(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::DeviceKey,
+) -> Result<types::VerifyTentativeDeviceResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "confirm_tentative_device",
+        (anchor_number, device_key),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn reject_tentative_device(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::DeviceKey,
+) -> Result<types::RejectTentativeDeviceResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "reject_tentative_device",
+        (anchor_number, device_key),
+    )
+    .map(|(x,)| x)
+}
+
 pub fn deploy_archive(
     env: &StateMachine,
     canister_id: CanisterId,
@@ -306,6 +566,114 @@ pub fn acknowledge_entries(
     )
 }
 
+pub fn export_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    range: Option<(types::AnchorNumber, types::AnchorNumber)>,
+    cursor: Option<types::AnchorNumber>,
+) -> Result<types::AnchorExportBatch, CallError> {
+    query_candid_as(env, canister_id, sender, "export_anchors", (range, cursor)).map(|(x,)| x)
+}
+
+pub fn import_anchors(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchors: Vec<types::ExportedAnchor>,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "import_anchors", (anchors,))
+}
+
+pub fn start_anchor_repair(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    policy: types::AnchorRepairPolicy,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "start_anchor_repair", (policy,))
+}
+
+pub fn anchor_repair_status(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<Option<types::AnchorRepairStatus>, CallError> {
+    query_candid_as(env, canister_id, sender, "anchor_repair_status", ()).map(|(x,)| x)
+}
+
+pub fn acknowledge_dormancy(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+) -> Result<(), CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "acknowledge_dormancy",
+        (anchor_number,),
+    )
+}
+
+pub fn start_inactivity_scan(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<(), CallError> {
+    call_candid_as(env, canister_id, sender, "start_inactivity_scan", ())
+}
+
+pub fn inactivity_report(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<Option<types::InactivityReport>, CallError> {
+    query_candid_as(env, canister_id, sender, "inactivity_report", ()).map(|(x,)| x)
+}
+
+pub fn claim_reserved_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_data: &types::DeviceData,
+) -> Result<types::ClaimReservedAnchorResponse, CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "claim_reserved_anchor",
+        (anchor_number, device_data),
+    )
+    .map(|(x,)| x)
+}
+
+pub fn reserve_anchor(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+    anchor_number: types::AnchorNumber,
+    device_key: types::DeviceKey,
+) -> Result<(), CallError> {
+    call_candid_as(
+        env,
+        canister_id,
+        sender,
+        "reserve_anchor",
+        (anchor_number, device_key),
+    )
+}
+
+pub fn anchor_reservations(
+    env: &StateMachine,
+    canister_id: CanisterId,
+    sender: Principal,
+) -> Result<Vec<types::AnchorReservation>, CallError> {
+    query_candid_as(env, canister_id, sender, "anchor_reservations", ()).map(|(x,)| x)
+}
+
 /// A "compatibility" module for the previous version of II to handle API changes.
 pub mod compat {
     use super::*;
@@ -174,10 +174,21 @@ pub fn arg_with_wasm_hash(wasm: Vec<u8>) -> Option<InternetIdentityInit> {
             entries_buffer_limit: 10_000,
             polling_interval_ns: Duration::from_secs(1).as_nanos() as u64,
             entries_fetch_limit: 10,
+            archive_integration: None,
+            buffer_overflow_policy: None,
+            retention_period_ns: None,
         }),
         canister_creation_cycles_cost: Some(0),
         register_rate_limit: None,
         max_num_latest_delegation_origins: None,
+        controllers: None,
+        shards: None,
+        anchor_limits: None,
+        dormancy_policy: None,
+        device_registration: None,
+        rate_limits: None,
+        anchor_allocation: None,
+        adaptive_captcha: None,
     })
 }
 
@@ -188,6 +199,14 @@ pub fn arg_with_rate_limit(rate_limit: RateLimitConfig) -> Option<InternetIdenti
         canister_creation_cycles_cost: None,
         register_rate_limit: Some(rate_limit),
         max_num_latest_delegation_origins: None,
+        controllers: None,
+        shards: None,
+        anchor_limits: None,
+        dormancy_policy: None,
+        device_registration: None,
+        rate_limits: None,
+        anchor_allocation: None,
+        adaptive_captcha: None,
     })
 }
 
@@ -200,6 +219,14 @@ pub fn arg_with_anchor_range(
         canister_creation_cycles_cost: None,
         register_rate_limit: None,
         max_num_latest_delegation_origins: None,
+        controllers: None,
+        shards: None,
+        anchor_limits: None,
+        dormancy_policy: None,
+        device_registration: None,
+        rate_limits: None,
+        anchor_allocation: None,
+        adaptive_captcha: None,
     })
 }
 
@@ -548,8 +575,20 @@ pub fn deploy_archive_via_ii(env: &StateMachine, ii_canister: CanisterId) -> Can
 }
 
 pub fn install_archive_canister(env: &StateMachine, wasm: Vec<u8>) -> CanisterId {
+    install_archive_canister_with_arg(env, wasm, archive_init_arg(principal_1()))
+}
+
+pub fn install_archive_canister_with_arg(
+    env: &StateMachine,
+    wasm: Vec<u8>,
+    arg: ArchiveInit,
+) -> CanisterId {
     let canister_id = env.create_canister();
-    env.install_canister(canister_id, wasm, encode_config(principal_1()));
+    env.install_canister(
+        canister_id,
+        wasm,
+        candid::encode_one(arg).expect("error encoding archive installation arg as candid"),
+    );
     canister_id
 }
 
@@ -559,13 +598,20 @@ pub fn upgrade_archive_canister(env: &StateMachine, canister_id: CanisterId, was
 }
 
 fn encode_config(authorized_principal: Principal) -> Vec<u8> {
-    let config = ArchiveInit {
+    candid::encode_one(archive_init_arg(authorized_principal))
+        .expect("error encoding II installation arg as candid")
+}
+
+/// Archive init argument as used by [install_archive_canister].
+pub fn archive_init_arg(authorized_principal: Principal) -> ArchiveInit {
+    ArchiveInit {
         ii_canister: authorized_principal,
         max_entries_per_call: 10,
         polling_interval_ns: Duration::from_secs(1).as_nanos() as u64,
         error_buffer_limit: 2,
-    };
-    candid::encode_one(config).expect("error encoding II installation arg as candid")
+        archive_integration: None,
+        retention_period_ns: None,
+    }
 }
 
 pub const ANCHOR_NUMBER_1: AnchorNumber = 100001;
@@ -0,0 +1,420 @@
+//! Allocation of the anchor numbers of new anchors.
+//!
+//! By default, anchor numbers are allocated sequentially, which reveals the number of registrations
+//! and makes anchor numbers easy to guess. Alternatively, anchor numbers can be picked at random
+//! (see [AnchorAllocationStrategy::Randomized]). Controllers can also reserve anchor numbers that
+//! can then only be claimed by a specific device.
+//!
+//! Anchors are stored in fixed-size records indexed by anchor number (see [crate::storage]), so
+//! anchor numbers can only be picked within a bounded window above the highest allocated anchor
+//! number. Anchor numbers that are skipped remain free and are tracked in an [AnchorBitmap] that is
+//! kept in the persistent state. The bitmap is only created once the first anchor number is
+//! allocated out of order (or released below the highest allocated anchor number, see
+//! [release_anchor]). From then on, the number of allocated anchors no longer matches the
+//! number of anchor records and is tracked in the persistent state as well.
+use crate::state;
+use crate::state::PersistentState;
+use crate::storage::anchor::Anchor;
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use internet_identity_interface::internet_identity::types::*;
+use sha2::{Digest, Sha256};
+use std::iter;
+
+/// Maximum number of anchor numbers above the highest allocated anchor number that can be
+/// allocated or reserved. This bounds the stable memory used by records of skipped anchor numbers
+/// (which are written as empty records) to a few hundred KB per allocation.
+pub const MAX_ALLOCATION_WINDOW: u64 = 300;
+
+/// Taken (i.e. allocated or reserved) anchor records, indexed by record number
+/// (i.e. anchor number - lowest anchor number of the assigned range).
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorBitmap {
+    /// Record number of the first bit. All records below are taken.
+    offset: u64,
+    /// Bit i of word w is set if record offset + 64 * w + i is taken.
+    /// Records beyond the last word are free.
+    words: Vec<u64>,
+}
+
+impl AnchorBitmap {
+    /// Creates a bitmap where exactly the first `count` records are taken.
+    pub fn with_taken_prefix(count: u64) -> Self {
+        let mut bitmap = Self {
+            offset: count - count % 64,
+            words: vec![],
+        };
+        for record in bitmap.offset..count {
+            bitmap.set(record);
+        }
+        bitmap
+    }
+
+    pub fn is_taken(&self, record: u64) -> bool {
+        if record < self.offset {
+            return true;
+        }
+        let index = record - self.offset;
+        self.words
+            .get((index / 64) as usize)
+            .map_or(false, |word| word & (1 << (index % 64)) != 0)
+    }
+
+    pub fn set(&mut self, record: u64) {
+        if record < self.offset {
+            return;
+        }
+        let index = record - self.offset;
+        let word = (index / 64) as usize;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (index % 64);
+
+        // keep the bitmap small by dropping the words of fully taken records
+        let full_words = self.words.iter().take_while(|w| **w == u64::MAX).count();
+        self.words.drain(..full_words);
+        self.offset += 64 * full_words as u64;
+    }
+
+    pub fn clear(&mut self, record: u64) {
+        if record < self.offset {
+            // restore the words of fully taken records dropped by set
+            let offset = record - record % 64;
+            let restored_words = ((self.offset - offset) / 64) as usize;
+            self.words
+                .splice(0..0, iter::repeat(u64::MAX).take(restored_words));
+            self.offset = offset;
+        }
+        let index = record - self.offset;
+        if let Some(word) = self.words.get_mut((index / 64) as usize) {
+            *word &= !(1 << (index % 64));
+        }
+    }
+
+    /// Returns the free records below `end` in ascending order.
+    pub fn free_records(&self, end: u64) -> impl Iterator<Item = u64> + '_ {
+        (self.offset..end).filter(|record| !self.is_taken(*record))
+    }
+}
+
+/// Checks the strategy and returns an error message if it is not acceptable.
+pub fn check_allocation_strategy(strategy: &AnchorAllocationStrategy) -> Result<(), String> {
+    match strategy {
+        AnchorAllocationStrategy::Sequential => Ok(()),
+        AnchorAllocationStrategy::Randomized { window_size } => {
+            if *window_size == 0 || *window_size > MAX_ALLOCATION_WINDOW {
+                return Err(format!(
+                    "window_size must be between 1 and {MAX_ALLOCATION_WINDOW}, got {window_size}"
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Allocates a fresh anchor according to the configured strategy.
+///
+/// Returns None if there are no free anchor numbers left.
+pub fn allocate_anchor() -> Option<(AnchorNumber, Anchor)> {
+    let (strategy, has_bitmap) = state::persistent_state(|persistent_state| {
+        (
+            persistent_state
+                .anchor_allocation
+                .clone()
+                .unwrap_or(AnchorAllocationStrategy::Sequential),
+            persistent_state.anchor_bitmap.is_some(),
+        )
+    });
+    // as long as no anchor number was allocated out of order, anchors are allocated as they always were
+    if strategy == AnchorAllocationStrategy::Sequential && !has_bitmap {
+        return state::storage_borrow_mut(|storage| storage.allocate_anchor());
+    }
+
+    let (lo, hi) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let record_count = state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    let range_size = hi - lo;
+    let record = state::persistent_state_mut(|persistent_state| {
+        let bitmap = anchor_bitmap_mut(persistent_state, record_count);
+        let record = match strategy {
+            AnchorAllocationStrategy::Sequential => bitmap.free_records(range_size).next(),
+            AnchorAllocationStrategy::Randomized { window_size } => {
+                let end = range_size.min(record_count + window_size);
+                let free = bitmap.free_records(end).count() as u64;
+                if free == 0 {
+                    // the window is beyond the end of the range
+                    bitmap.free_records(range_size).next()
+                } else {
+                    let n = random_u64(record_count) % free;
+                    bitmap.free_records(end).nth(n as usize)
+                }
+            }
+        }?;
+        bitmap.set(record);
+        increment_allocated_anchors(persistent_state);
+        Some(record)
+    })?;
+
+    let anchor_number = lo + record;
+    let anchor = state::storage_borrow_mut(|storage| storage.allocate_anchor_number(anchor_number))
+        .unwrap_or_else(|err| trap(&format!("failed to allocate anchor {anchor_number}: {err}")));
+    Some((anchor_number, anchor))
+}
+
+/// Allocates the given anchor number (e.g. to import an anchor). Traps if the anchor number is
+/// reserved.
+pub fn allocate_anchor_number(anchor_number: AnchorNumber) -> Anchor {
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    state::persistent_state_mut(|persistent_state| {
+        if is_reserved(&persistent_state.anchor_reservations, anchor_number) {
+            trap(&format!("anchor {anchor_number} is reserved"));
+        }
+        if let Some(bitmap) = persistent_state.anchor_bitmap.as_mut() {
+            bitmap.set(anchor_number - lo);
+            increment_allocated_anchors(persistent_state);
+        }
+    });
+    state::storage_borrow_mut(|storage| storage.allocate_anchor_number(anchor_number))
+        .unwrap_or_else(|err| trap(&format!("failed to allocate anchor {anchor_number}: {err}")))
+}
+
+/// Releases the given anchor number, so that it can be allocated again (e.g. if an anchor was
+/// registered but never received a device).
+///
+/// As long as no anchor number has been allocated out of order, releasing the highest anchor number
+/// simply drops its record, so that anchors keep being allocated sequentially without a bitmap.
+/// Releasing any other anchor number leaves a gap in the anchor records and therefore creates the
+/// bitmap, i.e. from then on anchor numbers are allocated using the bitmap (which hands out the
+/// released anchor number first if the strategy is sequential).
+pub fn release_anchor(anchor_number: AnchorNumber) {
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let record_count = state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    let has_bitmap =
+        state::persistent_state(|persistent_state| persistent_state.anchor_bitmap.is_some());
+    if !has_bitmap && anchor_number - lo + 1 == record_count {
+        state::storage_borrow_mut(|storage| storage.release_last_anchor_number(anchor_number))
+            .unwrap_or_else(|err| {
+                trap(&format!("failed to release anchor {anchor_number}: {err}"))
+            });
+        return;
+    }
+    state::storage_borrow_mut(|storage| storage.release_anchor_number(anchor_number))
+        .unwrap_or_else(|err| trap(&format!("failed to release anchor {anchor_number}: {err}")));
+    state::persistent_state_mut(|persistent_state| {
+        anchor_bitmap_mut(persistent_state, record_count).clear(anchor_number - lo);
+        if let Some(count) = persistent_state.allocated_anchors.as_mut() {
+            *count -= 1;
+        }
+    });
+}
+
+/// Reserves the given anchor number for the device with the given key.
+/// Traps if the anchor number is not free or too far beyond the highest allocated anchor number.
+pub fn reserve_anchor(anchor_number: AnchorNumber, device_key: DeviceKey) {
+    let (lo, hi) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let record_count = state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    if anchor_number < lo || anchor_number >= hi {
+        trap(&format!(
+            "anchor {anchor_number} is outside of the assigned anchor number range [{lo}, {hi})"
+        ));
+    }
+    let record = anchor_number - lo;
+    if record >= record_count + MAX_ALLOCATION_WINDOW {
+        trap(&format!(
+            "anchor {anchor_number} is more than {MAX_ALLOCATION_WINDOW} anchor numbers beyond the highest allocated anchor number"
+        ));
+    }
+
+    state::persistent_state_mut(|persistent_state| {
+        let bitmap = anchor_bitmap_mut(persistent_state, record_count);
+        if bitmap.is_taken(record) {
+            trap(&format!("anchor {anchor_number} is already taken"));
+        }
+        bitmap.set(record);
+        persistent_state
+            .anchor_reservations
+            .get_or_insert_with(Vec::new)
+            .push(AnchorReservation {
+                anchor_number,
+                device_key,
+            });
+    })
+}
+
+/// Removes the reservation of the given anchor number for the given device and allocates the anchor.
+/// Returns None if the anchor number is not reserved for the given device.
+pub fn claim_reservation(anchor_number: AnchorNumber, device_key: &DeviceKey) -> Option<Anchor> {
+    state::persistent_state_mut(|persistent_state| {
+        let reservations = persistent_state.anchor_reservations.as_mut()?;
+        let index = reservations.iter().position(|reservation| {
+            reservation.anchor_number == anchor_number && &reservation.device_key == device_key
+        })?;
+        reservations.remove(index);
+        increment_allocated_anchors(persistent_state);
+        Some(())
+    })?;
+    let anchor = state::storage_borrow_mut(|storage| storage.allocate_anchor_number(anchor_number))
+        .unwrap_or_else(|err| trap(&format!("failed to allocate anchor {anchor_number}: {err}")));
+    Some(anchor)
+}
+
+pub fn anchor_reservations() -> Vec<AnchorReservation> {
+    state::persistent_state(|persistent_state| {
+        persistent_state
+            .anchor_reservations
+            .clone()
+            .unwrap_or_default()
+    })
+}
+
+/// Returns the number of allocated anchors. This is less than the number of anchor records if
+/// anchor numbers have been skipped or reserved.
+pub fn allocated_anchor_count() -> u64 {
+    state::persistent_state(|persistent_state| persistent_state.allocated_anchors)
+        .unwrap_or_else(count_allocated_anchors)
+}
+
+/// Initializes the number of allocated anchors in the persistent state, if it is not tracked yet
+/// although anchor numbers have been allocated out of order (i.e. for a persistent state written by
+/// a previous version).
+pub fn init_allocated_anchors() {
+    let missing = state::persistent_state(|persistent_state| {
+        persistent_state.anchor_bitmap.is_some() && persistent_state.allocated_anchors.is_none()
+    });
+    if missing {
+        let count = count_allocated_anchors();
+        state::persistent_state_mut(|persistent_state| {
+            persistent_state.allocated_anchors = Some(count);
+        });
+    }
+}
+
+/// Counts the allocated anchors. Once anchor numbers have been allocated out of order, this goes
+/// over all anchor records, which is O(records). Use [allocated_anchor_count] instead, which is
+/// based on the running count.
+fn count_allocated_anchors() -> u64 {
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let record_count = state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    state::persistent_state(|persistent_state| {
+        let Some(bitmap) = persistent_state.anchor_bitmap.as_ref() else {
+            return record_count;
+        };
+        let reserved = persistent_state
+            .anchor_reservations
+            .iter()
+            .flatten()
+            .filter(|reservation| reservation.anchor_number - lo < record_count)
+            .count() as u64;
+        record_count - bitmap.free_records(record_count).count() as u64 - reserved
+    })
+}
+
+/// Returns the anchor bitmap, creating it (and starting to track the number of allocated anchors)
+/// if no anchor number has been allocated out of order yet.
+fn anchor_bitmap_mut(
+    persistent_state: &mut PersistentState,
+    record_count: u64,
+) -> &mut AnchorBitmap {
+    if persistent_state.anchor_bitmap.is_none() {
+        persistent_state.allocated_anchors = Some(record_count);
+    }
+    persistent_state
+        .anchor_bitmap
+        .get_or_insert_with(|| AnchorBitmap::with_taken_prefix(record_count))
+}
+
+fn increment_allocated_anchors(persistent_state: &mut PersistentState) {
+    if let Some(count) = persistent_state.allocated_anchors.as_mut() {
+        *count += 1;
+    }
+}
+
+fn is_reserved(reservations: &Option<Vec<AnchorReservation>>, anchor_number: AnchorNumber) -> bool {
+    reservations
+        .iter()
+        .flatten()
+        .any(|reservation| reservation.anchor_number == anchor_number)
+}
+
+/// Returns a random number that cannot be predicted by callers, because it is derived from the
+/// (secret) salt. The salt is set on the first delegation, so only anchors registered before are
+/// allocated with randomness derived from the time alone.
+fn random_u64(record_count: u64) -> u64 {
+    let salt = state::storage_borrow(|storage| storage.salt().cloned()).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(b"anchor allocation");
+    hasher.update(salt);
+    hasher.update(time().to_le_bytes());
+    hasher.update(record_count.to_le_bytes());
+    let hash = hasher.finalize();
+    u64::from_le_bytes(hash[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Storage;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    #[test]
+    fn should_track_taken_records() {
+        let mut bitmap = AnchorBitmap::with_taken_prefix(70);
+        assert!(bitmap.is_taken(69));
+        assert!(!bitmap.is_taken(70));
+
+        bitmap.set(72);
+        assert_eq!(
+            bitmap.free_records(75).collect::<Vec<_>>(),
+            vec![70, 71, 73, 74]
+        );
+    }
+
+    #[test]
+    fn should_drop_fully_taken_words() {
+        let mut bitmap = AnchorBitmap::with_taken_prefix(60);
+        for record in 60..130 {
+            bitmap.set(record);
+        }
+        assert_eq!(bitmap.offset, 128);
+        assert_eq!(bitmap.words.len(), 1);
+        assert!(bitmap.is_taken(0));
+        assert!(bitmap.is_taken(129));
+        assert_eq!(bitmap.free_records(132).collect::<Vec<_>>(), vec![130, 131]);
+    }
+
+    #[test]
+    fn should_clear_dropped_records() {
+        let mut bitmap = AnchorBitmap::with_taken_prefix(130);
+        bitmap.clear(129);
+        bitmap.clear(5);
+        assert_eq!(bitmap.offset, 0);
+        assert_eq!(
+            bitmap.free_records(131).collect::<Vec<_>>(),
+            vec![5, 129, 130]
+        );
+
+        bitmap.set(5);
+        assert_eq!(bitmap.offset, 128);
+        assert_eq!(bitmap.free_records(131).collect::<Vec<_>>(), vec![129, 130]);
+    }
+
+    #[test]
+    fn should_stay_sequential_when_releasing_the_highest_anchor() {
+        state::storage_replace(Storage::new((10_000, 11_000), DefaultMemoryImpl::default()));
+        let (first, _) = allocate_anchor().unwrap();
+        let (second, _) = allocate_anchor().unwrap();
+
+        release_anchor(second);
+        assert!(state::persistent_state(|ps| ps.anchor_bitmap.is_none()));
+        assert_eq!(allocated_anchor_count(), 1);
+        assert_eq!(allocate_anchor().unwrap().0, second);
+        assert_eq!(allocate_anchor().unwrap().0, second + 1);
+
+        // releasing an anchor below the highest one leaves a gap, which requires the bitmap
+        release_anchor(first);
+        assert!(state::persistent_state(|ps| ps.anchor_bitmap.is_some()));
+        assert_eq!(allocated_anchor_count(), 2);
+        assert_eq!(allocate_anchor().unwrap().0, first);
+    }
+}
@@ -1,55 +1,61 @@
 use crate::active_anchor_stats::IIDomain;
 use crate::archive::{archive_operation, device_diff};
-use crate::state::RegistrationState::DeviceTentativelyAdded;
 use crate::state::TentativeDeviceRegistration;
-use crate::storage::anchor::{Anchor, Device};
+use crate::storage::anchor::{Anchor, AnchorError, Device, InvariantChecks};
 use crate::{active_anchor_stats, state};
 use ic_cdk::api::time;
 use ic_cdk::{caller, trap};
 use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
 use internet_identity_interface::internet_identity::types::*;
 
+pub mod audio_captcha;
 pub mod registration;
+pub mod temp_keys;
 pub mod tentative_device_registration;
 
+/// Maximum number of operations that can be applied in a single call to [apply_operations].
+pub const MAX_OPERATIONS_PER_CALL: usize = 16;
+
 pub fn get_anchor_info(anchor_number: AnchorNumber) -> IdentityAnchorInfo {
-    let devices = state::anchor(anchor_number)
+    let anchor = state::anchor(anchor_number);
+    let flagged_dormant = anchor.flagged_dormant();
+    let recovery_status = anchor.recovery_status();
+    let devices = anchor
         .into_devices()
         .into_iter()
         .map(DeviceWithUsage::from)
         .collect();
     let now = time();
 
-    state::tentative_device_registrations(|tentative_device_registrations| {
-        match tentative_device_registrations.get(&anchor_number) {
-            Some(TentativeDeviceRegistration {
-                expiration,
-                state:
-                    DeviceTentativelyAdded {
-                        tentative_device, ..
-                    },
-            }) if *expiration > now => IdentityAnchorInfo {
-                devices,
-                device_registration: Some(DeviceRegistrationInfo {
+    let device_registration =
+        state::tentative_device_registrations(|tentative_device_registrations| {
+            match tentative_device_registrations.get(&anchor_number) {
+                Some(TentativeDeviceRegistration {
+                    expiration,
+                    tentative_devices,
+                    ..
+                }) if *expiration > now => Some(DeviceRegistrationInfo {
                     expiration: *expiration,
-                    tentative_device: Some(tentative_device.clone()),
+                    tentative_device: tentative_devices
+                        .first()
+                        .map(|tentative| tentative.device.clone()),
+                    tentative_devices: Some(
+                        tentative_devices
+                            .iter()
+                            .map(|tentative| tentative.device.clone())
+                            .collect(),
+                    ),
                 }),
-            },
-            Some(TentativeDeviceRegistration { expiration, .. }) if *expiration > now => {
-                IdentityAnchorInfo {
-                    devices,
-                    device_registration: Some(DeviceRegistrationInfo {
-                        expiration: *expiration,
-                        tentative_device: None,
-                    }),
-                }
+                None | Some(_) => None,
             }
-            None | Some(_) => IdentityAnchorInfo {
-                devices,
-                device_registration: None,
-            },
-        }
-    })
+        });
+
+    IdentityAnchorInfo {
+        devices,
+        device_registration,
+        flagged_dormant,
+        recovery_status: Some(recovery_status),
+    }
 }
 
 /// Handles all the bookkeeping required on anchor activity:
@@ -144,3 +150,354 @@ pub fn remove(anchor: &mut Anchor, device_key: DeviceKey) -> Operation {
 
     Operation::RemoveDevice { device: device_key }
 }
+
+/// Adds a device to the given anchor and returns the operation to be archived.
+/// Violations of the anchor constraints (see [Anchor]) are returned as errors.
+pub fn add_v2(
+    anchor: &mut Anchor,
+    device_data: DeviceData,
+) -> Result<(AddResponse, Operation), AddResponse> {
+    add_with_checks(anchor, device_data, InvariantChecks::Immediate)
+}
+
+fn add_with_checks(
+    anchor: &mut Anchor,
+    device_data: DeviceData,
+    checks: InvariantChecks,
+) -> Result<(AddResponse, Operation), AddResponse> {
+    let new_device = Device::from(device_data);
+    anchor.add_device_with_checks(new_device.clone(), checks)?;
+
+    Ok((
+        AddResponse::Ok,
+        Operation::AddDevice {
+            device: DeviceDataWithoutAlias::from(new_device),
+        },
+    ))
+}
+
+/// Updates a device of the given anchor and returns the operation to be archived.
+/// Violations of the anchor constraints (see [Anchor]) are returned as errors.
+pub fn update_v2(
+    anchor: &mut Anchor,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> Result<(UpdateResponse, Operation), UpdateResponse> {
+    modify_device_with(
+        anchor,
+        device_key,
+        |device| device.apply_device_data(device_data),
+        InvariantChecks::Immediate,
+    )
+}
+
+/// Updates the fields set in `patch` of a device of the given anchor and returns the operation to be archived.
+/// Violations of the anchor constraints (see [Anchor]) are returned as errors.
+pub fn update_device_fields(
+    anchor: &mut Anchor,
+    device_key: DeviceKey,
+    patch: DeviceDataPatch,
+) -> Result<(UpdateResponse, Operation), UpdateResponse> {
+    modify_device_with(
+        anchor,
+        device_key,
+        |device| device.apply_device_patch(patch),
+        InvariantChecks::Immediate,
+    )
+}
+
+fn modify_device_with(
+    anchor: &mut Anchor,
+    device_key: DeviceKey,
+    modify: impl FnOnce(&mut Device),
+    checks: InvariantChecks,
+) -> Result<(UpdateResponse, Operation), UpdateResponse> {
+    let Some(existing_device) = anchor.device(&device_key) else {
+        return Err(UpdateResponse::NotFound { device_key });
+    };
+
+    let mut new_device = existing_device.clone();
+    modify(&mut new_device);
+    let diff = device_diff(existing_device, &new_device);
+    anchor.modify_device_with_checks(&device_key, new_device, checks)?;
+
+    Ok((
+        UpdateResponse::Ok,
+        Operation::UpdateDevice {
+            device: device_key,
+            new_values: diff,
+        },
+    ))
+}
+
+/// Replaces a device of the given anchor with another and returns the operation to be archived.
+/// Violations of the anchor constraints (see [Anchor]) are returned as errors, in which case
+/// the anchor is left unchanged.
+pub fn replace_v2(
+    anchor: &mut Anchor,
+    old_device: DeviceKey,
+    new_device: DeviceData,
+) -> Result<(ReplaceResponse, Operation), ReplaceResponse> {
+    replace_with_checks(anchor, old_device, new_device, InvariantChecks::Immediate)
+}
+
+fn replace_with_checks(
+    anchor: &mut Anchor,
+    old_device: DeviceKey,
+    new_device: DeviceData,
+    checks: InvariantChecks,
+) -> Result<(ReplaceResponse, Operation), ReplaceResponse> {
+    // modify a copy, so that the removal is not persisted if adding the new device fails
+    let mut modified_anchor = anchor.clone();
+    modified_anchor.remove_device(&old_device)?;
+    let new_device = Device::from(new_device);
+    modified_anchor.add_device_with_checks(new_device.clone(), checks)?;
+    *anchor = modified_anchor;
+
+    Ok((
+        ReplaceResponse::Ok,
+        Operation::ReplaceDevice {
+            old_device,
+            new_device: DeviceDataWithoutAlias::from(new_device),
+        },
+    ))
+}
+
+/// Removes a device of the given anchor and returns the operation to be archived.
+/// Returns an error if the device does not exist or cannot be removed by the caller.
+pub fn remove_v2(
+    anchor: &mut Anchor,
+    device_key: DeviceKey,
+) -> Result<(RemoveResponse, Operation), RemoveResponse> {
+    anchor.remove_device(&device_key)?;
+    Ok((
+        RemoveResponse::Ok,
+        Operation::RemoveDevice { device: device_key },
+    ))
+}
+
+/// Applies the given operations to the anchor (in order) and returns the operations to be archived.
+/// If any of the operations fails, the anchor is left unchanged and the index of the failed operation is returned.
+/// The anchor invariants (e.g. the number of devices) are only checked for the final result, so
+/// intermediate states may violate them. If the final result violates them, the anchor is left unchanged.
+/// Panics if more than [MAX_OPERATIONS_PER_CALL] operations are provided.
+pub fn apply_operations(
+    anchor: &mut Anchor,
+    ops: Vec<AnchorOp>,
+) -> Result<(ApplyOperationsResponse, Vec<Operation>), ApplyOperationsResponse> {
+    if ops.len() > MAX_OPERATIONS_PER_CALL {
+        trap(&format!(
+            "too many operations: {}, limit {MAX_OPERATIONS_PER_CALL}",
+            ops.len()
+        ));
+    }
+
+    // modify a copy, so that no operation is persisted if any of them fails
+    let mut modified_anchor = anchor.clone();
+    let mut operations = vec![];
+    let mut only_removals = true;
+    for (index, op) in ops.into_iter().enumerate() {
+        only_removals &= matches!(op, AnchorOp::Remove { .. });
+        let result = match op {
+            AnchorOp::Add { device_data } => {
+                add_with_checks(&mut modified_anchor, device_data, InvariantChecks::Deferred)
+                    .map_err(AnchorOpError::Add)
+            }
+            AnchorOp::Update {
+                device_key,
+                device_data,
+            } => modify_device_with(
+                &mut modified_anchor,
+                device_key,
+                |device| device.apply_device_data(device_data),
+                InvariantChecks::Deferred,
+            )
+            .map_err(AnchorOpError::Update),
+            AnchorOp::Replace {
+                device_key,
+                device_data,
+            } => replace_with_checks(
+                &mut modified_anchor,
+                device_key,
+                device_data,
+                InvariantChecks::Deferred,
+            )
+            .map_err(AnchorOpError::Replace),
+            AnchorOp::Remove { device_key } => {
+                remove_v2(&mut modified_anchor, device_key).map_err(AnchorOpError::Remove)
+            }
+        };
+        match result {
+            Ok((_, operation)) => operations.push(operation),
+            Err(error) => {
+                return Err(ApplyOperationsResponse::Failed {
+                    index: index as u64,
+                    error,
+                })
+            }
+        }
+    }
+
+    // Removals cannot make the anchor violate the invariants, so anchors that violate them already
+    // (e.g. legacy anchors with multiple recovery phrases) can still be repaired by removing devices.
+    // See also the documentation on Anchor::remove_device.
+    if !only_removals {
+        modified_anchor.check_anchor_invariants().map_err(|err| {
+            ApplyOperationsResponse::InvariantViolation(AnchorInvariantViolation::from(err))
+        })?;
+    }
+
+    *anchor = modified_anchor;
+    Ok((ApplyOperationsResponse::Ok, operations))
+}
+
+impl From<AnchorError> for AddResponse {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::TooManyDevices { num_devices, limit } => AddResponse::TooManyDevices {
+                num_devices: num_devices as u64,
+                limit: limit as u64,
+            },
+            AnchorError::DeviceLimitExceeded {
+                field,
+                length,
+                limit,
+            } => AddResponse::DeviceFieldLimitExceeded {
+                field,
+                length: length as u64,
+                limit: limit as u64,
+            },
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                AddResponse::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::InvalidDeviceProtection { key_type } => {
+                AddResponse::InvalidDeviceProtection { key_type }
+            }
+            AnchorError::MultipleRecoveryPhrases => AddResponse::MultipleRecoveryPhrases,
+            AnchorError::DuplicateDevice { device_key } => {
+                AddResponse::DuplicateDevice { device_key }
+            }
+            err => trap(&format!("failed to add device: {err}")),
+        }
+    }
+}
+
+impl From<AnchorError> for UpdateResponse {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::NotFound { device_key } => UpdateResponse::NotFound { device_key },
+            AnchorError::CannotModifyDeviceKey => UpdateResponse::CannotModifyDeviceKey,
+            AnchorError::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            } => UpdateResponse::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            },
+            AnchorError::DeviceLimitExceeded {
+                field,
+                length,
+                limit,
+            } => UpdateResponse::DeviceFieldLimitExceeded {
+                field,
+                length: length as u64,
+                limit: limit as u64,
+            },
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                UpdateResponse::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::InvalidDeviceProtection { key_type } => {
+                UpdateResponse::InvalidDeviceProtection { key_type }
+            }
+            AnchorError::MultipleRecoveryPhrases => UpdateResponse::MultipleRecoveryPhrases,
+            err => trap(&format!("failed to modify device: {err}")),
+        }
+    }
+}
+
+impl From<AnchorError> for ReplaceResponse {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::NotFound { device_key } => ReplaceResponse::NotFound { device_key },
+            AnchorError::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            } => ReplaceResponse::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            },
+            AnchorError::TooManyDevices { num_devices, limit } => ReplaceResponse::TooManyDevices {
+                num_devices: num_devices as u64,
+                limit: limit as u64,
+            },
+            AnchorError::DeviceLimitExceeded {
+                field,
+                length,
+                limit,
+            } => ReplaceResponse::DeviceFieldLimitExceeded {
+                field,
+                length: length as u64,
+                limit: limit as u64,
+            },
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                ReplaceResponse::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::InvalidDeviceProtection { key_type } => {
+                ReplaceResponse::InvalidDeviceProtection { key_type }
+            }
+            AnchorError::MultipleRecoveryPhrases => ReplaceResponse::MultipleRecoveryPhrases,
+            AnchorError::DuplicateDevice { device_key } => {
+                ReplaceResponse::DuplicateDevice { device_key }
+            }
+            err => trap(&format!("failed to replace device: {err}")),
+        }
+    }
+}
+
+impl From<AnchorError> for AnchorInvariantViolation {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::TooManyDevices { num_devices, limit } => {
+                AnchorInvariantViolation::TooManyDevices {
+                    num_devices: num_devices as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                AnchorInvariantViolation::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::MultipleRecoveryPhrases => {
+                AnchorInvariantViolation::MultipleRecoveryPhrases
+            }
+            err => trap(&format!("failed to apply operations: {err}")),
+        }
+    }
+}
+
+impl From<AnchorError> for RemoveResponse {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::NotFound { device_key } => RemoveResponse::NotFound { device_key },
+            AnchorError::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            } => RemoveResponse::MutationNotAllowed {
+                authorized_principal,
+                actual_principal,
+            },
+            err => trap(&format!("failed to remove device: {err}")),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Audio variant of the captcha for users who cannot solve the image captcha.
+//!
+//! The canister cannot synthesize speech, so every character of the captcha (a digit from 1 to 9)
+//! is encoded as a sequence of that many beeps, followed by a pause. All beeps of a character share
+//! the same steady pitch, but their length and the gaps between them vary. The clip is overlaid
+//! with gliding tones of similar loudness at random positions (which listeners are told to ignore)
+//! and with noise, so that simply counting bursts of sound does not solve the captcha. The clip is
+//! returned as an 8 kHz, 8-bit mono WAV file.
+//!
+//! Note: audio captchas are still easier to solve automatically than the image captcha. Audio
+//! challenges are therefore rate limited per caller and in total (see [crate::rate_limit]) and come
+//! without an image, as an image restricted to digits would weaken the image captcha as well.
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ic_cdk::trap;
+use rand_core::RngCore;
+
+/// Characters of audio captchas: the digits that can be represented by a (non-zero) number of beeps.
+pub const AUDIO_CAPTCHA_CHARS: [char; 9] = ['1', '2', '3', '4', '5', '6', '7', '8', '9'];
+
+/// Returns `length` random characters of [AUDIO_CAPTCHA_CHARS].
+pub fn random_chars<T: RngCore>(rng: &mut T, length: usize) -> String {
+    // the modulo bias is negligible since the alphabet is much smaller than 2^32
+    (0..length)
+        .map(|_| AUDIO_CAPTCHA_CHARS[rng.next_u32() as usize % AUDIO_CAPTCHA_CHARS.len()])
+        .collect()
+}
+
+const SAMPLE_RATE: usize = 8_000;
+const MIN_BEEP_SAMPLES: usize = SAMPLE_RATE * 8 / 100;
+const MAX_BEEP_SAMPLES: usize = SAMPLE_RATE * 16 / 100;
+const MIN_GAP_SAMPLES: usize = SAMPLE_RATE * 8 / 100;
+const MAX_GAP_SAMPLES: usize = SAMPLE_RATE * 20 / 100;
+const MIN_PAUSE_SAMPLES: usize = SAMPLE_RATE * 6 / 10;
+const MAX_PAUSE_SAMPLES: usize = SAMPLE_RATE;
+const LEAD_IN_SAMPLES: usize = SAMPLE_RATE / 2;
+const BEEP_AMPLITUDE: f32 = 80.0;
+const MIN_DISTRACTOR_SAMPLES: usize = SAMPLE_RATE * 6 / 100;
+const MAX_DISTRACTOR_SAMPLES: usize = SAMPLE_RATE * 20 / 100;
+const DISTRACTORS_PER_SECOND: usize = 3;
+const NOISE_AMPLITUDE: u32 = 24;
+
+/// Returns the base64 encoded WAV clip for the given captcha characters.
+pub fn create_audio_captcha<T: RngCore>(rng: &mut T, chars: &str) -> String {
+    let mut samples = vec![0f32; LEAD_IN_SAMPLES];
+    for c in chars.chars() {
+        // the dummy captcha uses 'a', which is played as a single beep
+        let beeps = c.to_digit(10).unwrap_or(1);
+        // the steady pitch shared by the beeps of a character sets them apart from the distractors
+        let frequency = random_in(rng, 600, 900) as f32;
+        for _ in 0..beeps {
+            let length = random_in(rng, MIN_BEEP_SAMPLES, MAX_BEEP_SAMPLES);
+            samples.extend(tone(frequency, frequency, length, BEEP_AMPLITUDE));
+            samples.extend(vec![0f32; random_in(rng, MIN_GAP_SAMPLES, MAX_GAP_SAMPLES)]);
+        }
+        samples.extend(vec![
+            0f32;
+            random_in(rng, MIN_PAUSE_SAMPLES, MAX_PAUSE_SAMPLES)
+        ]);
+    }
+    add_distractors(rng, &mut samples);
+
+    let pcm: Vec<u8> = samples
+        .into_iter()
+        .map(|sample| {
+            let noise =
+                (rng.next_u32() % (2 * NOISE_AMPLITUDE + 1)) as f32 - NOISE_AMPLITUDE as f32;
+            (128.0 + sample + noise).clamp(0.0, 255.0) as u8
+        })
+        .collect();
+    BASE64.encode(wav(pcm))
+}
+
+/// Mixes gliding tones of random pitch, length and loudness into the samples at random positions.
+fn add_distractors<T: RngCore>(rng: &mut T, samples: &mut [f32]) {
+    let count = samples.len() * DISTRACTORS_PER_SECOND / SAMPLE_RATE;
+    for _ in 0..count {
+        let length = random_in(rng, MIN_DISTRACTOR_SAMPLES, MAX_DISTRACTOR_SAMPLES);
+        let start = random_in(rng, 0, samples.len().saturating_sub(length));
+        let from = random_in(rng, 300, 1200) as f32;
+        let to = random_in(rng, 300, 1200) as f32;
+        let amplitude = random_in(rng, 40, 70) as f32;
+        for (sample, distractor) in samples[start..]
+            .iter_mut()
+            .zip(tone(from, to, length, amplitude))
+        {
+            *sample += distractor;
+        }
+    }
+}
+
+/// Tone gliding from `from` to `to` Hz with a short fade in and out to avoid clicks.
+fn tone(from: f32, to: f32, length: usize, amplitude: f32) -> impl Iterator<Item = f32> {
+    let fade_samples = (length / 10).max(1);
+    let mut phase = 0f32;
+    (0..length).map(move |i| {
+        let fade = i.min(length - 1 - i).min(fade_samples) as f32 / fade_samples as f32;
+        let frequency = from + (to - from) * i as f32 / length as f32;
+        phase += 2.0 * std::f32::consts::PI * frequency / SAMPLE_RATE as f32;
+        amplitude * fade * phase.sin()
+    })
+}
+
+/// Returns a random number between `min` and `max` (inclusive).
+fn random_in<T: RngCore>(rng: &mut T, min: usize, max: usize) -> usize {
+    min + rng.next_u32() as usize % (max - min + 1)
+}
+
+/// Wraps 8-bit mono PCM samples in a WAV (RIFF) container.
+fn wav(pcm: Vec<u8>) -> Vec<u8> {
+    let data_len = u32::try_from(pcm.len()).unwrap_or_else(|_| trap("audio captcha too long"));
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes()); // size of the fmt chunk
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&1u16.to_le_bytes()); // bytes per sample
+    wav.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    wav.extend(pcm);
+    wav
+}
@@ -1,8 +1,11 @@
-use crate::anchor_management::{activity_bookkeeping, post_operation_bookkeeping};
-use crate::state::ChallengeInfo;
-use crate::storage::anchor::Device;
+use crate::anchor_management::{
+    activity_bookkeeping, audio_captcha, post_operation_bookkeeping, temp_keys,
+};
+use crate::rate_limit::RateLimitBucket;
+use crate::state::{ChallengeInfo, ChallengeKey};
+use crate::storage::anchor::{check_new_devices, Anchor, AnchorError, Device};
 use crate::storage::Salt;
-use crate::{secs_to_nanos, state};
+use crate::{anchor_allocation, rate_limit, secs_to_nanos, shards, state};
 use candid::Principal;
 use ic_cdk::api::time;
 use ic_cdk::{call, caller, trap};
@@ -15,27 +18,79 @@ use std::collections::HashMap;
 use captcha::filters::Wave;
 use lazy_static::lazy_static;
 
-mod rate_limit;
-
 // 5 mins
 const CAPTCHA_CHALLENGE_LIFETIME: u64 = secs_to_nanos(300);
 // How many captcha challenges we keep in memory (at most)
 const MAX_INFLIGHT_CHALLENGES: usize = 500;
-
-pub async fn create_challenge() -> Challenge {
+// How many of the captcha challenges in memory are created by anonymous callers (at most)
+const MAX_INFLIGHT_ANONYMOUS_CHALLENGES: usize = 250;
+// How many captcha challenges we keep in memory per (non-anonymous) caller (at most)
+const MAX_INFLIGHT_CHALLENGES_PER_CALLER: usize = 5;
+
+/// Creates a new captcha challenge. Audio challenges contain a captcha restricted to digits as a
+/// WAV clip instead of an image (see [audio_captcha]). They cannot be requested anonymously and are
+/// rate limited per caller as well as in total.
+///
+/// The number of challenges per caller is rate limited (if configured) which also bounds the cost
+/// of generating captchas. The anonymous principal is exempt, as the frontend creates challenges
+/// anonymously and all its users would otherwise share a single bucket. If there are too many
+/// inflight challenges of the same caller, the oldest challenges are evicted rather than rejecting
+/// new ones, so that flooding the canister with challenges cannot block registrations. Anonymous
+/// callers share a separate partition of the inflight challenges, which bounds their challenges
+/// like those of a single caller (albeit with a larger quota) and keeps anonymous floods from
+/// evicting the challenges of other callers (and vice versa).
+///
+/// If adaptive captchas are enabled, the captchas get harder when registrations are close to the
+/// registration rate limit (see [captcha_difficulty]).
+pub async fn create_challenge(kind: ChallengeKind) -> Challenge {
+    let creator = caller();
+    if creator != Principal::anonymous() {
+        // the token is deducted before the await so that the deduction is committed
+        rate_limit::process_rate_limit(RateLimitBucket::CreateChallenge(creator));
+    }
+    if kind == ChallengeKind::Audio {
+        if creator == Principal::anonymous() {
+            trap("audio challenges cannot be requested anonymously");
+        }
+        // check the caller first, so that a rate limited caller does not use up the total budget
+        rate_limit::process_rate_limit(RateLimitBucket::AudioChallenge(creator));
+        rate_limit::process_rate_limit(RateLimitBucket::AudioChallengeTotal);
+    }
     let mut rng = make_rng().await;
+    let difficulty = captcha_difficulty();
 
     state::inflight_challenges_mut(|inflight_challenges| {
         let now = time();
 
         // Prune old challenges. This drops all challenges that are older than
         // CAPTCHA_CHALLENGE_LIFETIME
+        let before = inflight_challenges.len();
         inflight_challenges.retain(|_, v| v.created > now - CAPTCHA_CHALLENGE_LIFETIME);
-
-        // Error out if there are too many inflight challenges
-        if inflight_challenges.len() >= MAX_INFLIGHT_CHALLENGES {
-            trap("too many inflight captchas");
-        }
+        let expired = (before - inflight_challenges.len()) as u64;
+
+        // Evict the oldest challenges of the caller's partition if there are too many inflight
+        // challenges
+        let evicted = if creator == Principal::anonymous() {
+            evict_oldest_challenges(
+                inflight_challenges,
+                MAX_INFLIGHT_ANONYMOUS_CHALLENGES,
+                |challenge| challenge.creator == creator,
+            )
+        } else {
+            evict_oldest_challenges(
+                inflight_challenges,
+                MAX_INFLIGHT_CHALLENGES_PER_CALLER,
+                |challenge| challenge.creator == creator,
+            ) + evict_oldest_challenges(
+                inflight_challenges,
+                MAX_INFLIGHT_CHALLENGES - MAX_INFLIGHT_ANONYMOUS_CHALLENGES,
+                |challenge| challenge.creator != Principal::anonymous(),
+            )
+        };
+        state::usage_metrics_mut(|metrics| {
+            metrics.challenge_metrics.expired += expired;
+            metrics.challenge_metrics.evicted += evicted;
+        });
 
         // actually create the challenge
 
@@ -47,7 +102,13 @@ pub async fn create_challenge() -> Challenge {
             let challenge_key = random_string(&mut rng, 10);
             if !inflight_challenges.contains_key(&challenge_key) {
                 // Then we create the CAPTCHA
-                let (Base64(png_base64), chars) = create_captcha(rng);
+                let (Base64(png_base64), chars) = create_captcha(&mut rng, &kind, difficulty);
+                let audio_wav_base64 = match kind {
+                    ChallengeKind::Image => None,
+                    ChallengeKind::Audio => {
+                        Some(audio_captcha::create_audio_captcha(&mut rng, &chars))
+                    }
+                };
 
                 // Finally insert
                 inflight_challenges.insert(
@@ -55,12 +116,15 @@ pub async fn create_challenge() -> Challenge {
                     ChallengeInfo {
                         created: now,
                         chars,
+                        creator,
                     },
                 );
 
+                state::usage_metrics_mut(|metrics| metrics.challenge_metrics.created += 1);
                 return Challenge {
                     png_base64,
                     challenge_key,
+                    audio_wav_base64,
                 };
             }
         }
@@ -69,6 +133,29 @@ pub async fn create_challenge() -> Challenge {
     })
 }
 
+/// Evicts the oldest of the challenges matching `filter` until there is room for another one
+/// within `limit`. Returns the number of evicted challenges.
+fn evict_oldest_challenges(
+    inflight_challenges: &mut HashMap<ChallengeKey, ChallengeInfo>,
+    limit: usize,
+    filter: impl Fn(&ChallengeInfo) -> bool,
+) -> u64 {
+    let mut matching: Vec<(Timestamp, ChallengeKey)> = inflight_challenges
+        .iter()
+        .filter(|(_, challenge)| filter(challenge))
+        .map(|(key, challenge)| (challenge.created, key.clone()))
+        .collect();
+    if matching.len() < limit {
+        return 0;
+    }
+    matching.sort();
+    let excess = matching.len() + 1 - limit;
+    for (_, key) in &matching[..excess] {
+        inflight_challenges.remove(key);
+    }
+    excess as u64
+}
+
 // Get a random number generator based on 'raw_rand'
 async fn make_rng() -> rand_chacha::ChaCha20Rng {
     let raw_rand: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
@@ -109,7 +196,11 @@ fn random_string<T: RngCore>(rng: &mut T, n: usize) -> String {
 }
 
 #[cfg(feature = "dummy_captcha")]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
+fn create_captcha<T: RngCore>(
+    rng: T,
+    _kind: &ChallengeKind,
+    _difficulty: usize,
+) -> (Base64, String) {
     let mut captcha = captcha::RngCaptcha::from_rng(rng);
     let captcha = captcha.set_chars(&vec!['a']).add_chars(1).view(96, 48);
 
@@ -138,17 +229,57 @@ lazy_static! {
 }
 
 const CAPTCHA_LENGTH: usize = 5;
+// Maximum number of characters added to the captcha under registration pressure
+const MAX_CAPTCHA_DIFFICULTY: usize = 2;
+
+/// Returns how much harder than usual new captchas should be, from 0 to [MAX_CAPTCHA_DIFFICULTY].
+///
+/// The difficulty only increases if adaptive captchas are enabled and less than half of the tokens
+/// of the registration rate limit are left, i.e. if the canister is under registration pressure.
+fn captcha_difficulty() -> usize {
+    let adaptive = state::persistent_state(|persistent_state| {
+        persistent_state.adaptive_captcha.unwrap_or(false)
+    });
+    if !adaptive {
+        return 0;
+    }
+    let Some((tokens, max_tokens)) = rate_limit::available_tokens(RateLimitBucket::Register) else {
+        return 0;
+    };
+    if tokens * 2 >= max_tokens {
+        0
+    } else if tokens * 10 >= max_tokens {
+        1
+    } else {
+        MAX_CAPTCHA_DIFFICULTY
+    }
+}
+
+/// Creates a captcha with [CAPTCHA_LENGTH] characters plus one character and stronger distortion
+/// per difficulty level.
+///
+/// Audio captchas can only represent digits and come without an image, as an image restricted to
+/// digits would be much easier to solve than a regular image captcha.
 #[cfg(not(feature = "dummy_captcha"))]
-fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
+fn create_captcha<T: RngCore>(
+    mut rng: T,
+    kind: &ChallengeKind,
+    difficulty: usize,
+) -> (Base64, String) {
+    if *kind == ChallengeKind::Audio {
+        let chars = audio_captcha::random_chars(&mut rng, CAPTCHA_LENGTH + difficulty);
+        return (Base64(String::new()), chars);
+    }
+
     let mut captcha = captcha::RngCaptcha::from_rng(rng);
     let mut chars = captcha.supported_chars();
     chars.retain(|c| !CHAR_REPLACEMENTS.contains_key(c));
 
     let captcha = captcha
         .set_chars(&chars)
-        .add_chars(CAPTCHA_LENGTH as u32)
-        .apply_filter(Wave::new(2.0, 20.0).horizontal())
-        .apply_filter(Wave::new(2.0, 20.0).vertical())
+        .add_chars((CAPTCHA_LENGTH + difficulty) as u32)
+        .apply_filter(Wave::new(2.0, 20.0 + 4.0 * difficulty as f64).horizontal())
+        .apply_filter(Wave::new(2.0, 20.0 + 4.0 * difficulty as f64).vertical())
         .view(220, 120);
     // if you ever change the size of the captcha, make sure to also change the
     // CSS in the frontend to match the new size (.c-captcha-placeholder)
@@ -162,10 +293,10 @@ fn create_captcha<T: RngCore>(rng: T) -> (Base64, String) {
 }
 
 // Check whether the CAPTCHA challenge was solved
-fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
+fn check_challenge(res: ChallengeAttempt) -> Result<(), ChallengeFailure> {
     // avoid processing too many characters
-    if res.chars.len() > CAPTCHA_LENGTH {
-        return Err(());
+    if res.chars.len() > CAPTCHA_LENGTH + MAX_CAPTCHA_DIFFICULTY {
+        return Err(ChallengeFailure::BadSolution);
     }
     // Normalize challenge attempts by replacing characters that are not in the captcha character set
     // with the respective replacement from CHAR_REPLACEMENTS.
@@ -178,40 +309,183 @@ fn check_challenge(res: ChallengeAttempt) -> Result<(), ()> {
     state::inflight_challenges_mut(|inflight_challenges| {
         match inflight_challenges.remove(&res.key) {
             Some(challenge) => {
+                if challenge.created <= time() - CAPTCHA_CHALLENGE_LIFETIME {
+                    return Err(ChallengeFailure::Expired);
+                }
                 if normalized_challenge_res != challenge.chars {
-                    return Err(());
+                    return Err(ChallengeFailure::BadSolution);
                 }
                 Ok(())
             }
-            None => Err(()),
+            None => Err(ChallengeFailure::UnknownKey),
         }
     })
 }
 
+enum ChallengeFailure {
+    BadSolution,
+    // never issued, already used, expired and pruned or evicted
+    UnknownKey,
+    Expired,
+}
+
+/// Checks the challenge and records the outcome in the challenge metrics.
+fn verify_challenge(challenge_result: ChallengeAttempt) -> Result<(), ()> {
+    let challenge_result = check_challenge(challenge_result);
+    state::usage_metrics_mut(|metrics| {
+        let challenges = &mut metrics.challenge_metrics;
+        match challenge_result {
+            Ok(()) => challenges.solved += 1,
+            Err(ref failure) => {
+                challenges.failed += 1;
+                match failure {
+                    ChallengeFailure::BadSolution => challenges.bad_solution += 1,
+                    ChallengeFailure::UnknownKey => challenges.unknown_key += 1,
+                    ChallengeFailure::Expired => challenges.expired_attempt += 1,
+                }
+            }
+        }
+    });
+    challenge_result.map_err(|_| ())
+}
+
+fn trap_if_not_self_authenticating(device: &Device) {
+    if caller() != Principal::self_authenticating(&device.pubkey) {
+        trap(&format!(
+            "{} could not be authenticated against {:?}",
+            caller(),
+            device.pubkey
+        ));
+    }
+}
+
 pub fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) -> RegisterResponse {
-    rate_limit::process_rate_limit();
-    if let Err(()) = check_challenge(challenge_result) {
+    rate_limit::process_rate_limit(RateLimitBucket::Register);
+    if let Err(()) = verify_challenge(challenge_result) {
         return RegisterResponse::BadChallenge;
     }
 
     let device = Device::from(device_data);
-    if caller() != Principal::self_authenticating(&device.pubkey) {
+    trap_if_not_self_authenticating(&device);
+
+    let Some((anchor_number, anchor)) = anchor_allocation::allocate_anchor() else {
+        return match shards::registration_redirect() {
+            Some(canister_id) => RegisterResponse::Redirect(canister_id),
+            None => RegisterResponse::CanisterFull,
+        };
+    };
+    create_anchor(anchor_number, anchor, vec![device]);
+    RegisterResponse::Registered {
+        user_number: anchor_number,
+    }
+}
+
+/// Registers a new anchor without devices for the calling temporary key (see [temp_keys]).
+/// The temporary key can add devices to the anchor until it expires. If no device is added by then,
+/// the anchor is released again.
+pub fn register_with_temp_key(
+    temp_key: DeviceKey,
+    challenge_result: ChallengeAttempt,
+) -> RegisterWithTempKeyResponse {
+    rate_limit::process_rate_limit(RateLimitBucket::Register);
+    if caller() != Principal::self_authenticating(&temp_key) {
         trap(&format!(
             "{} could not be authenticated against {:?}",
             caller(),
-            device.pubkey
+            temp_key
         ));
     }
+    if let Err(()) = verify_challenge(challenge_result) {
+        return RegisterWithTempKeyResponse::BadChallenge;
+    }
 
-    let allocation = state::storage_borrow_mut(|storage| storage.allocate_anchor());
-    let Some((anchor_number, mut anchor)) = allocation else {
-        return RegisterResponse::CanisterFull;
+    let Some((anchor_number, anchor)) = anchor_allocation::allocate_anchor() else {
+        return match shards::registration_redirect() {
+            Some(canister_id) => RegisterWithTempKeyResponse::Redirect(canister_id),
+            None => RegisterWithTempKeyResponse::CanisterFull,
+        };
     };
+    state::storage_borrow_mut(|storage| {
+        storage.write(anchor_number, anchor).unwrap_or_else(|err| {
+            trap(&format!(
+                "failed to write data of anchor {anchor_number}: {err}"
+            ))
+        });
+    });
+    let temp_key_expiration = temp_keys::add_temp_key(anchor_number, temp_key);
+    post_operation_bookkeeping(anchor_number, Operation::RegisterAnchorWithTempKey);
+    RegisterWithTempKeyResponse::Registered {
+        user_number: anchor_number,
+        temp_key_expiration,
+    }
+}
+
+/// Registers a new anchor with the given authentication device and optional recovery device.
+///
+/// The devices are checked before the challenge, so that the challenge can be retried with
+/// corrected devices.
+pub fn register_v2(
+    authentication_device: DeviceData,
+    recovery_device: Option<DeviceData>,
+    challenge_result: ChallengeAttempt,
+) -> RegisterV2Response {
+    rate_limit::process_rate_limit(RateLimitBucket::Register);
+
+    let authentication_device = Device::from(authentication_device);
+    trap_if_not_self_authenticating(&authentication_device);
+
+    let mut devices = vec![authentication_device];
+    if let Some(recovery_device) = recovery_device {
+        if recovery_device.purpose != Purpose::Recovery {
+            return RegisterV2Response::InvalidRecoveryDevice;
+        }
+        devices.push(Device::from(recovery_device));
+    }
+    if let Err(err) = check_new_devices(&[], &devices) {
+        return RegisterV2Response::from(err);
+    }
 
+    if let Err(()) = verify_challenge(challenge_result) {
+        return RegisterV2Response::BadChallenge;
+    }
+    let Some((anchor_number, anchor)) = anchor_allocation::allocate_anchor() else {
+        return match shards::registration_redirect() {
+            Some(canister_id) => RegisterV2Response::Redirect(canister_id),
+            None => RegisterV2Response::CanisterFull,
+        };
+    };
+    create_anchor(anchor_number, anchor, devices);
+    RegisterV2Response::Registered {
+        user_number: anchor_number,
+    }
+}
+
+/// Registers the anchor number reserved for the calling device (see [anchor_allocation::reserve_anchor]).
+/// Traps if the caller does not match the device.
+pub fn claim_reserved_anchor(
+    anchor_number: AnchorNumber,
+    device_data: DeviceData,
+) -> ClaimReservedAnchorResponse {
+    let device = Device::from(device_data);
+    trap_if_not_self_authenticating(&device);
+
+    let Some(anchor) = anchor_allocation::claim_reservation(anchor_number, &device.pubkey) else {
+        return ClaimReservedAnchorResponse::NotReserved;
+    };
+    create_anchor(anchor_number, anchor, vec![device]);
+    ClaimReservedAnchorResponse::Claimed {
+        user_number: anchor_number,
+    }
+}
+
+/// Adds the devices to the freshly allocated anchor, writes the anchor and archives the registration.
+/// The first device is the one used to register the anchor, any other devices are archived as
+/// additional devices.
+fn create_anchor(anchor_number: AnchorNumber, mut anchor: Anchor, devices: Vec<Device>) {
     anchor
-        .add_device(device.clone())
+        .add_devices(devices.clone())
         .unwrap_or_else(|err| trap(&format!("failed to register anchor {anchor_number}: {err}")));
-    activity_bookkeeping(&mut anchor, &device.pubkey);
+    activity_bookkeeping(&mut anchor, &devices[0].pubkey);
 
     // write anchor to stable memory
     state::storage_borrow_mut(|storage| {
@@ -222,11 +496,53 @@ pub fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) ->
         });
     });
 
-    let operation = Operation::RegisterAnchor {
-        device: DeviceDataWithoutAlias::from(device),
-    };
-    post_operation_bookkeeping(anchor_number, operation);
-    RegisterResponse::Registered {
-        user_number: anchor_number,
+    let mut devices = devices.into_iter();
+    if let Some(device) = devices.next() {
+        let operation = Operation::RegisterAnchor {
+            device: DeviceDataWithoutAlias::from(device),
+        };
+        post_operation_bookkeeping(anchor_number, operation);
+    }
+    for device in devices {
+        let operation = Operation::AddDevice {
+            device: DeviceDataWithoutAlias::from(device),
+        };
+        post_operation_bookkeeping(anchor_number, operation);
+    }
+}
+
+impl From<AnchorError> for RegisterV2Response {
+    fn from(err: AnchorError) -> Self {
+        match err {
+            AnchorError::TooManyDevices { num_devices, limit } => {
+                RegisterV2Response::TooManyDevices {
+                    num_devices: num_devices as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::DeviceLimitExceeded {
+                field,
+                length,
+                limit,
+            } => RegisterV2Response::DeviceFieldLimitExceeded {
+                field,
+                length: length as u64,
+                limit: limit as u64,
+            },
+            AnchorError::CumulativeDataLimitExceeded { length, limit } => {
+                RegisterV2Response::CumulativeDataLimitExceeded {
+                    length: length as u64,
+                    limit: limit as u64,
+                }
+            }
+            AnchorError::InvalidDeviceProtection { key_type } => {
+                RegisterV2Response::InvalidDeviceProtection { key_type }
+            }
+            AnchorError::MultipleRecoveryPhrases => RegisterV2Response::MultipleRecoveryPhrases,
+            AnchorError::DuplicateDevice { device_key } => {
+                RegisterV2Response::DuplicateDevice { device_key }
+            }
+            err => trap(&format!("failed to register anchor: {err}")),
+        }
     }
 }
@@ -0,0 +1,92 @@
+//! Temporary keys of anchors registered without a WebAuthn device
+//! (see [register_with_temp_key](crate::anchor_management::registration::register_with_temp_key)).
+//!
+//! The temporary key (e.g. a session key of the browser) can be used to add devices to its anchor
+//! until it expires, so that a user can complete the registration even if the creation of a passkey
+//! failed. The temporary key cannot be used for any other operation.
+//!
+//! Expired keys are removed by a timer. Anchors that have not received a device before their key
+//! expires are released again, as nobody could ever authenticate for them. The keys are kept in the
+//! persistent state, so that registrations in progress survive upgrades. As timers do not survive
+//! upgrades, the removal of the expired keys is rescheduled in `post_upgrade`.
+use crate::anchor_allocation;
+use crate::archive::archive_operation;
+use crate::state::TempKey;
+use crate::{secs_to_nanos, state};
+use candid::Principal;
+use ic_cdk::api::{id, time};
+use ic_cdk::{caller, trap};
+use ic_cdk_timers::set_timer;
+use internet_identity_interface::archive::types::Operation;
+use internet_identity_interface::internet_identity::types::*;
+use std::time::Duration;
+
+// 10 mins
+const TEMP_KEY_DURATION: u64 = secs_to_nanos(600);
+// How many temporary keys can be valid simultaneously
+const MAX_TEMP_KEYS: usize = 10_000;
+
+/// Adds a temporary key to the given (freshly registered) anchor and returns its expiration.
+pub fn add_temp_key(anchor_number: AnchorNumber, key: DeviceKey) -> Timestamp {
+    let expiration = time() + TEMP_KEY_DURATION;
+    remove_expired_temp_keys();
+    state::temp_keys_mut(|temp_keys| {
+        if temp_keys.len() >= MAX_TEMP_KEYS {
+            trap("too many temporary keys, try again later");
+        }
+        temp_keys.insert(anchor_number, TempKey { key, expiration });
+    });
+    set_timer(
+        Duration::from_nanos(TEMP_KEY_DURATION),
+        remove_expired_temp_keys,
+    );
+    expiration
+}
+
+/// Returns whether the caller is authenticated by the temporary key of the given anchor.
+pub fn is_temp_key_caller(anchor_number: AnchorNumber) -> bool {
+    state::temp_keys(|temp_keys| {
+        temp_keys.get(&anchor_number).map_or(false, |temp_key| {
+            temp_key.expiration > time()
+                && caller() == Principal::self_authenticating(&temp_key.key)
+        })
+    })
+}
+
+/// Removes the expired temporary keys and releases their anchors if no device was added.
+fn remove_expired_temp_keys() {
+    let now = time();
+    let expired: Vec<AnchorNumber> = state::temp_keys_mut(|temp_keys| {
+        let expired = temp_keys
+            .iter()
+            .filter(|(_, temp_key)| temp_key.expiration <= now)
+            .map(|(anchor_number, _)| *anchor_number)
+            .collect();
+        temp_keys.retain(|_, temp_key| temp_key.expiration > now);
+        expired
+    });
+    release_unused_anchors(expired);
+}
+
+/// Schedules the removal of the temporary keys loaded from the persistent state. To be called
+/// after an upgrade, which drops the timers set by [add_temp_key].
+pub fn resume_temp_key_expiration() {
+    let Some(last_expiration) = state::temp_keys(|temp_keys| {
+        temp_keys.values().map(|temp_key| temp_key.expiration).max()
+    }) else {
+        return;
+    };
+    set_timer(
+        Duration::from_nanos(last_expiration.saturating_sub(time())),
+        remove_expired_temp_keys,
+    );
+}
+
+fn release_unused_anchors(anchor_numbers: Vec<AnchorNumber>) {
+    for anchor_number in anchor_numbers {
+        if state::anchor(anchor_number).devices().is_empty() {
+            anchor_allocation::release_anchor(anchor_number);
+            archive_operation(anchor_number, id(), Operation::ReleaseAnchor);
+        }
+    }
+}
@@ -1,23 +1,82 @@
 use crate::anchor_management::add;
-use crate::state::RegistrationState::{DeviceRegistrationModeActive, DeviceTentativelyAdded};
-use crate::state::TentativeDeviceRegistration;
+use crate::rate_limit::RateLimitBucket;
+use crate::state::{TentativeDevice, TentativeDeviceRegistration, TentativeDeviceVerification};
 use crate::storage::anchor::Anchor;
-use crate::{secs_to_nanos, state};
+use crate::{rate_limit, secs_to_nanos, state};
 use candid::Principal;
 use ic_cdk::api::time;
-use ic_cdk::{call, trap};
-use internet_identity_interface::archive::types::Operation;
+use ic_cdk::{call, caller, trap};
+use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
 use internet_identity_interface::internet_identity::types::*;
-use std::collections::HashMap;
-use AddTentativeDeviceResponse::{AddedTentatively, AnotherDeviceTentativelyAdded};
-use VerifyTentativeDeviceResponse::{NoDeviceToVerify, WrongCode};
+use serde_bytes::ByteBuf;
+use std::collections::{HashMap, HashSet};
+use AddTentativeDeviceResponse::{
+    AddedTentatively, AnotherDeviceTentativelyAdded, PossessionChallenge,
+};
+use VerifyTentativeDeviceResponse::{NoDeviceToVerify, PossessionNotProven, WrongCode};
 
 // 15 mins
-const REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(900);
+const DEFAULT_REGISTRATION_MODE_DURATION: u64 = secs_to_nanos(900);
 // How many anchors can be in registration mode simultaneously
 const MAX_ANCHORS_IN_REGISTRATION_MODE: usize = 10_000;
 // How many verification attempts are given for a tentative device
-const MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
+const DEFAULT_MAX_DEVICE_REGISTRATION_ATTEMPTS: u8 = 3;
+// How many devices can be added tentatively per anchor
+const DEFAULT_MAX_TENTATIVE_DEVICES: u8 = 3;
+const DEFAULT_VERIFICATION_CODE_LENGTH: u8 = 6;
+const DEFAULT_VERIFICATION_CODE_ALPHABET: &str = "0123456789";
+// Two random bytes are used per character of the verification code.
+const MAX_VERIFICATION_CODE_LENGTH: u8 = 16;
+const MAX_VERIFICATION_CODE_ALPHABET_SIZE: usize = 64;
+// Minimum number of distinct verification codes (i.e. as many as the default 6 digit codes)
+const MIN_VERIFICATION_CODE_SPACE: u128 = 1_000_000;
+
+/// Returns the configuration of the device registration flow.
+pub fn device_registration_config() -> DeviceRegistrationConfig {
+    state::persistent_state(|persistent_state| persistent_state.device_registration.clone())
+        .unwrap_or(DeviceRegistrationConfig {
+            verification_code_length: DEFAULT_VERIFICATION_CODE_LENGTH,
+            verification_code_alphabet: DEFAULT_VERIFICATION_CODE_ALPHABET.to_string(),
+            max_verification_attempts: DEFAULT_MAX_DEVICE_REGISTRATION_ATTEMPTS,
+            max_tentative_devices: DEFAULT_MAX_TENTATIVE_DEVICES,
+            registration_mode_duration_ns: DEFAULT_REGISTRATION_MODE_DURATION,
+            possession_proof: false,
+        })
+}
+
+/// Checks that the configuration yields verification codes that are hard enough to guess.
+pub fn check_device_registration_config(config: &DeviceRegistrationConfig) -> Result<(), String> {
+    let alphabet: HashSet<char> = config.verification_code_alphabet.chars().collect();
+    if alphabet.len() != config.verification_code_alphabet.chars().count() {
+        return Err("verification code alphabet must not contain duplicate characters".to_string());
+    }
+    if alphabet.len() > MAX_VERIFICATION_CODE_ALPHABET_SIZE {
+        return Err(format!(
+            "verification code alphabet must not have more than {MAX_VERIFICATION_CODE_ALPHABET_SIZE} characters"
+        ));
+    }
+    if config.verification_code_length > MAX_VERIFICATION_CODE_LENGTH {
+        return Err(format!(
+            "verification code length must not exceed {MAX_VERIFICATION_CODE_LENGTH}"
+        ));
+    }
+    let code_space = (alphabet.len() as u128).pow(config.verification_code_length as u32);
+    if code_space < MIN_VERIFICATION_CODE_SPACE {
+        return Err(format!(
+            "verification codes must have at least {MIN_VERIFICATION_CODE_SPACE} possible values, got {code_space}"
+        ));
+    }
+    if config.max_verification_attempts == 0 {
+        return Err("at least one verification attempt must be allowed".to_string());
+    }
+    if config.max_tentative_devices == 0 {
+        return Err("at least one tentative device must be allowed".to_string());
+    }
+    if config.registration_mode_duration_ns == 0 {
+        return Err("registration mode duration must not be zero".to_string());
+    }
+    Ok(())
+}
 
 /// Enables device registration mode for the given anchor and returns the expiration timestamp (when it will be disabled again).
 /// If the device registration mode is already active it will just return the expiration timestamp again.
@@ -31,12 +90,14 @@ pub fn enter_device_registration_mode(anchor_number: AnchorNumber) -> Timestamp
         match registrations.get(&anchor_number) {
             Some(TentativeDeviceRegistration { expiration, .. }) => *expiration, // already enabled, just return the existing expiration
             None => {
-                let expiration = time() + REGISTRATION_MODE_DURATION;
+                let expiration =
+                    time() + device_registration_config().registration_mode_duration_ns;
                 registrations.insert(
                     anchor_number,
                     TentativeDeviceRegistration {
                         expiration,
-                        state: DeviceRegistrationModeActive,
+                        tentative_devices: vec![],
+                        failed_attempts: 0,
                     },
                 );
                 expiration
@@ -52,11 +113,23 @@ pub fn exit_device_registration_mode(anchor_number: AnchorNumber) {
     });
 }
 
+/// Adds the device as a candidate to be verified by the user.
+/// Multiple candidates (up to the configured maximum) can be added so that a single (malicious)
+/// device cannot block the registration of other devices. The user verifies the right candidate
+/// (using its verification code or by confirming it) and can reject the others.
 pub async fn add_tentative_device(
     anchor_number: AnchorNumber,
     device_data: DeviceData,
 ) -> AddTentativeDeviceResponse {
-    let verification_code = new_verification_code().await;
+    // This call is unauthenticated, so only anchors in registration mode are charged. Otherwise,
+    // anyone could create rate limit buckets for arbitrary anchors.
+    if !registration_mode_active(anchor_number, time()) {
+        return AddTentativeDeviceResponse::DeviceRegistrationModeOff;
+    }
+    // the token is deducted before the await so that the deduction is committed
+    rate_limit::process_rate_limit(RateLimitBucket::AddTentativeDevice(anchor_number));
+    let config = device_registration_config();
+    let randomness = random_bytes().await;
     let now = time();
 
     state::tentative_device_registrations_mut(|registrations| {
@@ -68,15 +141,35 @@ pub async fn add_tentative_device(
                 AddTentativeDeviceResponse::DeviceRegistrationModeOff
             }
             Some(TentativeDeviceRegistration {
-                state: DeviceTentativelyAdded { .. },
-                ..
-            }) => AnotherDeviceTentativelyAdded,
-            Some(mut registration) => {
-                registration.state = DeviceTentativelyAdded {
-                    tentative_device: device_data,
-                    failed_attempts: 0,
-                    verification_code: verification_code.clone(),
-                };
+                tentative_devices, ..
+            }) if tentative_devices.len() >= config.max_tentative_devices as usize
+                || tentative_devices
+                    .iter()
+                    .any(|tentative| tentative.device.pubkey == device_data.pubkey) =>
+            {
+                AnotherDeviceTentativelyAdded
+            }
+            Some(registration) if config.possession_proof => {
+                registration.tentative_devices.push(TentativeDevice {
+                    device: device_data,
+                    verification: TentativeDeviceVerification::PossessionProof {
+                        nonce: randomness.clone(),
+                        proven: false,
+                    },
+                });
+                PossessionChallenge {
+                    device_registration_timeout: registration.expiration,
+                    nonce: ByteBuf::from(randomness),
+                }
+            }
+            Some(registration) => {
+                let verification_code = new_verification_code(&randomness, &config);
+                registration.tentative_devices.push(TentativeDevice {
+                    device: device_data,
+                    verification: TentativeDeviceVerification::Code {
+                        verification_code: verification_code.clone(),
+                    },
+                });
                 AddedTentatively {
                     device_registration_timeout: registration.expiration,
                     verification_code,
@@ -104,13 +197,117 @@ pub fn verify_tentative_device(
     }
 }
 
-/// Checks the device verification code for a tentative device.
-/// If valid, returns the device to be added and exits device registration mode
+/// Adds the tentative device with the given key to the anchor, if it has proven possession of its
+/// key. See [verify_tentative_device] on how the [Result] is handled.
+pub fn confirm_tentative_device(
+    anchor: &mut Anchor,
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+) -> Result<(VerifyTentativeDeviceResponse, Operation), VerifyTentativeDeviceResponse> {
+    let device = state::tentative_device_registrations_mut(|registrations| {
+        prune_expired_tentative_device_registrations(registrations);
+
+        let registration = registrations
+            .get(&anchor_number)
+            .ok_or(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)?;
+        let Some(tentative) = registration
+            .tentative_devices
+            .iter()
+            .find(|tentative| tentative.device.pubkey == device_key) else {
+            return Err(NoDeviceToVerify);
+        };
+        match tentative.verification {
+            TentativeDeviceVerification::PossessionProof { proven: true, .. } => {
+                let device = tentative.device.clone();
+                registrations.remove(&anchor_number);
+                Ok(device)
+            }
+            TentativeDeviceVerification::PossessionProof { proven: false, .. } => {
+                Err(PossessionNotProven)
+            }
+            // devices verified by code cannot be confirmed without the code
+            TentativeDeviceVerification::Code { .. } => Err(NoDeviceToVerify),
+        }
+    })?;
+    let operation = add(anchor, device);
+    Ok((VerifyTentativeDeviceResponse::Verified, operation))
+}
+
+/// Removes the tentative device with the given key. The rejection is archived (to keep track of
+/// potentially malicious registration attempts). See [verify_tentative_device] on how the [Result]
+/// is handled.
+pub fn reject_tentative_device(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+) -> Result<(RejectTentativeDeviceResponse, Operation), RejectTentativeDeviceResponse> {
+    state::tentative_device_registrations_mut(|registrations| {
+        prune_expired_tentative_device_registrations(registrations);
+
+        let registration = registrations
+            .get_mut(&anchor_number)
+            .ok_or(RejectTentativeDeviceResponse::DeviceRegistrationModeOff)?;
+        let Some(index) = registration
+            .tentative_devices
+            .iter()
+            .position(|tentative| tentative.device.pubkey == device_key) else {
+            return Err(RejectTentativeDeviceResponse::NotFound);
+        };
+        let rejected = registration.tentative_devices.remove(index);
+        let operation = Operation::RejectTentativeDevice {
+            device: DeviceDataWithoutAlias::from(rejected.device),
+        };
+        Ok((RejectTentativeDeviceResponse::Rejected, operation))
+    })
+}
+
+/// Marks the tentative device as having proven possession of its key, if the call is signed by
+/// the tentative device and contains the nonce issued in [add_tentative_device].
+pub fn prove_tentative_device_possession(
+    anchor_number: AnchorNumber,
+    nonce: ByteBuf,
+) -> ProveDevicePossessionResponse {
+    let caller = caller();
+    state::tentative_device_registrations_mut(|registrations| {
+        prune_expired_tentative_device_registrations(registrations);
+
+        let Some(registration) = registrations.get_mut(&anchor_number) else {
+            return ProveDevicePossessionResponse::DeviceRegistrationModeOff;
+        };
+        if registration.tentative_devices.is_empty() {
+            return ProveDevicePossessionResponse::NoDeviceToVerify;
+        }
+        let Some(tentative) = registration
+            .tentative_devices
+            .iter_mut()
+            .find(|tentative| caller == Principal::self_authenticating(&tentative.device.pubkey)) else {
+            return ProveDevicePossessionResponse::NotSignedByTentativeDevice;
+        };
+        match &mut tentative.verification {
+            TentativeDeviceVerification::PossessionProof {
+                nonce: expected_nonce,
+                proven,
+            } => {
+                if nonce.as_slice() != expected_nonce.as_slice() {
+                    return ProveDevicePossessionResponse::WrongNonce;
+                }
+                *proven = true;
+                ProveDevicePossessionResponse::Proven
+            }
+            TentativeDeviceVerification::Code { .. } => {
+                ProveDevicePossessionResponse::NoDeviceToVerify
+            }
+        }
+    })
+}
+
+/// Checks the device verification code against the tentative devices.
+/// If valid, returns the matching device to be added and exits device registration mode
 /// If invalid, returns the appropriate error to send to the client and increases failed attempts. Exits device registration mode if there are no retries left.
 fn get_verified_device(
     anchor_number: AnchorNumber,
     user_verification_code: DeviceVerificationCode,
 ) -> Result<DeviceData, VerifyTentativeDeviceResponse> {
+    let max_attempts = device_registration_config().max_verification_attempts;
     state::tentative_device_registrations_mut(|registrations| {
         prune_expired_tentative_device_registrations(registrations);
 
@@ -118,50 +315,81 @@ fn get_verified_device(
             .remove(&anchor_number)
             .ok_or(VerifyTentativeDeviceResponse::DeviceRegistrationModeOff)?;
 
-        match tentative_registration.state {
-            DeviceRegistrationModeActive => Err(NoDeviceToVerify),
-            DeviceTentativelyAdded {
-                failed_attempts,
-                verification_code,
-                tentative_device,
-            } => {
-                if user_verification_code == verification_code {
-                    return Ok(tentative_device);
-                }
+        // devices proving possession are confirmed without a code
+        let has_code_verification =
+            tentative_registration
+                .tentative_devices
+                .iter()
+                .any(|tentative| {
+                    matches!(
+                        tentative.verification,
+                        TentativeDeviceVerification::Code { .. }
+                    )
+                });
+        if !has_code_verification {
+            registrations.insert(anchor_number, tentative_registration);
+            return Err(NoDeviceToVerify);
+        }
 
-                let failed_attempts = failed_attempts + 1;
-                if failed_attempts < MAX_DEVICE_REGISTRATION_ATTEMPTS {
-                    tentative_registration.state = DeviceTentativelyAdded {
-                        failed_attempts,
-                        tentative_device,
-                        verification_code,
-                    };
-                    // reinsert because retries are allowed
-                    registrations.insert(anchor_number, tentative_registration);
-                }
-                Err(WrongCode {
-                    retries_left: (MAX_DEVICE_REGISTRATION_ATTEMPTS - failed_attempts),
-                })
-            }
+        let verified = tentative_registration
+            .tentative_devices
+            .iter()
+            .position(|tentative| {
+                matches!(
+                    &tentative.verification,
+                    TentativeDeviceVerification::Code { verification_code }
+                        if *verification_code == user_verification_code
+                )
+            });
+        if let Some(index) = verified {
+            return Ok(tentative_registration
+                .tentative_devices
+                .remove(index)
+                .device);
+        }
+
+        tentative_registration.failed_attempts += 1;
+        let failed_attempts = tentative_registration.failed_attempts;
+        if failed_attempts < max_attempts {
+            // reinsert because retries are allowed
+            registrations.insert(anchor_number, tentative_registration);
         }
+        Err(WrongCode {
+            retries_left: max_attempts.saturating_sub(failed_attempts),
+        })
     })
 }
 
-/// Return a decimal representation of a random `u32` to be used as verification code
-async fn new_verification_code() -> DeviceVerificationCode {
+/// Returns 32 bytes of randomness from the management canister.
+async fn random_bytes() -> Vec<u8> {
     let res: Vec<u8> = match call(Principal::management_canister(), "raw_rand", ()).await {
         Ok((res,)) => res,
         Err((_, err)) => trap(&format!("failed to get randomness: {err}")),
     };
-    let rand = u32::from_be_bytes(res[..4].try_into().unwrap_or_else(|_| {
+    if res.len() < 2 * MAX_VERIFICATION_CODE_LENGTH as usize {
         trap(&format!(
-            "when creating device verification code from raw_rand output, expected raw randomness to be of length 32, got {}",
+            "expected raw randomness to be of length 32, got {}",
             res.len()
         ));
-    }));
+    }
+    res
+}
 
-    // the format! makes sure that the resulting string has exactly 6 characters.
-    format!("{:06}", (rand % 1_000_000))
+/// Returns a verification code of the configured length and alphabet derived from the given randomness.
+fn new_verification_code(
+    randomness: &[u8],
+    config: &DeviceRegistrationConfig,
+) -> DeviceVerificationCode {
+    let alphabet: Vec<char> = config.verification_code_alphabet.chars().collect();
+    // the modulo bias is negligible since the alphabet is much smaller than 2^16
+    randomness
+        .chunks_exact(2)
+        .take(config.verification_code_length as usize)
+        .map(|chunk| {
+            let rand = u16::from_be_bytes([chunk[0], chunk[1]]) as usize;
+            alphabet[rand % alphabet.len()]
+        })
+        .collect()
 }
 
 /// Removes __all__ expired device registrations -> there is no need to check expiration immediately after pruning.
@@ -172,3 +400,13 @@ fn prune_expired_tentative_device_registrations(
 
     registrations.retain(|_, TentativeDeviceRegistration { expiration, .. }| *expiration > now)
 }
+
+/// Returns whether the anchor is in device registration mode at the given time.
+fn registration_mode_active(anchor_number: AnchorNumber, now: Timestamp) -> bool {
+    state::tentative_device_registrations(|registrations| {
+        registrations
+            .get(&anchor_number)
+            .map(|registration| registration.expiration > now)
+            .unwrap_or(false)
+    })
+}
@@ -0,0 +1,114 @@
+//! Export and import of anchors in their stable memory representation.
+//!
+//! This allows to back up anchors and to move a range of anchors to another II canister (e.g. when
+//! splitting a canister). Anchors are transferred as candid encoded [Anchor] records (the same
+//! representation as used in stable memory) so that no information (e.g. last usage timestamps)
+//! is lost along the way.
+use crate::archive::archive_operation;
+use crate::storage::anchor::Anchor;
+use crate::storage::StorageError;
+use crate::{anchor_allocation, state};
+use ic_cdk::{caller, trap};
+use internet_identity_interface::archive::types::{DeviceDataWithoutAlias, Operation};
+use internet_identity_interface::internet_identity::types::*;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+/// Maximum number of anchors returned by a single call to [export_anchors].
+const EXPORT_BATCH_SIZE: u64 = 250;
+
+/// Exports the anchors in `range` [lo, hi) (all anchors of this canister if `None`) starting at `cursor`.
+/// The returned batch contains the cursor to be used to fetch the next batch, if any.
+pub fn export_anchors(
+    range: Option<(AnchorNumber, AnchorNumber)>,
+    cursor: Option<AnchorNumber>,
+) -> AnchorExportBatch {
+    let (start, end) = state::storage_borrow(|storage| {
+        let (lo, _) = storage.assigned_anchor_number_range();
+        let next_anchor_number = lo + storage.anchor_count() as u64;
+        let (range_lo, range_hi) = range.unwrap_or((lo, next_anchor_number));
+        if range_lo > range_hi {
+            trap(&format!("invalid anchor range [{range_lo}, {range_hi})"));
+        }
+        let start = cursor.unwrap_or(range_lo).max(range_lo).max(lo);
+        (start, range_hi.min(next_anchor_number))
+    });
+    let batch_end = end.min(start.saturating_add(EXPORT_BATCH_SIZE));
+
+    // skipped anchor numbers are not allocated and therefore not exported
+    let anchors = (start..batch_end)
+        .filter_map(|anchor_number| Some((anchor_number, state::allocated_anchor(anchor_number)?)))
+        .map(|(anchor_number, anchor)| {
+            let anchor = candid::encode_one(anchor).unwrap_or_else(|err| {
+                trap(&format!("failed to encode anchor {anchor_number}: {err}"))
+            });
+            ExportedAnchor {
+                anchor_number,
+                sha256: ByteBuf::from(Sha256::digest(&anchor).to_vec()),
+                anchor: ByteBuf::from(anchor),
+            }
+        })
+        .collect();
+
+    AnchorExportBatch {
+        anchors,
+        next_cursor: if batch_end < end {
+            Some(batch_end)
+        } else {
+            None
+        },
+    }
+}
+
+/// Imports the given anchors, overwriting existing ones.
+/// Anchors that do not exist yet must be imported in order, i.e. an imported anchor number must
+/// either already be allocated, be an anchor number skipped during allocation or be the next anchor
+/// number to be allocated. Reserved anchor numbers cannot be imported.
+///
+/// Traps on the first invalid anchor (including anchors violating the anchor invariants), so that a
+/// batch is either imported completely or not at all.
+pub fn import_anchors(anchors: Vec<ExportedAnchor>) {
+    for exported in anchors {
+        let anchor_number = exported.anchor_number;
+        if Sha256::digest(&exported.anchor).as_slice() != exported.sha256.as_slice() {
+            trap(&format!("checksum mismatch for anchor {anchor_number}"));
+        }
+        let anchor: Anchor = candid::decode_one(&exported.anchor)
+            .unwrap_or_else(|err| trap(&format!("failed to decode anchor {anchor_number}: {err}")));
+        anchor.check_invariants().unwrap_or_else(|err| {
+            trap(&format!(
+                "anchor {anchor_number} violates the anchor invariants: {err}"
+            ))
+        });
+
+        let (lo, hi) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+        if anchor_number < lo || anchor_number >= hi {
+            trap(&format!(
+                "anchor {anchor_number} is outside of the assigned anchor number range [{lo}, {hi})"
+            ));
+        }
+        let next_anchor_number =
+            lo + state::storage_borrow(|storage| storage.anchor_count()) as u64;
+        if anchor_number > next_anchor_number {
+            trap(&format!(
+                "anchor {anchor_number} cannot be imported before anchor {next_anchor_number}"
+            ));
+        }
+        let read = state::storage_borrow(|storage| storage.read(anchor_number));
+        if let Err(StorageError::BadAnchorNumber(_)) = read {
+            anchor_allocation::allocate_anchor_number(anchor_number);
+        }
+        let devices = anchor
+            .devices()
+            .iter()
+            .cloned()
+            .map(DeviceDataWithoutAlias::from)
+            .collect();
+        state::storage_borrow_mut(|storage| {
+            storage.write(anchor_number, anchor).unwrap_or_else(|err| {
+                trap(&format!("failed to write anchor {anchor_number}: {err}"))
+            });
+        });
+        archive_operation(anchor_number, caller(), Operation::ImportAnchor { devices });
+    }
+}
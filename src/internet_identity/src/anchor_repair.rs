@@ -0,0 +1,145 @@
+//! Sweep over all anchors to find (and optionally repair) anchors violating the anchor invariants.
+//!
+//! Anchors written by previous versions of II might not conform to the current invariants
+//! (see [Anchor::invariant_violations]). Whether a violation is repaired depends on the policy for
+//! its type, violations that can only be repaired by removing devices are only ever reported (see
+//! [Anchor::repair_invariant_violations]). The sweep is started by a controller and processes a batch
+//! of anchors per timer invocation. Its progress is kept in the persistent state, so that an
+//! interrupted sweep is resumed after an upgrade.
+use crate::archive::{archive_operation, device_diff};
+use crate::state;
+use crate::storage::anchor::{Anchor, DeviceRepair};
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::{caller, trap};
+use ic_cdk_timers::set_timer;
+use internet_identity_interface::archive::types::Operation;
+use internet_identity_interface::internet_identity::types::*;
+use std::time::Duration;
+
+/// Number of anchors processed per timer invocation.
+const REPAIR_BATCH_SIZE: u64 = 100;
+/// Maximum number of findings kept in the status.
+const MAX_REPAIR_FINDINGS: usize = 1000;
+
+/// Starts a new sweep with the given policy, replacing any previous one.
+pub fn start_anchor_repair(policy: AnchorRepairPolicy) {
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.anchor_repair = Some(AnchorRepairStatus {
+            policy,
+            initiated_by: caller(),
+            started: time(),
+            completed: None,
+            next_anchor_number: lo,
+            anchors_checked: 0,
+            anchors_with_violations: 0,
+            findings: vec![],
+        })
+    });
+    schedule_repair_step();
+}
+
+/// Resumes the sweep, if one is in progress. Must be called after upgrades.
+pub fn resume_anchor_repair() {
+    let in_progress = state::persistent_state(|persistent_state| {
+        matches!(
+            persistent_state.anchor_repair,
+            Some(AnchorRepairStatus {
+                completed: None,
+                ..
+            })
+        )
+    });
+    if in_progress {
+        schedule_repair_step();
+    }
+}
+
+pub fn anchor_repair_status() -> Option<AnchorRepairStatus> {
+    state::persistent_state(|persistent_state| persistent_state.anchor_repair.clone())
+}
+
+fn schedule_repair_step() {
+    set_timer(Duration::ZERO, repair_step);
+}
+
+/// Processes the next batch of anchors and schedules the next step if there are anchors left.
+fn repair_step() {
+    let Some(mut status) = anchor_repair_status() else {
+        return;
+    };
+    if status.completed.is_some() {
+        return;
+    }
+
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let end = lo + state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    let batch_end = end.min(status.next_anchor_number + REPAIR_BATCH_SIZE);
+
+    for anchor_number in status.next_anchor_number..batch_end {
+        // skipped anchor numbers are not allocated
+        let Some(anchor) = state::allocated_anchor(anchor_number) else {
+            continue;
+        };
+        let violations = anchor.invariant_violations();
+        status.anchors_checked += 1;
+        if violations.is_empty() {
+            continue;
+        }
+
+        let repaired = repair_anchor(anchor_number, anchor, &status.policy, status.initiated_by);
+        status.anchors_with_violations += 1;
+        if status.findings.len() < MAX_REPAIR_FINDINGS {
+            status.findings.push(AnchorRepairFinding {
+                anchor_number,
+                violations: violations.iter().map(ToString::to_string).collect(),
+                repaired,
+            });
+        }
+    }
+
+    status.next_anchor_number = batch_end;
+    let done = batch_end >= end;
+    if done {
+        status.completed = Some(time());
+    }
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.anchor_repair = Some(status);
+    });
+    if !done {
+        schedule_repair_step();
+    }
+}
+
+/// Repairs the given anchor according to the policy and archives the changes. Returns whether the
+/// anchor was modified.
+fn repair_anchor(
+    anchor_number: AnchorNumber,
+    mut anchor: Anchor,
+    policy: &AnchorRepairPolicy,
+    initiated_by: Principal,
+) -> bool {
+    let repairs = anchor.repair_invariant_violations(policy);
+    if repairs.is_empty() {
+        return false;
+    }
+    state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
+        |err| {
+            trap(&format!(
+                "unable to update anchor {anchor_number} in stable memory: {err}"
+            ))
+        },
+    );
+
+    for repair in repairs {
+        let operation = match repair {
+            DeviceRepair::Modified { old, new } => Operation::UpdateDevice {
+                new_values: device_diff(&old, &new),
+                device: old.pubkey,
+            },
+        };
+        archive_operation(anchor_number, initiated_by, operation);
+    }
+    true
+}
@@ -1,5 +1,5 @@
-use crate::state;
 use crate::storage::anchor::Device;
+use crate::{state, HOUR_NS};
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::call::{call_with_payment, CallResult};
 use ic_cdk::api::management_canister::main::{
@@ -8,7 +8,8 @@ use ic_cdk::api::management_canister::main::{
     InstallCodeArgument,
 };
 use ic_cdk::api::time;
-use ic_cdk::{call, caller, id, trap};
+use ic_cdk::{call, caller, id, print, trap};
+use ic_cdk_timers::set_timer_interval;
 use internet_identity_interface::archive::types::*;
 use internet_identity_interface::internet_identity::types::*;
 use serde_bytes::ByteBuf;
@@ -74,6 +75,19 @@ pub struct ArchiveStatusCache {
     pub init: Option<ArchiveInit>,
 }
 
+/// Progress information about pushing entries to the archive (only used if configured to push).
+#[derive(Clone, Debug, Default)]
+pub struct ArchivePushState {
+    // Whether a call to the archive is currently in flight.
+    pub in_progress: bool,
+    // Number of failed pushes since the last successful push.
+    pub consecutive_failures: u32,
+    // No push is attempted before this timestamp (used to back off after failures).
+    pub next_attempt: Timestamp,
+    // Timestamp of the last successful push, if any.
+    pub last_successful_push: Option<Timestamp>,
+}
+
 struct VerifiedWasm(Vec<u8>);
 
 pub async fn deploy_archive(wasm: ByteBuf) -> DeployArchiveResult {
@@ -263,6 +277,8 @@ fn config_to_init(config: &ArchiveConfig) -> ArchiveInit {
         max_entries_per_call: ENTRIES_PER_CALL,
         polling_interval_ns: config.polling_interval_ns,
         error_buffer_limit: CALL_ERROR_BUFFER_SIZE,
+        archive_integration: config.archive_integration.clone(),
+        retention_period_ns: config.retention_period_ns,
     }
 }
 
@@ -318,7 +334,15 @@ pub fn archive_operation(anchor_number: AnchorNumber, caller: Principal, operati
     };
 
     // For layout versions < 6 this will always be false because nothing is ever added to the buffer
-    if data.entries_buffer.len() as u64 >= config.entries_buffer_limit {
+    let buffer_full = data.entries_buffer.len() as u64 >= config.entries_buffer_limit;
+    // As long as there are spilled entries, new entries must be spilled as well to keep the order.
+    let spill = buffer_full || state::storage_borrow(|storage| storage.archive_overflow_len() > 0);
+    if buffer_full
+        && config
+            .buffer_overflow_policy
+            .unwrap_or(BufferOverflowPolicy::Trap)
+            == BufferOverflowPolicy::Trap
+    {
         trap("cannot archive operation, archive entries buffer limit reached")
     }
 
@@ -331,16 +355,27 @@ pub fn archive_operation(anchor_number: AnchorNumber, caller: Principal, operati
         sequence_number: data.sequence_number,
     };
     let encoded_entry = candid::encode_one(entry).expect("failed to encode archive entry");
+    let buffered_entry = BufferedEntry {
+        anchor_number,
+        timestamp,
+        entry: ByteBuf::from(encoded_entry),
+        sequence_number: data.sequence_number,
+    };
 
-    // add entry to buffer (which is emptied by the archive periodically, see fetch_entries and acknowledge entries)
-    state::archive_data_mut(|data| {
-        Rc::make_mut(&mut data.entries_buffer).push(BufferedEntry {
-            anchor_number,
-            timestamp,
-            entry: ByteBuf::from(encoded_entry),
-            sequence_number: data.sequence_number,
+    if spill {
+        // the entry is moved to the buffer once there is space again, see refill_entries_buffer
+        state::storage_borrow_mut(|storage| storage.push_archive_overflow_entry(&buffered_entry))
+            .unwrap_or_else(|err| {
+                trap(&format!(
+                    "cannot archive operation, archive entries buffer limit reached: {err}"
+                ))
+            });
+    } else {
+        // add entry to buffer (which is emptied periodically, see fetch_entries, acknowledge_entries and push_entries)
+        state::archive_data_mut(|data| {
+            Rc::make_mut(&mut data.entries_buffer).push(buffered_entry);
         });
-    });
+    }
 
     state::archive_data_mut(|data| {
         data.sequence_number += 1;
@@ -363,15 +398,131 @@ pub fn fetch_entries() -> Vec<BufferedEntry> {
 }
 
 pub fn acknowledge_entries(sequence_number: u64) {
-    state::persistent_state_mut(|ps| {
-        let Created{ref mut data, .. } = ps.archive_state else {
+    state::persistent_state(|ps| {
+        let Created{ref data, .. } = ps.archive_state else {
             trap("no archive deployed!");
         };
         trap_if_caller_not_archive(data);
+    });
+    remove_archived_entries(sequence_number);
+}
 
+/// Removes all buffered entries up to the given sequence number and moves spilled entries (if any)
+/// into the freed up space of the buffer.
+fn remove_archived_entries(sequence_number: u64) {
+    state::persistent_state_mut(|ps| {
+        let Created{ref mut data, .. } = ps.archive_state else {
+            return;
+        };
         // Only keep entries with higher sequence number as the highest acknowledged.
         Rc::make_mut(&mut data.entries_buffer).retain(|e| e.sequence_number > sequence_number)
     });
+    refill_entries_buffer();
+}
+
+fn refill_entries_buffer() {
+    state::persistent_state_mut(|ps| {
+        let Created{ref mut data, ref config } = ps.archive_state else {
+            return;
+        };
+        let buffer = Rc::make_mut(&mut data.entries_buffer);
+        while (buffer.len() as u64) < config.entries_buffer_limit {
+            let Some(entry) = state::storage_borrow_mut(|storage| storage.pop_archive_overflow_entry()) else {
+                break;
+            };
+            buffer.push(entry);
+        }
+    });
+}
+
+/// Starts the timer to push entries to the archive, if II is configured to push entries.
+/// The polling interval of the archive config is used as the push interval.
+pub fn start_push_timer() {
+    let config = match state::archive_state() {
+        NotConfigured => return,
+        Configured { config } | CreationInProgress { config, .. } | Created { config, .. } => {
+            config
+        }
+    };
+    if config.archive_integration != Some(ArchiveIntegration::Push) {
+        return;
+    }
+    set_timer_interval(Duration::from_nanos(config.polling_interval_ns), || {
+        ic_cdk::spawn(push_entries())
+    });
+}
+
+/// Sends a batch of buffered entries to the archive and removes them from the buffer on success.
+/// On failure, further pushes are delayed with an exponentially increasing back-off.
+/// *Note:* Must not trap after the call to the archive, otherwise the push would remain in progress.
+async fn push_entries() {
+    const WRITE_ENTRIES_METHOD: &str = "write_entries";
+    const MAX_PUSH_BACKOFF_NS: u64 = HOUR_NS;
+
+    let Created {data, config} = state::archive_state() else {
+        // nothing to push if the archive has not been deployed yet
+        return
+    };
+    if config.archive_integration != Some(ArchiveIntegration::Push) {
+        return;
+    }
+
+    let now = time();
+    let should_push = state::archive_push_state_mut(|push_state| {
+        if push_state.in_progress || now < push_state.next_attempt {
+            return false;
+        }
+        push_state.in_progress = true;
+        true
+    });
+    if !should_push {
+        return;
+    }
+
+    // buffered entries are ordered by sequence number
+    // i.e. this takes the lowest entries_fetch_limit many entries
+    let entries: Vec<BufferedEntry> = data
+        .entries_buffer
+        .iter()
+        .take(config.entries_fetch_limit as usize)
+        .cloned()
+        .collect();
+    let Some(highest_sequence_number) = entries.last().map(|e| e.sequence_number) else {
+        // nothing to push
+        state::archive_push_state_mut(|push_state| push_state.in_progress = false);
+        return;
+    };
+
+    let result: CallResult<()> =
+        call(data.archive_canister, WRITE_ENTRIES_METHOD, (entries,)).await;
+
+    match result {
+        Ok(()) => {
+            remove_archived_entries(highest_sequence_number);
+            state::archive_push_state_mut(|push_state| {
+                *push_state = ArchivePushState {
+                    in_progress: false,
+                    consecutive_failures: 0,
+                    next_attempt: 0,
+                    last_successful_push: Some(time()),
+                }
+            });
+        }
+        Err((code, message)) => {
+            print(format!(
+                "failed to push entries to the archive! error code: {code:?}, message: {message}"
+            ));
+            state::archive_push_state_mut(|push_state| {
+                push_state.in_progress = false;
+                push_state.consecutive_failures = push_state.consecutive_failures.saturating_add(1);
+                let backoff = config
+                    .polling_interval_ns
+                    .saturating_mul(1 << push_state.consecutive_failures.min(32))
+                    .min(MAX_PUSH_BACKOFF_NS);
+                push_state.next_attempt = time().saturating_add(backoff);
+            });
+        }
+    }
 }
 
 fn trap_if_caller_not_archive(data: &ArchiveData) {
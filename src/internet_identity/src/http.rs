@@ -1,6 +1,10 @@
 use crate::archive::ArchiveState;
 use crate::assets::ContentType;
-use crate::{assets, state, IC0_APP_DOMAIN, INTERNETCOMPUTER_ORG_DOMAIN, LABEL_ASSETS, LABEL_SIG};
+use crate::rate_limit::RateLimitBucket;
+use crate::{
+    anchor_allocation, assets, rate_limit, state, IC0_APP_DOMAIN, INTERNETCOMPUTER_ORG_DOMAIN,
+    LABEL_ASSETS, LABEL_SIG,
+};
 use base64::engine::general_purpose::STANDARD as BASE64;
 use base64::Engine;
 use ic_cdk::api::stable::stable64_size;
@@ -108,7 +112,7 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
     state::storage_borrow(|storage| {
         w.encode_gauge(
             "internet_identity_user_count",
-            storage.anchor_count() as f64,
+            anchor_allocation::allocated_anchor_count() as f64,
             "Number of users registered in this canister.",
         )?;
         let (lo, hi) = storage.assigned_anchor_number_range();
@@ -164,7 +168,28 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "internet_identity_anchor_operations_counter",
             usage_metrics.anchor_operation_counter as f64,
             "The number of anchor operations since last upgrade",
-        )
+        )?;
+        let challenges = &usage_metrics.challenge_metrics;
+        w.counter_vec(
+            "internet_identity_challenges",
+            "The number of captcha challenges since last upgrade, by outcome.",
+        )?
+        .value(&[("outcome", "created")], challenges.created as f64)?
+        .value(&[("outcome", "solved")], challenges.solved as f64)?
+        .value(&[("outcome", "failed")], challenges.failed as f64)?
+        .value(&[("outcome", "expired")], challenges.expired as f64)?
+        .value(&[("outcome", "evicted")], challenges.evicted as f64)?;
+        w.counter_vec(
+            "internet_identity_challenge_failures",
+            "The number of failed captcha challenge attempts since last upgrade, by reason.",
+        )?
+        .value(
+            &[("reason", "bad_solution")],
+            challenges.bad_solution as f64,
+        )?
+        .value(&[("reason", "unknown_key")], challenges.unknown_key as f64)?
+        .value(&[("reason", "expired")], challenges.expired_attempt as f64)?;
+        Ok::<(), std::io::Error>(())
     })?;
     if let ArchiveState::Created { ref data, .. } = state::archive_state() {
         w.encode_gauge(
@@ -177,6 +202,25 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             data.entries_buffer.len() as f64,
             "The number of buffered archive entries.",
         )?;
+        w.encode_gauge(
+            "internet_identity_spilled_archive_entries",
+            state::storage_borrow(|storage| storage.archive_overflow_len()) as f64,
+            "The number of archive entries spilled to stable memory because the buffer was full.",
+        )?;
+        state::archive_push_state(|push_state| {
+            if let Some(timestamp) = push_state.last_successful_push {
+                w.encode_gauge(
+                    "internet_identity_archive_last_successful_push_timestamp_seconds",
+                    Duration::from_nanos(timestamp).as_secs_f64(),
+                    "Timestamp of the last successful push of entries to the archive.",
+                )?;
+            }
+            w.encode_gauge(
+                "internet_identity_archive_push_consecutive_failures",
+                push_state.consecutive_failures as f64,
+                "The number of failed pushes to the archive since the last successful push.",
+            )
+        })?;
     }
     state::persistent_state(|persistent_state| {
         if let Some(ref register_rate_limit_config) = persistent_state.registration_rate_limit {
@@ -236,14 +280,50 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
 
         Ok::<(), std::io::Error>(())
     })?;
-    state::registration_rate_limit(|rate_limit_opt| {
-        if let Some(ref rate_limit_state) = rate_limit_opt {
+    let mut max_tokens = w.gauge_vec(
+        "internet_identity_rate_limit_max_tokens",
+        "The maximum number of calls per bucket that are allowed in any time window, by bucket type.",
+    )?;
+    for name in RateLimitBucket::NAMES {
+        if let Some(config) = rate_limit::rate_limit_config(name) {
+            max_tokens = max_tokens.value(&[("bucket", name)], config.max_tokens as f64)?;
+        }
+    }
+    state::rate_limits(|buckets| {
+        if let Some(rate_limit_state) = buckets.get(&RateLimitBucket::Register) {
             w.encode_gauge(
                 "internet_identity_register_rate_limit_current_tokens",
                 rate_limit_state.tokens as f64,
                 "The number of `register` calls that are still allowed in the current time window.",
             )?;
         }
+        // (tracked, exhausted) buckets by bucket type
+        let counts: Vec<(&str, usize, usize)> = RateLimitBucket::NAMES
+            .iter()
+            .map(|name| {
+                let states: Vec<_> = buckets
+                    .iter()
+                    .filter(|(bucket, _)| bucket.name() == *name)
+                    .map(|(_, state)| state)
+                    .collect();
+                let exhausted = states.iter().filter(|state| state.tokens == 0).count();
+                (*name, states.len(), exhausted)
+            })
+            .collect();
+        let mut tracked = w.gauge_vec(
+            "internet_identity_rate_limit_tracked_buckets",
+            "The number of buckets currently tracked, by bucket type.",
+        )?;
+        for (name, count, _) in &counts {
+            tracked = tracked.value(&[("bucket", name)], *count as f64)?;
+        }
+        let mut exhausted = w.gauge_vec(
+            "internet_identity_rate_limit_exhausted_buckets",
+            "The number of tracked buckets that had no tokens left on the last call, by bucket type.",
+        )?;
+        for (name, _, count) in &counts {
+            exhausted = exhausted.value(&[("bucket", name)], *count as f64)?;
+        }
         Ok::<(), std::io::Error>(())
     })?;
     Ok(())
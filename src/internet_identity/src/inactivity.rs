@@ -0,0 +1,144 @@
+//! Scan over all anchors reporting the number of anchors by inactivity.
+//!
+//! The scan is started by a controller and processes a batch of anchors per timer invocation (like
+//! the anchor repair sweep). Its progress is kept in the persistent state, so that an interrupted
+//! scan is resumed after an upgrade.
+//!
+//! If a [DormancyPolicy] is configured, anchors that have been inactive for longer than the policy
+//! allows are flagged as dormant. The flag is shown to the user in `get_anchor_info` until it is
+//! acknowledged, so that the user can check whether their recovery setup is still valid.
+use crate::state;
+use crate::storage::anchor::Anchor;
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use ic_cdk_timers::set_timer;
+use internet_identity_interface::internet_identity::types::*;
+use std::time::Duration;
+
+/// Number of anchors processed per timer invocation.
+const SCAN_BATCH_SIZE: u64 = 500;
+/// Lower bounds (in days) of the inactivity buckets reported.
+const BUCKET_LOWER_BOUNDS_DAYS: [u64; 5] = [0, 30, 90, 365, 730];
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Starts a new scan, replacing any previous one.
+pub fn start_inactivity_scan() {
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.inactivity_scan = Some(InactivityReport {
+            started: time(),
+            completed: None,
+            next_anchor_number: lo,
+            buckets: BUCKET_LOWER_BOUNDS_DAYS
+                .iter()
+                .map(|days| InactivityBucket {
+                    inactive_for_days: *days,
+                    anchors: 0,
+                })
+                .collect(),
+            unknown_activity: 0,
+            flagged_dormant: 0,
+        })
+    });
+    schedule_scan_step();
+}
+
+/// Resumes the scan, if one is in progress. Must be called after upgrades.
+pub fn resume_inactivity_scan() {
+    let in_progress = state::persistent_state(|persistent_state| {
+        matches!(
+            persistent_state.inactivity_scan,
+            Some(InactivityReport {
+                completed: None,
+                ..
+            })
+        )
+    });
+    if in_progress {
+        schedule_scan_step();
+    }
+}
+
+pub fn inactivity_report() -> Option<InactivityReport> {
+    state::persistent_state(|persistent_state| persistent_state.inactivity_scan.clone())
+}
+
+/// Clears the dormancy flag of the anchor (if set).
+pub fn acknowledge_dormancy(anchor_number: AnchorNumber, mut anchor: Anchor) {
+    if anchor.flagged_dormant().is_none() {
+        return;
+    }
+    anchor.clear_dormancy_flag();
+    write_anchor(anchor_number, anchor);
+}
+
+fn schedule_scan_step() {
+    set_timer(Duration::ZERO, scan_step);
+}
+
+/// Processes the next batch of anchors and schedules the next step if there are anchors left.
+fn scan_step() {
+    let Some(mut report) = inactivity_report() else {
+        return;
+    };
+    if report.completed.is_some() {
+        return;
+    }
+
+    let policy =
+        state::persistent_state(|persistent_state| persistent_state.dormancy_policy.clone());
+    let (lo, _) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let end = lo + state::storage_borrow(|storage| storage.anchor_count()) as u64;
+    let batch_end = end.min(report.next_anchor_number + SCAN_BATCH_SIZE);
+
+    for anchor_number in report.next_anchor_number..batch_end {
+        // skipped anchor numbers are not allocated
+        let Some(mut anchor) = state::allocated_anchor(anchor_number) else {
+            continue;
+        };
+        let Some(last_activity) = anchor.last_activity() else {
+            report.unknown_activity += 1;
+            continue;
+        };
+        // activity after the start of the scan counts as no inactivity
+        let inactive_for = report.started.saturating_sub(last_activity);
+        if let Some(bucket) = report
+            .buckets
+            .iter_mut()
+            .rev()
+            .find(|bucket| inactive_for >= bucket.inactive_for_days * NANOS_PER_DAY)
+        {
+            bucket.anchors += 1;
+        }
+
+        if let Some(DormancyPolicy { dormant_after_ns }) = policy {
+            if inactive_for > dormant_after_ns && anchor.flagged_dormant().is_none() {
+                anchor.flag_dormant(report.started);
+                write_anchor(anchor_number, anchor);
+                report.flagged_dormant += 1;
+            }
+        }
+    }
+
+    report.next_anchor_number = batch_end;
+    let done = batch_end >= end;
+    if done {
+        report.completed = Some(time());
+    }
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.inactivity_scan = Some(report);
+    });
+    if !done {
+        schedule_scan_step();
+    }
+}
+
+fn write_anchor(anchor_number: AnchorNumber, anchor: Anchor) {
+    state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
+        |err| {
+            trap(&format!(
+                "unable to update anchor {anchor_number} in stable memory: {err}"
+            ))
+        },
+    );
+}
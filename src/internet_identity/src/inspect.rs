@@ -0,0 +1,295 @@
+//! Host-side inspection of II stable memory snapshots.
+//!
+//! When built for the host (rather than wasm32), the II binary inspects stable memory snapshots
+//! (e.g. the ones in `src/internet_identity/stable_memory/`) using the same [Storage] implementation as the canister:
+//!
+//! ```bash
+//! cargo run -p internet_identity -- header src/internet_identity/stable_memory/persistent_state_archive_v6.bin.gz
+//! cargo run -p internet_identity -- anchor src/internet_identity/stable_memory/persistent_state_archive_v6.bin.gz 10000
+//! cargo run -p internet_identity -- persistent-state src/internet_identity/stable_memory/persistent_state_archive_v6.bin.gz
+//! cargo run -p internet_identity -- validate src/internet_identity/stable_memory/persistent_state_archive_v6.bin.gz
+//! ```
+//!
+//! Snapshots can be either raw (`*.bin`) or gzip compressed (`*.bin.gz`). Snapshots are not loaded
+//! into memory but read from disk as needed (see [FileMemory]), compressed snapshots are decompressed
+//! to a temporary file first.
+use crate::storage::anchor::{default_anchor_limits, Anchor};
+use crate::storage::{Storage, StorageError, SUPPORTED_LAYOUT_VERSIONS};
+use flate2::read::GzDecoder;
+use ic_stable_structures::Memory;
+use internet_identity_interface::internet_identity::types::AnchorNumber;
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::process::exit;
+use std::rc::Rc;
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+const USAGE: &str = "usage: internet_identity <command> <snapshot> [args]
+
+commands:
+  header <snapshot>                  print the stable memory header
+  anchor <snapshot> <anchor_number>  print the anchor as JSON
+  persistent-state <snapshot>        print the persistent state
+  validate <snapshot>                check all anchors against the anchor invariants";
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (Some(command), Some(path)) = (args.first(), args.get(1)) else {
+        fail(USAGE);
+    };
+    let storage = load_snapshot(path);
+
+    match command.as_str() {
+        "header" => print_header(&storage),
+        "anchor" => {
+            let anchor_number = args
+                .get(2)
+                .and_then(|arg| arg.parse::<AnchorNumber>().ok())
+                .unwrap_or_else(|| fail(USAGE));
+            let anchor = storage.read(anchor_number).unwrap_or_else(|err| {
+                fail(&format!("failed to read anchor {anchor_number}: {err}"))
+            });
+            println!("{:#}", anchor_json(anchor_number, &anchor));
+        }
+        "persistent-state" => match storage.read_persistent_state() {
+            Ok(state) => println!("{state:#?}"),
+            Err(err) => fail(&format!("failed to read persistent state: {err:?}")),
+        },
+        "validate" => validate(&storage),
+        _ => fail(USAGE),
+    }
+}
+
+/// Opens the snapshot at the given path.
+fn load_snapshot(path: &str) -> Storage<FileMemory> {
+    let file =
+        File::open(path).unwrap_or_else(|err| fail(&format!("failed to open {path}: {err}")));
+    let file = if path.ends_with(".gz") {
+        decompress(file).unwrap_or_else(|err| fail(&format!("failed to read {path}: {err}")))
+    } else {
+        file
+    };
+    let memory =
+        FileMemory::new(file).unwrap_or_else(|err| fail(&format!("failed to open {path}: {err}")));
+
+    // Storage::from_memory traps on an invalid header, which does not produce a helpful
+    // message outside of a canister.
+    let mut magic_and_version = [0u8; 4];
+    if memory.size() > 0 {
+        memory.read(0, &mut magic_and_version);
+    }
+    if &magic_and_version[0..3] != b"IIC" {
+        fail(&format!("{path} is not an II stable memory snapshot"));
+    }
+    let version = magic_and_version[3];
+    if !SUPPORTED_LAYOUT_VERSIONS.contains(&version) {
+        fail(&format!(
+            "{path} has stable memory layout version {version}, supported versions are {} to {}",
+            SUPPORTED_LAYOUT_VERSIONS.start(),
+            SUPPORTED_LAYOUT_VERSIONS.end()
+        ));
+    }
+    Storage::from_memory(memory).unwrap_or_else(|| fail(&format!("{path} is empty")))
+}
+
+/// Decompresses the given gzip file to an (anonymous) temporary file.
+fn decompress(file: File) -> std::io::Result<File> {
+    let path = std::env::temp_dir().join(format!("ii-snapshot-{}.bin", std::process::id()));
+    let mut decompressed = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    // the file stays accessible through the handle (at least on unix)
+    let _ = std::fs::remove_file(&path);
+    std::io::copy(&mut GzDecoder::new(file), &mut decompressed)?;
+    Ok(decompressed)
+}
+
+fn print_header<M: Memory + Clone>(storage: &Storage<M>) {
+    let (lo, hi) = storage.assigned_anchor_number_range();
+    println!("magic:        IIC");
+    println!("version:      {}", storage.version());
+    println!("anchor count: {}", storage.anchor_count());
+    println!("range:        [{lo}, {hi})");
+    println!("entry size:   {}", storage.entry_size());
+    println!(
+        "salt:         {}",
+        storage
+            .salt()
+            .map(hex::encode)
+            .unwrap_or_else(|| "-".to_string())
+    );
+}
+
+/// Reads all anchors and checks them against the anchor invariants, using the anchor limits
+/// configured in the snapshot (or the defaults, if not configured).
+/// Exits with a non-zero exit code if any anchor cannot be read or is in violation.
+fn validate<M: Memory + Clone>(storage: &Storage<M>) {
+    let limits = storage
+        .read_persistent_state()
+        .ok()
+        .and_then(|persistent_state| persistent_state.anchor_limits)
+        .unwrap_or_else(default_anchor_limits);
+    let (lo, _) = storage.assigned_anchor_number_range();
+    let mut violations = 0;
+    for anchor_number in lo..lo + storage.anchor_count() as u64 {
+        let result = match storage.read(anchor_number) {
+            Ok(anchor) => anchor
+                .check_invariants_with_limits(&limits)
+                .map_err(|err| format!("{err:?}")),
+            // skipped during allocation
+            Err(StorageError::BadAnchorNumber(_)) => continue,
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = result {
+            violations += 1;
+            println!("anchor {anchor_number}: {err}");
+        }
+    }
+    println!(
+        "checked {} anchors, {violations} invalid",
+        storage.anchor_count()
+    );
+    if violations > 0 {
+        exit(1);
+    }
+}
+
+fn anchor_json(anchor_number: AnchorNumber, anchor: &Anchor) -> serde_json::Value {
+    let devices: Vec<serde_json::Value> = anchor
+        .devices()
+        .iter()
+        .map(|device| {
+            json!({
+                "pubkey": hex::encode(&device.pubkey),
+                "alias": device.alias,
+                "credential_id": device.credential_id.as_ref().map(hex::encode),
+                "purpose": device.purpose,
+                "key_type": device.key_type,
+                "protection": device.protection,
+                "origin": device.origin,
+                "last_usage_timestamp": device.last_usage_timestamp,
+            })
+        })
+        .collect();
+    json!({
+        "anchor_number": anchor_number,
+        "devices": devices,
+        "flagged_dormant": anchor.flagged_dormant(),
+    })
+}
+
+/// [Memory] backed by a snapshot file, which is read as needed.
+///
+/// The file is never modified: writes (e.g. by the memory manager initializing its header) are kept
+/// in memory, at the granularity of WASM pages.
+#[derive(Clone)]
+struct FileMemory(Rc<RefCell<FileMemoryInner>>);
+
+struct FileMemoryInner {
+    file: File,
+    file_size: u64,
+    size_pages: u64,
+    written_pages: BTreeMap<u64, Vec<u8>>,
+}
+
+impl FileMemory {
+    fn new(file: File) -> std::io::Result<Self> {
+        let file_size = file.metadata()?.len();
+        Ok(Self(Rc::new(RefCell::new(FileMemoryInner {
+            file,
+            file_size,
+            size_pages: (file_size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE,
+            written_pages: BTreeMap::new(),
+        }))))
+    }
+
+    /// Calls `f` for each page overlapping with the given range with the page number, the offset
+    /// within the page and the range of `buf` corresponding to the part of the page.
+    fn for_each_page(
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(u64, usize, std::ops::Range<usize>),
+    ) {
+        let mut done = 0;
+        while done < len {
+            let address = offset + done as u64;
+            let page_offset = (address % WASM_PAGE_SIZE) as usize;
+            let n = (WASM_PAGE_SIZE as usize - page_offset).min(len - done);
+            f(address / WASM_PAGE_SIZE, page_offset, done..done + n);
+            done += n;
+        }
+    }
+}
+
+impl FileMemoryInner {
+    fn check_bounds(&self, offset: u64, len: usize) {
+        let end = offset.checked_add(len as u64).expect("address overflow");
+        if end > self.size_pages * WASM_PAGE_SIZE {
+            panic!("{offset} + {len} is out of bounds");
+        }
+    }
+
+    /// Reads the given part of the page from the file. Bytes beyond the end of the file are zero.
+    fn read_from_file(&mut self, page: u64, page_offset: usize, dst: &mut [u8]) {
+        dst.fill(0);
+        let address = page * WASM_PAGE_SIZE + page_offset as u64;
+        if address >= self.file_size {
+            return;
+        }
+        let n = (self.file_size - address).min(dst.len() as u64) as usize;
+        self.file
+            .seek(SeekFrom::Start(address))
+            .and_then(|_| self.file.read_exact(&mut dst[..n]))
+            .unwrap_or_else(|err| fail(&format!("failed to read snapshot: {err}")));
+    }
+}
+
+impl Memory for FileMemory {
+    fn size(&self) -> u64 {
+        self.0.borrow().size_pages
+    }
+
+    fn grow(&self, pages: u64) -> i64 {
+        let mut inner = self.0.borrow_mut();
+        let old_size = inner.size_pages;
+        inner.size_pages += pages;
+        old_size as i64
+    }
+
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let mut inner = self.0.borrow_mut();
+        inner.check_bounds(offset, dst.len());
+        Self::for_each_page(offset, dst.len(), |page, page_offset, range| {
+            match inner.written_pages.get(&page) {
+                Some(data) => dst[range.clone()]
+                    .copy_from_slice(&data[page_offset..page_offset + range.len()]),
+                None => inner.read_from_file(page, page_offset, &mut dst[range]),
+            }
+        });
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let mut inner = self.0.borrow_mut();
+        inner.check_bounds(offset, src.len());
+        Self::for_each_page(offset, src.len(), |page, page_offset, range| {
+            if !inner.written_pages.contains_key(&page) {
+                let mut data = vec![0; WASM_PAGE_SIZE as usize];
+                inner.read_from_file(page, 0, &mut data);
+                inner.written_pages.insert(page, data);
+            }
+            let data = inner.written_pages.get_mut(&page).unwrap();
+            data[page_offset..page_offset + range.len()].copy_from_slice(&src[range]);
+        });
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("{message}");
+    exit(1)
+}
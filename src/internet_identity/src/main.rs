@@ -1,8 +1,10 @@
-use crate::active_anchor_stats::IIDomain;
-use crate::anchor_management::{post_operation_bookkeeping, tentative_device_registration};
+use crate::anchor_management::{
+    post_operation_bookkeeping, temp_keys, tentative_device_registration,
+};
 use crate::archive::ArchiveState;
 use crate::assets::init_assets;
-use crate::storage::anchor::{Anchor, Device};
+use crate::rate_limit::RateLimitBucket;
+use crate::storage::anchor::{check_anchor_limits, Anchor, Device};
 use candid::{candid_method, Principal};
 use ic_cdk::api::{caller, set_certified_data, trap};
 use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
@@ -14,12 +16,21 @@ use serde_bytes::ByteBuf;
 use storage::{Salt, Storage};
 
 mod active_anchor_stats;
+mod anchor_allocation;
 mod anchor_management;
+mod anchor_migration;
+mod anchor_repair;
 mod archive;
 mod assets;
 mod delegation;
 mod hash;
 mod http;
+mod inactivity;
+#[cfg(not(target_arch = "wasm32"))]
+mod inspect;
+mod rate_limit;
+mod security_events;
+mod shards;
 mod state;
 mod storage;
 
@@ -49,7 +60,10 @@ async fn init_salt() {
 #[update]
 #[candid_method]
 fn enter_device_registration_mode(anchor_number: AnchorNumber) -> Timestamp {
-    authenticate_and_record_activity(anchor_number);
+    authenticate_and_record_security_event(
+        anchor_number,
+        Some(SecurityEventKind::RegistrationModeEntered),
+    );
     tentative_device_registration::enter_device_registration_mode(anchor_number)
 }
 
@@ -86,8 +100,39 @@ fn verify_tentative_device(
 
 #[update]
 #[candid_method]
-async fn create_challenge() -> Challenge {
-    anchor_management::registration::create_challenge().await
+fn prove_tentative_device_possession(
+    anchor_number: AnchorNumber,
+    nonce: ByteBuf,
+) -> ProveDevicePossessionResponse {
+    tentative_device_registration::prove_tentative_device_possession(anchor_number, nonce)
+}
+
+#[update]
+#[candid_method]
+fn confirm_tentative_device(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+) -> VerifyTentativeDeviceResponse {
+    authenticated_anchor_operation(anchor_number, |anchor| {
+        tentative_device_registration::confirm_tentative_device(anchor, anchor_number, device_key)
+    })
+}
+
+#[update]
+#[candid_method]
+fn reject_tentative_device(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+) -> RejectTentativeDeviceResponse {
+    authenticated_anchor_operation(anchor_number, |_| {
+        tentative_device_registration::reject_tentative_device(anchor_number, device_key)
+    })
+}
+
+#[update]
+#[candid_method]
+async fn create_challenge(kind: Option<ChallengeKind>) -> Challenge {
+    anchor_management::registration::create_challenge(kind.unwrap_or(ChallengeKind::Image)).await
 }
 
 #[update]
@@ -96,14 +141,109 @@ fn register(device_data: DeviceData, challenge_result: ChallengeAttempt) -> Regi
     anchor_management::registration::register(device_data, challenge_result)
 }
 
+#[update]
+#[candid_method]
+fn register_v2(
+    authentication_device: DeviceData,
+    recovery_device: Option<DeviceData>,
+    challenge_result: ChallengeAttempt,
+) -> RegisterV2Response {
+    anchor_management::registration::register_v2(
+        authentication_device,
+        recovery_device,
+        challenge_result,
+    )
+}
+
+/// Registers a new anchor for the calling temporary key, which can add devices to the anchor until
+/// it expires.
+#[update]
+#[candid_method]
+fn register_with_temp_key(
+    temp_key: DeviceKey,
+    challenge_result: ChallengeAttempt,
+) -> RegisterWithTempKeyResponse {
+    anchor_management::registration::register_with_temp_key(temp_key, challenge_result)
+}
+
+/// Registers the anchor number that a controller reserved for the calling device.
+#[update]
+#[candid_method]
+fn claim_reserved_anchor(
+    anchor_number: AnchorNumber,
+    device_data: DeviceData,
+) -> ClaimReservedAnchorResponse {
+    anchor_management::registration::claim_reserved_anchor(anchor_number, device_data)
+}
+
 #[update]
 #[candid_method]
 fn add(anchor_number: AnchorNumber, device_data: DeviceData) {
-    authenticated_anchor_operation(anchor_number, |anchor| {
+    device_addition_operation(anchor_number, |anchor| {
         Ok(((), anchor_management::add(anchor, device_data)))
     })
 }
 
+#[update]
+#[candid_method]
+fn add_v2(anchor_number: AnchorNumber, device_data: DeviceData) -> AddResponse {
+    device_addition_operation(anchor_number, |anchor| {
+        anchor_management::add_v2(anchor, device_data)
+    })
+}
+
+#[update]
+#[candid_method]
+fn update_v2(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> UpdateResponse {
+    authenticated_anchor_operation(anchor_number, |anchor| {
+        anchor_management::update_v2(anchor, device_key, device_data)
+    })
+}
+
+#[update]
+#[candid_method]
+fn update_device_fields(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+    patch: DeviceDataPatch,
+) -> UpdateResponse {
+    authenticated_anchor_operation(anchor_number, |anchor| {
+        anchor_management::update_device_fields(anchor, device_key, patch)
+    })
+}
+
+#[update]
+#[candid_method]
+fn replace_v2(
+    anchor_number: AnchorNumber,
+    device_key: DeviceKey,
+    device_data: DeviceData,
+) -> ReplaceResponse {
+    authenticated_anchor_operation(anchor_number, |anchor| {
+        anchor_management::replace_v2(anchor, device_key, device_data)
+    })
+}
+
+#[update]
+#[candid_method]
+fn remove_v2(anchor_number: AnchorNumber, device_key: DeviceKey) -> RemoveResponse {
+    authenticated_anchor_operation(anchor_number, |anchor| {
+        anchor_management::remove_v2(anchor, device_key)
+    })
+}
+
+#[update]
+#[candid_method]
+fn apply_operations(anchor_number: AnchorNumber, ops: Vec<AnchorOp>) -> ApplyOperationsResponse {
+    authenticated_anchor_operations(anchor_number, |anchor| {
+        anchor_management::apply_operations(anchor, ops)
+    })
+}
+
 #[update]
 #[candid_method]
 fn update(anchor_number: AnchorNumber, device_key: DeviceKey, device_data: DeviceData) {
@@ -135,6 +275,7 @@ fn remove(anchor_number: AnchorNumber, device_key: DeviceKey) {
 }
 
 /// Returns all devices of the anchor (authentication and recovery) but no information about device registrations.
+/// Returns an empty list if the anchor is served by another shard (see [lookup_v2]).
 /// Deprecated: use [get_anchor_credentials] instead
 #[query]
 #[candid_method(query)]
@@ -155,12 +296,27 @@ fn lookup(anchor_number: AnchorNumber) -> Vec<DeviceData> {
     })
 }
 
+/// Like [lookup], but returns a redirect if the anchor is served by another shard.
+#[query]
+#[candid_method(query)]
+fn lookup_v2(anchor_number: AnchorNumber) -> LookupResponse {
+    match shards::redirect(anchor_number) {
+        Some(canister_id) => LookupResponse::Redirect(canister_id),
+        None => LookupResponse::Ok(lookup(anchor_number)),
+    }
+}
+
 #[query]
 #[candid_method(query)]
 fn get_anchor_credentials(anchor_number: AnchorNumber) -> AnchorCredentials {
-    let anchor = state::anchor(anchor_number);
+    // The response cannot express a redirect, so anchors served by another shard have no credentials
+    // here (see [get_anchor_credentials_v2]).
+    let devices = match shards::redirect(anchor_number) {
+        Some(_) => vec![],
+        None => state::anchor(anchor_number).into_devices(),
+    };
 
-    anchor.into_devices().into_iter().fold(
+    devices.into_iter().fold(
         AnchorCredentials {
             credentials: vec![],
             recovery_credentials: vec![],
@@ -185,6 +341,23 @@ fn get_anchor_credentials(anchor_number: AnchorNumber) -> AnchorCredentials {
     )
 }
 
+/// Like [get_anchor_credentials], but returns a redirect if the anchor is served by another shard.
+#[query]
+#[candid_method(query)]
+fn get_anchor_credentials_v2(anchor_number: AnchorNumber) -> GetAnchorCredentialsResponse {
+    match shards::redirect(anchor_number) {
+        Some(canister_id) => GetAnchorCredentialsResponse::Redirect(canister_id),
+        None => GetAnchorCredentialsResponse::Ok(get_anchor_credentials(anchor_number)),
+    }
+}
+
+/// Returns the canister responsible for the given anchor number.
+#[query]
+#[candid_method(query)]
+fn route(anchor_number: AnchorNumber) -> RouteResponse {
+    shards::route(anchor_number)
+}
+
 #[update] // this is an update call because queries are not (yet) certified
 #[candid_method]
 fn get_anchor_info(anchor_number: AnchorNumber) -> IdentityAnchorInfo {
@@ -192,6 +365,22 @@ fn get_anchor_info(anchor_number: AnchorNumber) -> IdentityAnchorInfo {
     anchor_management::get_anchor_info(anchor_number)
 }
 
+#[update] // this is an update call because queries are not (yet) certified
+#[candid_method]
+fn get_security_events(anchor_number: AnchorNumber) -> Vec<SecurityEvent> {
+    let anchor = state::anchor(anchor_number);
+    trap_if_not_authenticated(&anchor);
+    anchor.security_events()
+}
+
+#[update]
+#[candid_method]
+fn acknowledge_dormancy(anchor_number: AnchorNumber) {
+    let anchor = state::anchor(anchor_number);
+    trap_if_not_authenticated(&anchor);
+    inactivity::acknowledge_dormancy(anchor_number, anchor)
+}
+
 #[query]
 #[candid_method(query)]
 fn get_principal(anchor_number: AnchorNumber, frontend: FrontendHostname) -> Principal {
@@ -207,7 +396,8 @@ async fn prepare_delegation(
     session_key: SessionKey,
     max_time_to_live: Option<u64>,
 ) -> (UserKey, Timestamp) {
-    let ii_domain = authenticate_and_record_activity(anchor_number);
+    let ii_domain = authenticate_and_record_activity(anchor_number).ii_domain();
+    rate_limit::process_rate_limit(RateLimitBucket::PrepareDelegation(anchor_number));
     delegation::prepare_delegation(
         anchor_number,
         frontend,
@@ -270,6 +460,7 @@ fn stats() -> InternetIdentityStats {
     let domain_active_anchor_stats = state::persistent_state(|persistent_state| {
         persistent_state.domain_active_anchor_stats.clone()
     });
+    let shards = state::persistent_state(|persistent_state| persistent_state.shards.clone());
     let (latest_delegation_origins, max_num_latest_delegation_origins) =
         state::persistent_state(|persistent_state| {
             let origins = persistent_state
@@ -287,7 +478,7 @@ fn stats() -> InternetIdentityStats {
 
     state::storage_borrow(|storage| InternetIdentityStats {
         assigned_user_number_range: storage.assigned_anchor_number_range(),
-        users_registered: storage.anchor_count() as u64,
+        users_registered: anchor_allocation::allocated_anchor_count(),
         archive_info,
         canister_creation_cycles_cost,
         storage_layout_version: storage.version(),
@@ -295,6 +486,7 @@ fn stats() -> InternetIdentityStats {
         domain_active_anchor_stats,
         max_num_latest_delegation_origins,
         latest_delegation_origins,
+        shards,
     })
 }
 
@@ -321,12 +513,89 @@ fn acknowledge_entries(sequence_number: u64) {
     archive::acknowledge_entries(sequence_number)
 }
 
+/// Returns a batch of anchors of the given range [lo, hi) starting at the cursor (if any).
+/// Only callable by the controllers configured in the install arg.
+#[query]
+#[candid_method(query)]
+fn export_anchors(
+    range: Option<(AnchorNumber, AnchorNumber)>,
+    cursor: Option<AnchorNumber>,
+) -> AnchorExportBatch {
+    trap_if_not_controller();
+    anchor_migration::export_anchors(range, cursor)
+}
+
+/// Writes the given anchors (as returned by `export_anchors`) to stable memory.
+/// Only callable by the controllers configured in the install arg.
+#[update]
+#[candid_method]
+fn import_anchors(anchors: Vec<ExportedAnchor>) {
+    trap_if_not_controller();
+    anchor_migration::import_anchors(anchors)
+}
+
+/// Starts a sweep to find (and, depending on the policy, repair) anchors violating the anchor invariants.
+/// Only callable by the controllers configured in the install arg.
+#[update]
+#[candid_method]
+fn start_anchor_repair(policy: AnchorRepairPolicy) {
+    trap_if_not_controller();
+    anchor_repair::start_anchor_repair(policy)
+}
+
+/// Returns the status of the current (or last) anchor repair sweep, if any.
+/// Only callable by the controllers configured in the install arg.
+#[query]
+#[candid_method(query)]
+fn anchor_repair_status() -> Option<AnchorRepairStatus> {
+    trap_if_not_controller();
+    anchor_repair::anchor_repair_status()
+}
+
+/// Starts a scan counting anchors by inactivity (and flagging dormant anchors according to the dormancy policy).
+/// Only callable by the controllers configured in the install arg.
+#[update]
+#[candid_method]
+fn start_inactivity_scan() {
+    trap_if_not_controller();
+    inactivity::start_inactivity_scan()
+}
+
+/// Returns the report of the current (or last) inactivity scan, if any.
+/// Only callable by the controllers configured in the install arg.
+#[query]
+#[candid_method(query)]
+fn inactivity_report() -> Option<InactivityReport> {
+    trap_if_not_controller();
+    inactivity::inactivity_report()
+}
+
+/// Reserves the given anchor number for the device with the given key, which can then claim it
+/// using `claim_reserved_anchor`.
+/// Only callable by the controllers configured in the install arg.
+#[update]
+#[candid_method]
+fn reserve_anchor(anchor_number: AnchorNumber, device_key: DeviceKey) {
+    trap_if_not_controller();
+    anchor_allocation::reserve_anchor(anchor_number, device_key)
+}
+
+/// Returns the anchor numbers that are reserved but not yet claimed.
+/// Only callable by the controllers configured in the install arg.
+#[query]
+#[candid_method(query)]
+fn anchor_reservations() -> Vec<AnchorReservation> {
+    trap_if_not_controller();
+    anchor_allocation::anchor_reservations()
+}
+
 #[init]
 fn init(maybe_arg: Option<InternetIdentityInit>) {
     init_assets();
     state::init_new();
 
     apply_install_arg(maybe_arg);
+    archive::start_push_timer();
 
     // make sure the fully initialized storage configuration is written to stable memory
     state::storage_borrow_mut(|storage| storage.flush());
@@ -343,8 +612,13 @@ fn post_upgrade(maybe_arg: Option<InternetIdentityInit>) {
     update_root_hash();
     // load the persistent state after initializing storage, otherwise the memory address to load it from cannot be calculated
     state::load_persistent_state();
+    anchor_allocation::init_allocated_anchors();
 
     apply_install_arg(maybe_arg);
+    archive::start_push_timer();
+    anchor_repair::resume_anchor_repair();
+    inactivity::resume_inactivity_scan();
+    temp_keys::resume_temp_key_expiration();
 }
 
 fn apply_install_arg(maybe_arg: Option<InternetIdentityInit>) {
@@ -372,6 +646,51 @@ fn apply_install_arg(maybe_arg: Option<InternetIdentityInit>) {
                 persistent_state.max_num_latest_delegation_origins = Some(limit);
             })
         }
+        if let Some(controllers) = arg.controllers {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.controllers = Some(controllers);
+            })
+        }
+        if let Some(shards) = arg.shards {
+            shards::set_shards(shards);
+        }
+        if let Some(limits) = arg.anchor_limits {
+            let entry_size_limit =
+                state::storage_borrow(|storage| storage.candid_entry_size_limit());
+            check_anchor_limits(&limits, entry_size_limit).unwrap_or_else(|err| trap(&err));
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.anchor_limits = Some(limits);
+            })
+        }
+        if let Some(rate_limits) = arg.rate_limits {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.rate_limits = Some(rate_limits);
+            })
+        }
+        if let Some(config) = arg.device_registration {
+            tentative_device_registration::check_device_registration_config(&config)
+                .unwrap_or_else(|err| trap(&err));
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.device_registration = Some(config);
+            })
+        }
+        if let Some(strategy) = arg.anchor_allocation {
+            anchor_allocation::check_allocation_strategy(&strategy)
+                .unwrap_or_else(|err| trap(&err));
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.anchor_allocation = Some(strategy);
+            })
+        }
+        if let Some(adaptive_captcha) = arg.adaptive_captcha {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.adaptive_captcha = Some(adaptive_captcha);
+            })
+        }
+        if let Some(policy) = arg.dormancy_policy {
+            state::persistent_state_mut(|persistent_state| {
+                persistent_state.dormancy_policy = Some(policy);
+            })
+        }
     }
 }
 
@@ -406,20 +725,32 @@ fn update_root_hash() {
 }
 
 /// Authenticates the caller (traps if not authenticated) and updates the device used to authenticate
-/// reflecting the current activity. Also updates the aggregated stats on daily and monthly active users.
+/// reflecting the current activity. Also updates the aggregated stats on daily and monthly active users
+/// and records the use of recovery devices as security event. Returns the device used to authenticate.
 ///
 /// Note: this function reads / writes the anchor from / to stable memory. It is intended to be used by functions that
 /// do not further modify the anchor.
-fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Option<IIDomain> {
+fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Device {
+    authenticate_and_record_security_event(anchor_number, None)
+}
+
+/// Like [authenticate_and_record_activity] but additionally records a security event of the given
+/// kind caused by the device used to authenticate.
+fn authenticate_and_record_security_event(
+    anchor_number: AnchorNumber,
+    event_kind: Option<SecurityEventKind>,
+) -> Device {
     let mut anchor = state::anchor(anchor_number);
-    let device = trap_if_not_authenticated(&anchor);
-    let domain = device.ii_domain();
-    let device_key = device.pubkey.clone();
-    anchor_management::activity_bookkeeping(&mut anchor, &device_key);
+    let device = trap_if_not_authenticated(&anchor).clone();
+    anchor_management::activity_bookkeeping(&mut anchor, &device.pubkey);
+    security_events::record_authentication(&mut anchor, &device);
+    if let Some(kind) = event_kind {
+        security_events::record_event(&mut anchor, kind, &device);
+    }
     state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
         |err| panic!("last_usage_timestamp update: unable to update anchor {anchor_number}: {err}"),
     );
-    domain
+    device
 }
 
 /// Authenticates the caller (traps if not authenticated) calls the provided function and handles all
@@ -435,13 +766,65 @@ fn authenticate_and_record_activity(anchor_number: AnchorNumber) -> Option<IIDom
 fn authenticated_anchor_operation<R>(
     anchor_number: AnchorNumber,
     op: impl FnOnce(&mut Anchor) -> Result<(R, Operation), R>,
+) -> R {
+    authenticated_anchor_operations(anchor_number, |anchor| {
+        op(anchor).map(|(ret, operation)| (ret, vec![operation]))
+    })
+}
+
+/// Like [authenticated_anchor_operation] but also accepts the temporary key of the anchor (see
+/// [temp_keys]). Must only be used to add devices, which is the only purpose of the temporary key.
+fn device_addition_operation<R>(
+    anchor_number: AnchorNumber,
+    op: impl FnOnce(&mut Anchor) -> Result<(R, Operation), R>,
+) -> R {
+    anchor_operations(
+        anchor_number,
+        trap_if_not_authenticated_or_temp_key,
+        |anchor| op(anchor).map(|(ret, operation)| (ret, vec![operation])),
+    )
+}
+
+/// Like [authenticated_anchor_operation] but for functions applying multiple operations to the anchor.
+/// The anchor is written once and each of the returned operations is archived (in order).
+fn authenticated_anchor_operations<R>(
+    anchor_number: AnchorNumber,
+    op: impl FnOnce(&mut Anchor) -> Result<(R, Vec<Operation>), R>,
+) -> R {
+    anchor_operations(
+        anchor_number,
+        |_, anchor| Some(trap_if_not_authenticated(anchor).pubkey.clone()),
+        op,
+    )
+}
+
+/// Authenticates the caller using `authenticate`, which returns the key of the device used (if any),
+/// and applies the operations as described in [authenticated_anchor_operations].
+fn anchor_operations<R>(
+    anchor_number: AnchorNumber,
+    authenticate: impl FnOnce(AnchorNumber, &Anchor) -> Option<DeviceKey>,
+    op: impl FnOnce(&mut Anchor) -> Result<(R, Vec<Operation>), R>,
 ) -> R {
     // load anchor
     let mut anchor = state::anchor(anchor_number);
-    let device_key = trap_if_not_authenticated(&anchor).pubkey.clone();
-    anchor_management::activity_bookkeeping(&mut anchor, &device_key);
+    let device_key = authenticate(anchor_number, &anchor);
+    rate_limit::process_rate_limit(RateLimitBucket::DeviceMutation(anchor_number));
+    if let Some(ref device_key) = device_key {
+        anchor_management::activity_bookkeeping(&mut anchor, device_key);
+    }
+    let devices_before = anchor.devices().clone();
 
     let result = op(&mut anchor);
+    if result.is_ok() {
+        if let Some(device) = device_key
+            .as_ref()
+            .and_then(|key| devices_before.iter().find(|device| &device.pubkey == key))
+        {
+            security_events::record_authentication(&mut anchor, device);
+        }
+        let devices_after = anchor.devices().clone();
+        security_events::record_device_changes(&mut anchor, &devices_before, &devices_after);
+    }
 
     // write back anchor
     state::storage_borrow_mut(|storage| storage.write(anchor_number, anchor)).unwrap_or_else(
@@ -449,8 +832,10 @@ fn authenticated_anchor_operation<R>(
     );
 
     match result {
-        Ok((ret, operation)) => {
-            post_operation_bookkeeping(anchor_number, operation);
+        Ok((ret, operations)) => {
+            for operation in operations {
+                post_operation_bookkeeping(anchor_number, operation);
+            }
             ret
         }
         Err(err) => err,
@@ -468,8 +853,50 @@ fn trap_if_not_authenticated(anchor: &Anchor) -> &Device {
     trap(&format!("{} could not be authenticated.", caller()))
 }
 
+/// Like [trap_if_not_authenticated] but also accepts the temporary key of the anchor (see
+/// [temp_keys]). Returns the key of the device used or None if the temporary key was used.
+fn trap_if_not_authenticated_or_temp_key(
+    anchor_number: AnchorNumber,
+    anchor: &Anchor,
+) -> Option<DeviceKey> {
+    let authenticated_device = anchor
+        .devices()
+        .iter()
+        .find(|device| caller() == Principal::self_authenticating(&device.pubkey));
+    if let Some(device) = authenticated_device {
+        return Some(device.pubkey.clone());
+    }
+    if temp_keys::is_temp_key_caller(anchor_number) {
+        return None;
+    }
+    trap(&format!("{} could not be authenticated.", caller()))
+}
+
+/// Checks if the caller is one of the controllers configured in the install arg.
+/// Traps if the caller is not a controller.
+fn trap_if_not_controller() {
+    let caller = caller();
+    let is_controller = state::persistent_state(|persistent_state| {
+        persistent_state
+            .controllers
+            .as_ref()
+            .map(|controllers| controllers.contains(&caller))
+            .unwrap_or(false)
+    });
+    if !is_controller {
+        trap(&format!("{caller} is not a controller."))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
 fn main() {}
 
+/// Outside of a canister, the binary is used to inspect stable memory snapshots.
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    inspect::main()
+}
+
 // Order dependent: do not move above any function annotated with #[candid_method]!
 candid::export_service!();
 
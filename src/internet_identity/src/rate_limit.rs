@@ -0,0 +1,192 @@
+//! Token bucket rate limits on canister calls.
+//!
+//! There are global buckets for `register` and audio challenges, per-caller buckets for `create_challenge` (except for anonymous
+//! callers) and audio challenges, and per-anchor buckets for calls made on behalf of an anchor (e.g. `prepare_delegation`). Each
+//! kind of bucket has its own [RateLimitConfig] and a rate limit is only enforced if it is configured, except for audio challenges
+//! which are always rate limited (see [DEFAULT_AUDIO_CHALLENGE_RATE_LIMIT] and [DEFAULT_AUDIO_CHALLENGE_TOTAL_RATE_LIMIT]).
+use crate::state;
+use crate::state::RateLimitState;
+use candid::Principal;
+use ic_cdk::api::time;
+use ic_cdk::trap;
+use internet_identity_interface::internet_identity::types::{AnchorNumber, RateLimitConfig};
+use std::cmp::min;
+use std::collections::HashMap;
+
+/// Maximum number of per-anchor / per-caller buckets of the same kind tracked at the same time.
+/// The limit is per kind, so that filling up the buckets of one kind (e.g. with unauthenticated
+/// `add_tentative_device` calls) does not affect the other kinds.
+const MAX_TRACKED_BUCKETS_PER_KIND: usize = 10_000;
+
+/// Rate limit of audio challenges per caller, if not configured otherwise (60 per hour).
+pub const DEFAULT_AUDIO_CHALLENGE_RATE_LIMIT: RateLimitConfig = RateLimitConfig {
+    time_per_token_ns: 60 * 1_000_000_000,
+    max_tokens: 3,
+};
+/// Rate limit of audio challenges of all callers together, if not configured otherwise. Audio
+/// captchas are easier to solve automatically than image captchas, so their total number is capped
+/// (1200 per hour), on top of the per-caller limit.
+pub const DEFAULT_AUDIO_CHALLENGE_TOTAL_RATE_LIMIT: RateLimitConfig = RateLimitConfig {
+    time_per_token_ns: 3 * 1_000_000_000,
+    max_tokens: 100,
+};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RateLimitBucket {
+    Register,
+    PrepareDelegation(AnchorNumber),
+    DeviceMutation(AnchorNumber),
+    AddTentativeDevice(AnchorNumber),
+    CreateChallenge(Principal),
+    AudioChallenge(Principal),
+    AudioChallengeTotal,
+}
+
+impl RateLimitBucket {
+    /// Names of the different kinds of buckets, as used in the metrics.
+    pub const NAMES: [&'static str; 7] = [
+        "register",
+        "prepare_delegation",
+        "device_mutation",
+        "add_tentative_device",
+        "create_challenge",
+        "audio_challenge",
+        "audio_challenge_total",
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RateLimitBucket::Register => Self::NAMES[0],
+            RateLimitBucket::PrepareDelegation(_) => Self::NAMES[1],
+            RateLimitBucket::DeviceMutation(_) => Self::NAMES[2],
+            RateLimitBucket::AddTentativeDevice(_) => Self::NAMES[3],
+            RateLimitBucket::CreateChallenge(_) => Self::NAMES[4],
+            RateLimitBucket::AudioChallenge(_) => Self::NAMES[5],
+            RateLimitBucket::AudioChallengeTotal => Self::NAMES[6],
+        }
+    }
+}
+
+/// Returns the configuration of the bucket with the given name, if the rate limit is enabled.
+pub fn rate_limit_config(name: &str) -> Option<RateLimitConfig> {
+    state::persistent_state(|ps| {
+        let rate_limits = ps.rate_limits.as_ref();
+        match name {
+            "register" => ps.registration_rate_limit.clone(),
+            "prepare_delegation" => rate_limits?.prepare_delegation.clone(),
+            "device_mutation" => rate_limits?.device_mutation.clone(),
+            "add_tentative_device" => rate_limits?.add_tentative_device.clone(),
+            "create_challenge" => rate_limits?.create_challenge.clone(),
+            "audio_challenge" => Some(
+                rate_limits
+                    .and_then(|rate_limits| rate_limits.audio_challenge.clone())
+                    .unwrap_or(DEFAULT_AUDIO_CHALLENGE_RATE_LIMIT),
+            ),
+            "audio_challenge_total" => Some(
+                rate_limits
+                    .and_then(|rate_limits| rate_limits.audio_challenge_total.clone())
+                    .unwrap_or(DEFAULT_AUDIO_CHALLENGE_TOTAL_RATE_LIMIT),
+            ),
+            _ => None,
+        }
+    })
+}
+
+/// Processes the rate limit of the given bucket:
+///   1. Check if the rate limit is enabled
+///   2. Initialize / update the token count
+///   3. Verify that the current call is not rate limited
+///
+/// The rate limit is based on `tokens`. Each call uses one token. Tokens replenish over time,
+/// every `time_per_token_ns` a new token is added. If tokens is 0 no further calls are allowed until
+/// tokens have replenished.
+/// There is a maximum of `max_tokens` tokens, when reached the tokens not increase any further.
+/// This is the maximum number of calls that can be handled in a burst.
+pub fn process_rate_limit(bucket: RateLimitBucket) {
+    let Some(config) = rate_limit_config(bucket.name()) else {
+        // rate limit disabled -> nothing to do
+        return;
+    };
+
+    state::rate_limits_mut(|buckets| {
+        if !buckets.contains_key(&bucket)
+            && tracked_buckets_of_kind(buckets, &bucket) >= MAX_TRACKED_BUCKETS_PER_KIND
+        {
+            prune_full_buckets(buckets);
+            if tracked_buckets_of_kind(buckets, &bucket) >= MAX_TRACKED_BUCKETS_PER_KIND {
+                trap("rate limit reached, try again later");
+            }
+        }
+
+        let state = buckets.entry(bucket).or_insert_with(|| RateLimitState {
+            tokens: config.max_tokens,
+            token_timestamp: time(),
+        });
+        add_tokens(state, &config);
+
+        // deduct a token for the current call
+        if state.tokens > 0 {
+            state.tokens -= 1;
+        } else {
+            trap("rate limit reached, try again later");
+        }
+    })
+}
+
+/// Returns the number of tokens currently available in the given bucket and its maximum number of
+/// tokens, without deducting a token. Returns None if the rate limit is disabled.
+pub fn available_tokens(bucket: RateLimitBucket) -> Option<(u64, u64)> {
+    let config = rate_limit_config(bucket.name())?;
+    let tokens = state::rate_limits(|buckets| match buckets.get(&bucket) {
+        Some(state) => {
+            let mut state = state.clone();
+            add_tokens(&mut state, &config);
+            state.tokens
+        }
+        None => config.max_tokens,
+    });
+    Some((tokens, config.max_tokens))
+}
+
+/// Returns the number of tracked buckets of the same kind as `bucket`.
+fn tracked_buckets_of_kind(
+    buckets: &HashMap<RateLimitBucket, RateLimitState>,
+    bucket: &RateLimitBucket,
+) -> usize {
+    buckets
+        .keys()
+        .filter(|tracked| tracked.name() == bucket.name())
+        .count()
+}
+
+/// Removes the buckets that have replenished all their tokens. Such buckets are indistinguishable
+/// from new ones and do not need to be tracked.
+fn prune_full_buckets(buckets: &mut HashMap<RateLimitBucket, RateLimitState>) {
+    let configs: HashMap<&str, RateLimitConfig> = RateLimitBucket::NAMES
+        .iter()
+        .filter_map(|name| rate_limit_config(name).map(|config| (*name, config)))
+        .collect();
+    buckets.retain(|bucket, state| match configs.get(bucket.name()) {
+        Some(config) => {
+            add_tokens(state, config);
+            state.tokens < config.max_tokens
+        }
+        None => false,
+    });
+}
+
+/// Adds new tokens to the rate limit state according to the time past since the last update.
+/// To avoid floating point computation, the state is only updated if at least enough time has passed
+/// to add one full token and the `token_timestamp` refers to the last timestamp that has ben accounted
+/// for in the current token count.
+///
+/// I.e. if the time passed would allow to add 1.7 tokens to the state, only 1 token is added and
+/// the `token_timestamp` is increased by 1*`time_per_token_ns`.
+fn add_tokens(state: &mut RateLimitState, config: &RateLimitConfig) {
+    let new_tokens = (time() - state.token_timestamp) / config.time_per_token_ns;
+    if new_tokens > 0 {
+        // The number of tokens is capped otherwise tokens might accumulate
+        state.tokens = min(config.max_tokens, state.tokens + new_tokens);
+        state.token_timestamp += config.time_per_token_ns * new_tokens;
+    }
+}
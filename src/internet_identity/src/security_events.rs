@@ -0,0 +1,46 @@
+//! Feed of security-relevant events per anchor, so that every device of an anchor can show what
+//! happened to the anchor recently (e.g. that device registration mode was entered using a recovery
+//! phrase).
+//!
+//! The following events are recorded:
+//! * device registration mode was entered
+//! * a device was added or removed
+//! * a recovery device was used to authenticate
+//!
+//! The events are stored with the anchor itself in a bounded ring (see
+//! [crate::storage::anchor::MAX_SECURITY_EVENTS]), i.e. old events of an anchor are eventually
+//! dropped in favour of its new ones. The functions recording events only modify the given anchor,
+//! it is up to the caller to write the anchor back to storage.
+use crate::storage::anchor::{Anchor, Device};
+use ic_cdk::api::time;
+use internet_identity_interface::internet_identity::types::*;
+
+/// Records an event of the given kind caused by (or affecting) the given device.
+pub fn record_event(anchor: &mut Anchor, kind: SecurityEventKind, device: &Device) {
+    anchor.record_security_event(SecurityEvent {
+        timestamp: time(),
+        kind,
+        purpose: device.purpose.clone(),
+        key_type: device.key_type.clone(),
+    });
+}
+
+/// Records the use of a recovery device, if the caller was authenticated by one.
+pub fn record_authentication(anchor: &mut Anchor, device: &Device) {
+    if device.purpose == Purpose::Recovery {
+        record_event(anchor, SecurityEventKind::RecoveryUsed, device);
+    }
+}
+
+/// Records the devices that were added to or removed from an anchor by an anchor operation.
+pub fn record_device_changes(anchor: &mut Anchor, before: &[Device], after: &[Device]) {
+    let contains = |devices: &[Device], device: &Device| {
+        devices.iter().any(|other| other.pubkey == device.pubkey)
+    };
+    for device in before.iter().filter(|device| !contains(after, device)) {
+        record_event(anchor, SecurityEventKind::DeviceRemoved, device);
+    }
+    for device in after.iter().filter(|device| !contains(before, device)) {
+        record_event(anchor, SecurityEventKind::DeviceAdded, device);
+    }
+}
@@ -0,0 +1,91 @@
+//! Routing of anchors to sibling II canisters (shards).
+//!
+//! Each II canister serves a single range of anchors. In order to scale beyond the capacity of a
+//! single canister, the anchor number space is split across multiple canisters. Every canister knows
+//! about its siblings and their anchor ranges so that it can tell clients where to find an anchor.
+//!
+//! Note: the calls cannot be forwarded to the responsible shard because the shard would then see
+//! this canister rather than the user as the caller. Instead, clients are redirected: calls concerning
+//! an anchor served by a sibling and registrations once this canister is full return the canister to
+//! retry the call with.
+use crate::state;
+use candid::Principal;
+use ic_cdk::trap;
+use internet_identity_interface::internet_identity::types::*;
+
+/// Returns the canister responsible for the given anchor number.
+pub fn route(anchor_number: AnchorNumber) -> RouteResponse {
+    let (lo, hi) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    if lo <= anchor_number && anchor_number < hi {
+        return RouteResponse::Local;
+    }
+    match shard_for(anchor_number) {
+        Some(canister_id) => RouteResponse::Shard(canister_id),
+        None => RouteResponse::Unknown,
+    }
+}
+
+/// Returns the sibling canister serving the given anchor number, if it is not served by this canister.
+pub fn redirect(anchor_number: AnchorNumber) -> Option<Principal> {
+    match route(anchor_number) {
+        RouteResponse::Shard(canister_id) => Some(canister_id),
+        RouteResponse::Local | RouteResponse::Unknown => None,
+    }
+}
+
+/// Returns the sibling canister new anchors should be registered with once this canister is full,
+/// i.e. the shard serving the range following the own range (if any).
+pub fn registration_redirect() -> Option<Principal> {
+    let (_, own_hi) = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    state::persistent_state(|persistent_state| {
+        persistent_state
+            .shards
+            .as_ref()?
+            .iter()
+            .filter(|shard| shard.assigned_user_number_range.0 >= own_hi)
+            .min_by_key(|shard| shard.assigned_user_number_range.0)
+            .map(|shard| shard.canister_id)
+    })
+}
+
+/// Sets the list of sibling canisters.
+/// Traps if any of the ranges is empty or overlaps with another range (including the own range).
+pub fn set_shards(shards: Vec<ShardInfo>) {
+    let own_range = state::storage_borrow(|storage| storage.assigned_anchor_number_range());
+    let mut ranges: Vec<(AnchorNumber, AnchorNumber)> = shards
+        .iter()
+        .map(|shard| shard.assigned_user_number_range)
+        .collect();
+    ranges.push(own_range);
+    ranges.sort();
+
+    if let Some((lo, hi)) = ranges.iter().find(|(lo, hi)| lo >= hi) {
+        trap(&format!("invalid shard range [{lo}, {hi})"));
+    }
+    for pair in ranges.windows(2) {
+        let ((lo_1, hi_1), (lo_2, hi_2)) = (pair[0], pair[1]);
+        if hi_1 > lo_2 {
+            trap(&format!(
+                "shard range [{lo_1}, {hi_1}) overlaps with range [{lo_2}, {hi_2})"
+            ));
+        }
+    }
+
+    state::persistent_state_mut(|persistent_state| {
+        persistent_state.shards = Some(shards);
+    })
+}
+
+fn shard_for(anchor_number: AnchorNumber) -> Option<Principal> {
+    state::persistent_state(|persistent_state| {
+        persistent_state
+            .shards
+            .as_ref()?
+            .iter()
+            .find(|shard| {
+                let (lo, hi) = shard.assigned_user_number_range;
+                lo <= anchor_number && anchor_number < hi
+            })
+            .map(|shard| shard.canister_id)
+    })
+}
@@ -1,6 +1,8 @@
-use crate::archive::{ArchiveData, ArchiveState, ArchiveStatusCache};
+use crate::anchor_allocation::AnchorBitmap;
+use crate::archive::{ArchiveData, ArchivePushState, ArchiveState, ArchiveStatusCache};
+use crate::rate_limit::RateLimitBucket;
 use crate::storage::anchor::Anchor;
-use crate::storage::DEFAULT_RANGE_SIZE;
+use crate::storage::{StorageError, DEFAULT_RANGE_SIZE};
 use crate::{Salt, Storage};
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::time;
@@ -26,19 +28,35 @@ thread_local! {
     static ASSETS: RefCell<Assets> = RefCell::new(HashMap::default());
 }
 
+/// Registration state of new devices added using the two step device add flow
 pub struct TentativeDeviceRegistration {
     pub expiration: Timestamp,
-    pub state: RegistrationState,
+    // Devices tentatively added (in order), empty until the first device is added
+    pub tentative_devices: Vec<TentativeDevice>,
+    // Failed attempts to verify any of the tentative devices using a verification code
+    pub failed_attempts: FailedAttemptsCounter,
 }
 
-/// Registration state of new devices added using the two step device add flow
-pub enum RegistrationState {
-    DeviceRegistrationModeActive,
-    DeviceTentativelyAdded {
-        tentative_device: DeviceData,
+/// Key that is allowed to add devices to a freshly registered anchor until it expires
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct TempKey {
+    pub key: DeviceKey,
+    pub expiration: Timestamp,
+}
+
+pub struct TentativeDevice {
+    pub device: DeviceData,
+    pub verification: TentativeDeviceVerification,
+}
+
+/// How a tentatively added device is verified before it is added to the anchor.
+pub enum TentativeDeviceVerification {
+    /// The user enters the verification code shown on the new device.
+    Code {
         verification_code: DeviceVerificationCode,
-        failed_attempts: FailedAttemptsCounter,
     },
+    /// The new device signs the nonce and the user confirms the device.
+    PossessionProof { nonce: Vec<u8>, proven: bool },
 }
 
 #[derive(Default)]
@@ -47,12 +65,31 @@ pub struct UsageMetrics {
     pub delegation_counter: u64,
     // number of anchor operations (register, add, remove, update) since last upgrade
     pub anchor_operation_counter: u64,
+    // captcha challenges since last upgrade
+    pub challenge_metrics: ChallengeMetrics,
+}
+
+#[derive(Default)]
+pub struct ChallengeMetrics {
+    pub created: u64,
+    pub solved: u64,
+    // wrong solution, unknown challenge key or expired challenge
+    pub failed: u64,
+    pub bad_solution: u64,
+    pub unknown_key: u64,
+    // solution submitted after the challenge expired (but before it was pruned)
+    pub expired_attempt: u64,
+    pub expired: u64,
+    // dropped before expiration to make room for new challenges
+    pub evicted: u64,
 }
 
 // The challenges we store and check against
 pub struct ChallengeInfo {
     pub created: Timestamp,
     pub chars: String,
+    // caller that created the challenge, used to bound the inflight challenges per caller
+    pub creator: Principal,
 }
 
 pub type ChallengeKey = String;
@@ -87,6 +124,35 @@ pub struct PersistentState {
     pub latest_delegation_origins: Option<HashMap<FrontendHostname, Timestamp>>,
     // Maximum number of latest delegation origins to store
     pub max_num_latest_delegation_origins: Option<u64>,
+    // Principals allowed to call controller-only endpoints
+    pub controllers: Option<Vec<Principal>>,
+    // Sibling II canisters serving other anchor ranges
+    pub shards: Option<Vec<ShardInfo>>,
+    // Status of the anchor repair sweep, if any
+    pub anchor_repair: Option<AnchorRepairStatus>,
+    // Limits on the number and size of devices per anchor (defaults apply if not set)
+    pub anchor_limits: Option<AnchorLimits>,
+    // Policy to flag dormant anchors, if any
+    pub dormancy_policy: Option<DormancyPolicy>,
+    // Report of the inactivity scan, if any
+    pub inactivity_scan: Option<InactivityReport>,
+    // Configuration of the remote device registration flow, if not the default
+    pub device_registration: Option<DeviceRegistrationConfig>,
+    // Configuration for the per-anchor rate limits, if any.
+    pub rate_limits: Option<RateLimits>,
+    // Strategy to allocate the anchor numbers of new anchors (sequential if not set)
+    pub anchor_allocation: Option<AnchorAllocationStrategy>,
+    // Taken anchor numbers, once the first anchor number has been allocated out of order
+    pub anchor_bitmap: Option<AnchorBitmap>,
+    // Anchor numbers reserved for specific devices
+    pub anchor_reservations: Option<Vec<AnchorReservation>>,
+    // Number of allocated anchors, tracked once the anchor bitmap exists (until then, it is equal to
+    // the number of anchor records)
+    pub allocated_anchors: Option<u64>,
+    // Whether captchas get harder under registration pressure (disabled if not set)
+    pub adaptive_captcha: Option<bool>,
+    // Temporary keys of anchors registered using `register_with_temp_key`, if any
+    pub temp_keys: Option<HashMap<AnchorNumber, TempKey>>,
 }
 
 impl Default for PersistentState {
@@ -99,6 +165,20 @@ impl Default for PersistentState {
             domain_active_anchor_stats: None,
             latest_delegation_origins: None,
             max_num_latest_delegation_origins: Some(MAX_NUM_DELEGATION_ORIGINS),
+            controllers: None,
+            shards: None,
+            anchor_repair: None,
+            anchor_limits: None,
+            dormancy_policy: None,
+            inactivity_scan: None,
+            device_registration: None,
+            rate_limits: None,
+            anchor_allocation: None,
+            anchor_bitmap: None,
+            anchor_reservations: None,
+            allocated_anchors: None,
+            adaptive_captcha: None,
+            temp_keys: None,
         }
     }
 }
@@ -138,8 +218,11 @@ struct State {
     persistent_state: RefCell<PersistentState>,
     // Cache of the archive status (to make unwanted calls to deploy_archive cheap to dismiss).
     archive_status_cache: RefCell<Option<ArchiveStatusCache>>,
-    // Tracking data for the registration rate limit, if any. Not persisted across upgrades.
-    registration_rate_limit: RefCell<Option<RateLimitState>>,
+    // Progress and back-off information of pushing entries to the archive (if configured to push).
+    // Not persisted across upgrades.
+    archive_push_state: RefCell<ArchivePushState>,
+    // Tracking data for the rate limits, by bucket. Not persisted across upgrades.
+    rate_limits: RefCell<HashMap<RateLimitBucket, RateLimitState>>,
 }
 
 impl Default for State {
@@ -154,7 +237,8 @@ impl Default for State {
             usage_metrics: RefCell::new(UsageMetrics::default()),
             persistent_state: RefCell::new(PersistentState::default()),
             archive_status_cache: RefCell::new(None),
-            registration_rate_limit: RefCell::new(None),
+            archive_push_state: RefCell::new(ArchivePushState::default()),
+            rate_limits: RefCell::new(HashMap::new()),
         }
     }
 }
@@ -265,6 +349,17 @@ pub fn anchor(anchor: AnchorNumber) -> Anchor {
     })
 }
 
+/// Returns the anchor, or None if the anchor number has not been allocated (see [Storage::allocate_anchor_number]).
+pub fn allocated_anchor(anchor_number: AnchorNumber) -> Option<Anchor> {
+    storage_borrow(|storage| match storage.read(anchor_number) {
+        Ok(anchor) => Some(anchor),
+        Err(StorageError::BadAnchorNumber(_)) => None,
+        Err(err) => trap(&format!(
+            "failed to read device data of user {anchor_number}: {err}"
+        )),
+    })
+}
+
 pub fn archive_state() -> ArchiveState {
     STATE.with(|s| s.persistent_state.borrow().archive_state.clone())
 }
@@ -293,6 +388,19 @@ pub fn tentative_device_registrations_mut<R>(
     STATE.with(|s| f(&mut s.tentative_device_registrations.borrow_mut()))
 }
 
+pub fn temp_keys<R>(f: impl FnOnce(&HashMap<AnchorNumber, TempKey>) -> R) -> R {
+    persistent_state(|persistent_state| match persistent_state.temp_keys {
+        Some(ref temp_keys) => f(temp_keys),
+        None => f(&HashMap::new()),
+    })
+}
+
+pub fn temp_keys_mut<R>(f: impl FnOnce(&mut HashMap<AnchorNumber, TempKey>) -> R) -> R {
+    persistent_state_mut(|persistent_state| {
+        f(persistent_state.temp_keys.get_or_insert_with(HashMap::new))
+    })
+}
+
 pub fn assets<R>(f: impl FnOnce(&Assets) -> R) -> R {
     ASSETS.with(|assets| f(&assets.borrow()))
 }
@@ -363,12 +471,12 @@ pub fn persistent_state_mut<R>(f: impl FnOnce(&mut PersistentState) -> R) -> R {
     STATE.with(|s| f(&mut s.persistent_state.borrow_mut()))
 }
 
-pub fn registration_rate_limit<R>(f: impl FnOnce(&Option<RateLimitState>) -> R) -> R {
-    STATE.with(|s| f(&s.registration_rate_limit.borrow()))
+pub fn rate_limits<R>(f: impl FnOnce(&HashMap<RateLimitBucket, RateLimitState>) -> R) -> R {
+    STATE.with(|s| f(&s.rate_limits.borrow()))
 }
 
-pub fn registration_rate_limit_mut<R>(f: impl FnOnce(&mut Option<RateLimitState>) -> R) -> R {
-    STATE.with(|s| f(&mut s.registration_rate_limit.borrow_mut()))
+pub fn rate_limits_mut<R>(f: impl FnOnce(&mut HashMap<RateLimitBucket, RateLimitState>) -> R) -> R {
+    STATE.with(|s| f(&mut s.rate_limits.borrow_mut()))
 }
 
 pub fn cached_archive_status() -> Option<ArchiveStatusCache> {
@@ -390,6 +498,14 @@ pub fn cache_archive_status(archive_status: ArchiveStatusCache) {
     })
 }
 
+pub fn archive_push_state<R>(f: impl FnOnce(&ArchivePushState) -> R) -> R {
+    STATE.with(|s| f(&s.archive_push_state.borrow()))
+}
+
+pub fn archive_push_state_mut<R>(f: impl FnOnce(&mut ArchivePushState) -> R) -> R {
+    STATE.with(|s| f(&mut s.archive_push_state.borrow_mut()))
+}
+
 pub fn invalidate_archive_status_cache() {
     STATE.with(|state| {
         *state.archive_status_cache.borrow_mut() = None;
@@ -3,11 +3,26 @@
 //!
 //! ## Stable Memory Layout
 //!
+//! The first WASM page of stable memory holds the II header. The rest of stable memory is managed by
+//! a [MemoryManager] (whose own header occupies the second WASM page) which provides the following
+//! virtual memories:
+//! * memory 0: anchor records and the persistent state
+//! * memory 1: the archive overflow region
+//!
+//! The memory manager allocates memory to the virtual memories in buckets of 128 WASM pages, as it
+//! is needed. As long as only memory 0 has been used, its buckets are allocated in order and it maps
+//! one-to-one onto the stable memory starting at ENTRY_OFFSET. This makes the layout indistinguishable
+//! from layout version 6 (where the anchor records were stored directly in stable memory) and allows
+//! rolling back to a version supporting only layout version 6. The layout version is bumped to 7 once
+//! memory 1 is used.
+//!
 //! Variables used below:
 //! * HEADER_SIZE: 66 bytes
 //! * ENTRY_OFFSET: 131 072 bytes = 2 WASM Pages
 //! * Anchor size: 4096 bytes
 //!
+//! The addresses below are the addresses in stable memory while memory 0 maps one-to-one onto it.
+//!
 //! ```text
 //! ------------------------------------------- <- Address 0
 //! Magic "IIC"                 ↕ 3 bytes
@@ -26,8 +41,10 @@
 //! -------------------------------------------
 //! Entry offset (ENTRY_OFFSET) ↕ 8 bytes
 //! ------------------------------------------- <- HEADER_SIZE
-//! Reserved space              ↕ (RESERVED_HEADER_BYTES - HEADER_SIZE) bytes
-//! ------------------------------------------- <- ENTRY_OFFSET
+//! Reserved space              ↕ (WASM_PAGE_SIZE - HEADER_SIZE) bytes
+//! ------------------------------------------- <- WASM_PAGE_SIZE
+//! Memory manager header       ↕ WASM_PAGE_SIZE bytes
+//! ------------------------------------------- <- ENTRY_OFFSET (start of memory 0)
 //! A_0_size                    ↕ 2 bytes
 //! -------------------------------------------
 //! Candid encoded entry        ↕ A_0_size bytes
@@ -49,7 +66,7 @@
 //! Unused space A_MAX          ↕ (SIZE_MAX - A_MAX_size - 2) bytes
 //! -------------------------------------------
 //! Unallocated space           ↕ STABLE_MEMORY_RESERVE bytes
-//! -------------------------------------------
+//! ------------------------------------------- <- STABLE_MEMORY_SIZE
 //! ```
 //!
 //! ## Persistent State
@@ -63,6 +80,21 @@
 //! The [PersistentState] is serialized at the end of stable memory to allow for variable sized data
 //! without the risk of running out of space (which might easily happen if the RESERVED_HEADER_BYTES
 //! were used instead).
+//!
+//! ## Unallocated Anchor Numbers
+//!
+//! Anchor numbers are not necessarily allocated in order (see [Storage::allocate_anchor_number]).
+//! The number of anchors in the header is the number of records up to (and including) the record of
+//! the highest allocated anchor number. Records of anchor numbers that have been skipped have an
+//! entry size of 0 and are reported as [StorageError::BadAnchorNumber], just like anchor numbers
+//! beyond the last record.
+//!
+//! ## Archive Overflow Region
+//!
+//! If II is configured to spill archive entries to stable memory (instead of refusing anchor
+//! operations once the archive entries buffer is full), the entries are kept in a bounded queue in
+//! memory 1 (see [archive_overflow]). The memory manager only allocates memory to the region (right
+//! after the memory in use) once entries actually need to be spilled.
 
 use std::convert::TryInto;
 use std::fmt;
@@ -70,16 +102,20 @@ use std::io::{Read, Write};
 use std::ops::RangeInclusive;
 
 use ic_cdk::api::trap;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::reader::{BufferedReader, Reader};
 use ic_stable_structures::writer::{BufferedWriter, Writer};
-use ic_stable_structures::Memory;
+use ic_stable_structures::{Memory, RestrictedMemory};
 
+use internet_identity_interface::archive::types::BufferedEntry;
 use internet_identity_interface::internet_identity::types::*;
 
 use crate::state::PersistentState;
 use crate::storage::anchor::Anchor;
+use crate::storage::archive_overflow::{ArchiveOverflow, ArchiveOverflowError};
 
 pub mod anchor;
+pub mod archive_overflow;
 
 #[cfg(test)]
 mod tests;
@@ -87,13 +123,18 @@ mod tests;
 // version   0: invalid
 // version 1-5: no longer supported
 // version   6: 4KB anchors, candid anchor record layout, persistent state with archive pull config
-// version  7+: invalid
-const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 6..=6;
+// version   7: like version 6, with the archive overflow region in use (memory manager layout)
+// version  8+: invalid
+pub const SUPPORTED_LAYOUT_VERSIONS: RangeInclusive<u8> = 6..=7;
 
 const WASM_PAGE_SIZE: u64 = 65_536;
 
 /// Reserved space for the header before the anchor records start.
 const ENTRY_OFFSET: u64 = 2 * WASM_PAGE_SIZE; // 1 page reserved for II config, 1 for memory manager
+/// Number of WASM pages at the start of stable memory that are not managed by the memory manager.
+const UNMANAGED_PAGES: u64 = 1;
+const ANCHOR_MEMORY_ID: MemoryId = MemoryId::new(0);
+const ARCHIVE_OVERFLOW_MEMORY_ID: MemoryId = MemoryId::new(1);
 const DEFAULT_ENTRY_SIZE: u16 = 4096;
 const EMPTY_SALT: [u8; 32] = [0; 32];
 const GB: u64 = 1 << 30;
@@ -103,6 +144,9 @@ const GB: u64 = 1 << 30;
 const STABLE_MEMORY_SIZE: u64 = 32 * GB;
 /// We reserve the last ~800 MB of stable memory for later new features.
 const STABLE_MEMORY_RESERVE: u64 = 8 * GB / 10;
+/// Maximum size of the memory used to spill archive entries.
+/// This is enough for ~32k entries.
+const ARCHIVE_OVERFLOW_SIZE: u64 = 64 * (1 << 20);
 
 const PERSISTENT_STATE_MAGIC: [u8; 4] = *b"IIPS"; // II Persistent State
 
@@ -112,10 +156,15 @@ pub const DEFAULT_RANGE_SIZE: u64 =
 
 pub type Salt = [u8; 32];
 
+type ManagedMemory<M> = VirtualMemory<RestrictedMemory<M>>;
+
 /// Data type responsible for managing anchor data in stable memory.
-pub struct Storage<M> {
+pub struct Storage<M: Memory> {
     header: Header,
+    /// The whole stable memory, only used directly for the header.
     memory: M,
+    anchor_memory: ManagedMemory<M>,
+    archive_overflow_memory: ManagedMemory<M>,
 }
 
 #[repr(packed)]
@@ -124,7 +173,8 @@ struct Header {
     // version   0: invalid
     // version 1-5: no longer supported
     // version   6: 4KB anchors, candid anchor record layout, persistent state with archive pull config
-    // version  7+: invalid
+    // version   7: like version 6, with the archive overflow region in use (memory manager layout)
+    // version  8+: invalid
     version: u8,
     num_anchors: u32,
     id_range_lo: u64,
@@ -134,7 +184,7 @@ struct Header {
     first_entry_offset: u64,
 }
 
-impl<M: Memory> Storage<M> {
+impl<M: Memory + Clone> Storage<M> {
     /// Creates a new empty storage that manages the data of anchors in
     /// the specified range.
     pub fn new((id_range_lo, id_range_hi): (AnchorNumber, AnchorNumber), memory: M) -> Self {
@@ -150,19 +200,48 @@ impl<M: Memory> Storage<M> {
             ));
         }
 
-        Self {
-            header: Header {
-                magic: *b"IIC",
-                version: 6,
-                num_anchors: 0,
-                id_range_lo,
-                id_range_hi,
-                entry_size: DEFAULT_ENTRY_SIZE,
-                salt: EMPTY_SALT,
-                first_entry_offset: ENTRY_OFFSET,
-            },
+        let header = Header {
+            magic: *b"IIC",
+            version: 6,
+            num_anchors: 0,
+            id_range_lo,
+            id_range_hi,
+            entry_size: DEFAULT_ENTRY_SIZE,
+            salt: EMPTY_SALT,
+            first_entry_offset: ENTRY_OFFSET,
+        };
+        Self::with_managed_memory(header, memory)
+    }
+
+    fn with_managed_memory(header: Header, memory: M) -> Self {
+        let memory_manager = MemoryManager::init(RestrictedMemory::new(
+            memory.clone(),
+            UNMANAGED_PAGES..STABLE_MEMORY_SIZE / WASM_PAGE_SIZE,
+        ));
+        let storage = Self {
+            header,
             memory,
+            anchor_memory: memory_manager.get(ANCHOR_MEMORY_ID),
+            archive_overflow_memory: memory_manager.get(ARCHIVE_OVERFLOW_MEMORY_ID),
+        };
+        if storage.header.version == 6 {
+            // The anchor memory is the only memory in use, so its buckets have been allocated in
+            // order. Make sure it covers everything that has been written to stable memory, which
+            // might have been done (without memory manager) by a version supporting only layout 6.
+            let pages = storage
+                .memory
+                .size()
+                .saturating_sub(ENTRY_OFFSET / WASM_PAGE_SIZE);
+            if storage.anchor_memory.size() < pages
+                && storage
+                    .anchor_memory
+                    .grow(pages - storage.anchor_memory.size())
+                    < 0
+            {
+                trap("failed to grow anchor memory");
+            }
         }
+        storage
     }
 
     pub fn salt(&self) -> Option<&Salt> {
@@ -220,7 +299,7 @@ impl<M: Memory> Storage<M> {
             trap(&format!("unsupported header version: {}", header.version));
         }
 
-        Some(Self { header, memory })
+        Some(Self::with_managed_memory(header, memory))
     }
 
     /// Allocates a fresh Identity Anchor.
@@ -237,6 +316,66 @@ impl<M: Memory> Storage<M> {
         Some((anchor_number, Anchor::new()))
     }
 
+    /// Allocates the given Identity Anchor, which must not be allocated yet.
+    ///
+    /// The records of the anchor numbers skipped (if any) are marked as unallocated. Since these
+    /// records are written, the caller must make sure that only a bounded number of anchor numbers
+    /// is skipped.
+    pub fn allocate_anchor_number(
+        &mut self,
+        anchor_number: AnchorNumber,
+    ) -> Result<Anchor, StorageError> {
+        let (lo, hi) = self.assigned_anchor_number_range();
+        if anchor_number < lo || anchor_number >= hi {
+            return Err(StorageError::AnchorNumberOutOfRange {
+                anchor_number,
+                range: (lo, hi),
+            });
+        }
+
+        let record_number = (anchor_number - lo) as u32;
+        if record_number < self.header.num_anchors {
+            if !self.read_entry_bytes(record_number).is_empty() {
+                return Err(StorageError::AnchorNumberTaken(anchor_number));
+            }
+            return Ok(Anchor::new());
+        }
+
+        // the skipped records might contain leftovers of a persistent state written during an upgrade
+        for skipped in self.header.num_anchors..record_number {
+            self.write_entry_bytes(skipped, vec![])?;
+        }
+        self.header.num_anchors = record_number + 1;
+        self.flush();
+        Ok(Anchor::new())
+    }
+
+    /// Marks the record of the given (allocated) anchor as unallocated again, so that the anchor
+    /// number can be allocated again with [Storage::allocate_anchor_number].
+    pub fn release_anchor_number(
+        &mut self,
+        anchor_number: AnchorNumber,
+    ) -> Result<(), StorageError> {
+        let record_number = self.anchor_number_to_record(anchor_number)?;
+        self.write_entry_bytes(record_number, vec![])
+    }
+
+    /// Drops the record of the given anchor, which must be the highest allocated anchor, so that
+    /// the anchor number is handed out again by [Storage::allocate_anchor].
+    pub fn release_last_anchor_number(
+        &mut self,
+        anchor_number: AnchorNumber,
+    ) -> Result<(), StorageError> {
+        let record_number = self.anchor_number_to_record(anchor_number)?;
+        if record_number + 1 != self.header.num_anchors {
+            return Err(StorageError::BadAnchorNumber(anchor_number));
+        }
+        self.write_entry_bytes(record_number, vec![])?;
+        self.header.num_anchors = record_number;
+        self.flush();
+        Ok(())
+    }
+
     /// Writes the data of the specified anchor to stable memory.
     pub fn write(&mut self, anchor_number: AnchorNumber, data: Anchor) -> Result<(), StorageError> {
         let record_number = self.anchor_number_to_record(anchor_number)?;
@@ -253,7 +392,7 @@ impl<M: Memory> Storage<M> {
         // use buffered writer to minimize expensive stable memory operations
         let mut writer = BufferedWriter::new(
             self.header.entry_size as usize,
-            Writer::new(&mut self.memory, address),
+            Writer::new(&mut self.anchor_memory, address),
         );
         writer
             .write_all(&(buf.len() as u16).to_le_bytes())
@@ -267,6 +406,10 @@ impl<M: Memory> Storage<M> {
     pub fn read(&self, anchor_number: AnchorNumber) -> Result<Anchor, StorageError> {
         let record_number = self.anchor_number_to_record(anchor_number)?;
         let data_buf = self.read_entry_bytes(record_number);
+        if data_buf.is_empty() {
+            // the anchor number was skipped during allocation
+            return Err(StorageError::BadAnchorNumber(anchor_number));
+        }
         candid::decode_one(&data_buf).map_err(StorageError::DeserializationError)
     }
 
@@ -276,7 +419,7 @@ impl<M: Memory> Storage<M> {
         // use buffered reader to minimize expensive stable memory operations
         let mut reader = BufferedReader::new(
             self.header.entry_size as usize,
-            Reader::new(&self.memory, address),
+            Reader::new(&self.anchor_memory, address),
         );
 
         let mut len_buf = vec![0; 2];
@@ -315,6 +458,7 @@ impl<M: Memory> Storage<M> {
         writer.write_all(slice).expect("bug: failed to grow memory");
     }
 
+    /// Returns the number of anchor records, including the records of unallocated anchor numbers.
     pub fn anchor_count(&self) -> usize {
         self.header.num_anchors as usize
     }
@@ -381,8 +525,10 @@ impl<M: Memory> Storage<M> {
         Ok(record_number)
     }
 
+    /// Returns the address of the given record in the anchor memory, which starts at ENTRY_OFFSET.
     fn record_address(&self, record_number: u32) -> u64 {
-        self.header.first_entry_offset + record_number as u64 * self.header.entry_size as u64
+        self.header.first_entry_offset - ENTRY_OFFSET
+            + record_number as u64 * self.header.entry_size as u64
     }
 
     /// The anchor space is divided into two parts:
@@ -390,7 +536,7 @@ impl<M: Memory> Storage<M> {
     /// * length bytes of encoded candid
     ///
     /// This function returns the length limit of the candid part.
-    fn candid_entry_size_limit(&self) -> usize {
+    pub fn candid_entry_size_limit(&self) -> usize {
         self.header.entry_size as usize - std::mem::size_of::<u16>()
     }
 
@@ -411,7 +557,7 @@ impl<M: Memory> Storage<M> {
 
         // In practice, for all reasonably sized persistent states (<800MB) the writes are
         // infallible because we have a stable memory reserve (i.e. growing the memory will succeed).
-        let mut writer = Writer::new(&mut self.memory, address);
+        let mut writer = Writer::new(&mut self.anchor_memory, address);
         writer.write_all(&PERSISTENT_STATE_MAGIC).unwrap();
         writer
             .write_all(&(encoded_state.len() as u64).to_le_bytes())
@@ -425,12 +571,12 @@ impl<M: Memory> Storage<M> {
         const WASM_PAGE_SIZE: u64 = 65536;
         let address = self.unused_memory_start();
 
-        if address > self.memory.size() * WASM_PAGE_SIZE {
+        if address > self.anchor_memory.size() * WASM_PAGE_SIZE {
             // the address where the persistent state would be is not allocated yet
             return Err(PersistentStateError::NotFound);
         }
 
-        let mut reader = Reader::new(&self.memory, address);
+        let mut reader = Reader::new(&self.anchor_memory, address);
         let mut magic_buf: [u8; 4] = [0; 4];
         reader
             .read_exact(&mut magic_buf)
@@ -461,6 +607,44 @@ impl<M: Memory> Storage<M> {
     pub fn version(&self) -> u8 {
         self.header.version
     }
+
+    pub fn entry_size(&self) -> u16 {
+        self.header.entry_size
+    }
+
+    /// Appends the given entry to the archive overflow region.
+    ///
+    /// Bumps the layout version to 7 as the anchor memory no longer maps one-to-one onto the stable
+    /// memory once the memory manager allocates memory to the archive overflow region.
+    pub fn push_archive_overflow_entry(
+        &mut self,
+        entry: &BufferedEntry,
+    ) -> Result<(), ArchiveOverflowError> {
+        if self.header.version < 7 {
+            self.header.version = 7;
+            self.flush();
+        }
+        archive_overflow().push(&mut self.archive_overflow_memory, entry)
+    }
+
+    /// Removes the oldest entry from the archive overflow region and returns it, if any.
+    pub fn pop_archive_overflow_entry(&mut self) -> Option<BufferedEntry> {
+        archive_overflow().pop(&mut self.archive_overflow_memory)
+    }
+
+    /// Returns the number of entries in the archive overflow region.
+    pub fn archive_overflow_len(&self) -> u64 {
+        archive_overflow().len(&self.archive_overflow_memory)
+    }
+
+    /// Returns the maximum number of entries the archive overflow region can hold.
+    pub fn archive_overflow_capacity(&self) -> u64 {
+        archive_overflow().capacity()
+    }
+}
+
+fn archive_overflow() -> ArchiveOverflow {
+    ArchiveOverflow::new(0, ARCHIVE_OVERFLOW_SIZE)
 }
 
 #[derive(Debug)]
@@ -477,6 +661,7 @@ pub enum StorageError {
         range: (AnchorNumber, AnchorNumber),
     },
     BadAnchorNumber(u64),
+    AnchorNumberTaken(AnchorNumber),
     DeserializationError(candid::error::Error),
     SerializationError(candid::error::Error),
     EntrySizeLimitExceeded(usize),
@@ -494,6 +679,7 @@ impl fmt::Display for StorageError {
                 anchor_number, range.0, range.1
             ),
             Self::BadAnchorNumber(n) => write!(f, "bad Identity Anchor {n}"),
+            Self::AnchorNumberTaken(n) => write!(f, "Identity Anchor {n} is already allocated"),
             Self::DeserializationError(err) => {
                 write!(f, "failed to deserialize a Candid value: {err}")
             }
@@ -1,5 +1,5 @@
 use crate::active_anchor_stats::IIDomain;
-use crate::{IC0_APP_ORIGIN, INTERNETCOMPUTER_ORG_ORIGIN};
+use crate::{state, IC0_APP_ORIGIN, INTERNETCOMPUTER_ORG_ORIGIN};
 use candid::{CandidType, Deserialize, Principal};
 use internet_identity_interface::archive::types::DeviceDataWithoutAlias;
 use internet_identity_interface::internet_identity::types::*;
@@ -15,6 +15,10 @@ mod tests;
 #[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
 pub struct Anchor {
     devices: Vec<Device>,
+    // Time at which the anchor was flagged as dormant by the inactivity scan, cleared when acknowledged by the user.
+    flagged_dormant: Option<Timestamp>,
+    // Most recent security events of the anchor (oldest first), bounded by MAX_SECURITY_EVENTS.
+    security_events: Option<Vec<SecurityEvent>>,
 }
 
 impl Device {
@@ -28,6 +32,28 @@ impl Device {
         self.protection = device_data.protection;
         self.origin = device_data.origin;
     }
+
+    /// Applies the fields set in `patch` to self while leaving the other fields intact.
+    pub fn apply_device_patch(&mut self, patch: DeviceDataPatch) {
+        if let Some(alias) = patch.alias {
+            self.alias = alias;
+        }
+        if let Some(credential_id) = patch.credential_id {
+            self.credential_id = Some(credential_id);
+        }
+        if let Some(purpose) = patch.purpose {
+            self.purpose = purpose;
+        }
+        if let Some(key_type) = patch.key_type {
+            self.key_type = key_type;
+        }
+        if let Some(protection) = patch.protection {
+            self.protection = protection;
+        }
+        if let Some(origin) = patch.origin {
+            self.origin = origin;
+        }
+    }
 }
 
 impl From<DeviceData> for Device {
@@ -91,21 +117,48 @@ impl Anchor {
     /// Creation of new anchors is restricted in order to make sure that the device checks are
     /// not accidentally bypassed.
     pub(super) fn new() -> Anchor {
-        Self { devices: vec![] }
+        Self {
+            devices: vec![],
+            flagged_dormant: None,
+            security_events: None,
+        }
     }
 
     pub fn add_device(&mut self, device: Device) -> Result<(), AnchorError> {
+        self.add_device_with_checks(device, InvariantChecks::Immediate)
+    }
+
+    /// Like [add_device](Anchor::add_device), but only checks the anchor invariants if `checks` is
+    /// [InvariantChecks::Immediate].
+    pub fn add_device_with_checks(
+        &mut self,
+        device: Device,
+        checks: InvariantChecks,
+    ) -> Result<(), AnchorError> {
         if self.devices.iter().any(|e| e.pubkey == device.pubkey) {
             return Err(AnchorError::DuplicateDevice {
                 device_key: device.pubkey,
             });
         }
         check_device_invariants(&device)?;
-        check_anchor_invariants(&self.devices.iter().chain(iter::once(&device)).collect())?;
+        if checks == InvariantChecks::Immediate {
+            check_anchor_invariants(
+                &self.devices.iter().chain(iter::once(&device)).collect(),
+                &anchor_limits(),
+            )?;
+        }
         self.devices.push(device);
         Ok(())
     }
 
+    /// Adds several devices at once, e.g. to register an anchor together with its recovery device.
+    /// The anchor invariants are checked for the resulting set of devices only.
+    pub fn add_devices(&mut self, devices: Vec<Device>) -> Result<(), AnchorError> {
+        check_new_devices(&self.devices, &devices)?;
+        self.devices.extend(devices);
+        Ok(())
+    }
+
     /// Removes a device from this anchor.
     /// **Note:** Does not check invariants, based on the assumption that no invariant can be
     /// violated by removing a device. See also the documentation on
@@ -127,6 +180,17 @@ impl Anchor {
         &mut self,
         device_key: &DeviceKey,
         modified_device: Device,
+    ) -> Result<(), AnchorError> {
+        self.modify_device_with_checks(device_key, modified_device, InvariantChecks::Immediate)
+    }
+
+    /// Like [modify_device](Anchor::modify_device), but only checks the anchor invariants if
+    /// `checks` is [InvariantChecks::Immediate].
+    pub fn modify_device_with_checks(
+        &mut self,
+        device_key: &DeviceKey,
+        modified_device: Device,
+        checks: InvariantChecks,
     ) -> Result<(), AnchorError> {
         if device_key != &modified_device.pubkey {
             return Err(AnchorError::CannotModifyDeviceKey);
@@ -134,16 +198,19 @@ impl Anchor {
         check_device_invariants(&modified_device)?;
         let index = self.device_index(device_key)?;
         check_mutation_allowed(&self.devices[index])?;
-        check_anchor_invariants(
-            &self
-                .devices
-                .iter()
-                // filter out the device before modification
-                .filter(|e| e.pubkey != device_key)
-                // append the device with modification
-                .chain(iter::once(&modified_device))
-                .collect(),
-        )?;
+        if checks == InvariantChecks::Immediate {
+            check_anchor_invariants(
+                &self
+                    .devices
+                    .iter()
+                    // filter out the device before modification
+                    .filter(|e| e.pubkey != device_key)
+                    // append the device with modification
+                    .chain(iter::once(&modified_device))
+                    .collect(),
+                &anchor_limits(),
+            )?;
+        }
 
         self.devices[index] = modified_device;
         Ok(())
@@ -156,6 +223,72 @@ impl Anchor {
         Ok(index)
     }
 
+    /// Checks the device and anchor invariants of this anchor.
+    /// Anchors stored in stable memory might not fulfill all invariants (e.g. if they were created
+    /// before an invariant was introduced). This function can be used to find such anchors.
+    pub fn check_invariants(&self) -> Result<(), AnchorError> {
+        self.check_invariants_with_limits(&anchor_limits())
+    }
+
+    /// Like [Anchor::check_invariants] but against the given limits instead of the configured ones.
+    pub fn check_invariants_with_limits(&self, limits: &AnchorLimits) -> Result<(), AnchorError> {
+        for device in &self.devices {
+            check_device_invariants(device)?;
+        }
+        check_anchor_invariants(&self.devices.iter().collect(), limits)
+    }
+
+    /// Checks only the anchor invariants (e.g. the number of devices) of this anchor, i.e. the
+    /// invariants not checked on modifications with [InvariantChecks::Deferred].
+    pub fn check_anchor_invariants(&self) -> Result<(), AnchorError> {
+        check_anchor_invariants(&self.devices.iter().collect(), &anchor_limits())
+    }
+
+    /// Returns all device invariant violations and the first anchor invariant violation of this anchor.
+    pub fn invariant_violations(&self) -> Vec<AnchorError> {
+        let mut violations: Vec<AnchorError> = self
+            .devices
+            .iter()
+            .filter_map(|device| check_device_invariants(device).err())
+            .collect();
+        if let Err(err) = check_anchor_invariants(&self.devices.iter().collect(), &anchor_limits())
+        {
+            violations.push(err);
+        }
+        violations
+    }
+
+    /// Repairs the invariant violations that the policy asks to repair. Only violations that can be
+    /// fixed without removing devices are repaired:
+    ///   * Protection is removed from devices that are not recovery phrases
+    ///
+    /// Other violations (e.g. multiple recovery phrases or exceeding the variable fields limit) are
+    /// left as is, as they cannot be repaired without the user deciding which data to give up.
+    /// **Note:** Bypasses the mutation checks on protected devices, so it must only be used by
+    /// maintenance operations of the canister itself.
+    pub fn repair_invariant_violations(
+        &mut self,
+        policy: &AnchorRepairPolicy,
+    ) -> Vec<DeviceRepair> {
+        let mut repairs = vec![];
+        if policy.invalid_device_protection == ViolationPolicy::Report {
+            return repairs;
+        }
+        for device in self.devices.iter_mut() {
+            if device.protection == DeviceProtection::Protected
+                && device.key_type != KeyType::SeedPhrase
+            {
+                let old = device.clone();
+                device.protection = DeviceProtection::Unprotected;
+                repairs.push(DeviceRepair::Modified {
+                    old,
+                    new: device.clone(),
+                });
+            }
+        }
+        repairs
+    }
+
     /// Returns a reference to the device given the key.
     pub fn device(&self, device_key: &DeviceKey) -> Option<&Device> {
         self.devices.iter().find(|e| e.pubkey == device_key)
@@ -198,6 +331,70 @@ impl Anchor {
         timestamps.pop().unwrap_or_default()
     }
 
+    /// Returns a summary of the recovery setup of this anchor.
+    pub fn recovery_status(&self) -> RecoveryStatus {
+        let (recovery_phrases, recovery_devices): (Vec<&Device>, Vec<&Device>) = self
+            .devices
+            .iter()
+            .filter(|device| device.purpose == Purpose::Recovery)
+            .partition(|device| device.key_type == KeyType::SeedPhrase);
+        let mut authentication_origins = self
+            .devices
+            .iter()
+            .filter(|device| device.purpose == Purpose::Authentication)
+            .map(|device| &device.origin);
+        let authentication_devices_share_origin = match authentication_origins.next() {
+            Some(first) => authentication_origins.all(|origin| origin == first),
+            None => false,
+        };
+        let used = |devices: &[&Device]| {
+            devices
+                .iter()
+                .any(|device| device.last_usage_timestamp.is_some())
+        };
+
+        RecoveryStatus {
+            has_recovery_phrase: !recovery_phrases.is_empty(),
+            has_recovery_device: !recovery_devices.is_empty(),
+            recovery_phrase_used: used(&recovery_phrases),
+            recovery_device_used: used(&recovery_devices),
+            authentication_devices_share_origin,
+        }
+    }
+
+    /// Returns the time at which the anchor was flagged as dormant, if the flag is set.
+    pub fn flagged_dormant(&self) -> Option<Timestamp> {
+        self.flagged_dormant
+    }
+
+    /// Flags the anchor as dormant at the given time.
+    pub fn flag_dormant(&mut self, time: Timestamp) {
+        self.flagged_dormant = Some(time);
+    }
+
+    pub fn clear_dormancy_flag(&mut self) {
+        self.flagged_dormant = None;
+    }
+
+    /// Returns the recorded security events of the anchor, newest first.
+    pub fn security_events(&self) -> Vec<SecurityEvent> {
+        self.security_events
+            .iter()
+            .flatten()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Records a security event, dropping the oldest event if [MAX_SECURITY_EVENTS] is exceeded.
+    pub fn record_security_event(&mut self, event: SecurityEvent) {
+        let events = self.security_events.get_or_insert_with(Vec::new);
+        events.push(event);
+        if events.len() > MAX_SECURITY_EVENTS {
+            events.drain(..events.len() - MAX_SECURITY_EVENTS);
+        }
+    }
+
     /// Returns information about the domains this anchor was active on since the given timestamp.
     /// Activity on unknown / other domain will be dropped if there is also activity on an II domain
     /// for the following reasons:
@@ -251,6 +448,23 @@ impl Anchor {
     }
 }
 
+/// When to check the anchor invariants on modifications of an anchor.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvariantChecks {
+    /// The anchor invariants are checked on each modification.
+    Immediate,
+    /// The anchor invariants are not checked on modifications, so that a sequence of modifications
+    /// may pass through intermediate states violating them. The caller must check the final result
+    /// using [Anchor::check_anchor_invariants]. Device invariants are still checked.
+    Deferred,
+}
+
+/// Change made to a device by [Anchor::repair_invariant_violations].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DeviceRepair {
+    Modified { old: Device, new: Device },
+}
+
 /// Possible outcomes of domain bound activity for an anchor since a specific timestamp.
 pub enum DomainActivity {
     // no activity at all
@@ -322,7 +536,86 @@ fn caller() -> Principal {
     tests::test_caller()
 }
 
-/// This checks anchor invariants, in particular:
+/// The number of devices is limited. The front-end limits the devices further
+/// by only allowing 8 devices with purpose `authentication` to make sure there is always
+/// a slot for the recovery devices.
+/// Note however, that a free device slot does not guarantee that it will fit the the anchor
+/// due to the `DEFAULT_VARIABLE_FIELDS_LIMIT`.
+pub const DEFAULT_MAX_DEVICES_PER_ANCHOR: u64 = 10;
+
+/// Single devices can use up to 564 bytes for the variable length fields alone.
+/// In order to not give away all the anchor space to the device vector, we limit the sum of the
+/// size of all variable fields of all devices. This ensures that we have the flexibility to expand
+/// or change anchors in the future.
+/// The value 2048 was chosen because it is the max anchor size before the stable memory migration.
+/// This means that all pre-existing anchors are below this limit. And after the migration, the
+/// candid encoded `vec devices` will stay far below 4KB in size (testing showed anchors of
+/// ~2500 bytes).
+pub const DEFAULT_VARIABLE_FIELDS_LIMIT: u64 = 2348;
+
+/// Upper bound of the candid encoding overhead of a single device on top of its variable length fields
+/// (fixed size fields, variant tags, option flags and length prefixes).
+const DEVICE_ENCODING_OVERHEAD: u64 = 64;
+/// Upper bound of the candid encoding overhead of an anchor on top of its devices (magic and type table).
+const ANCHOR_ENCODING_OVERHEAD: u64 = 512;
+/// Maximum number of security events kept per anchor.
+pub const MAX_SECURITY_EVENTS: usize = 8;
+/// Upper bound of the candid encoding size of [MAX_SECURITY_EVENTS] security events.
+const SECURITY_EVENTS_ENCODING_OVERHEAD: u64 = 256;
+
+/// Returns the configured anchor limits or the defaults, if not configured.
+pub fn anchor_limits() -> AnchorLimits {
+    state::persistent_state(|persistent_state| persistent_state.anchor_limits.clone())
+        .unwrap_or_else(default_anchor_limits)
+}
+
+/// Returns the anchor limits that apply if none are configured.
+pub fn default_anchor_limits() -> AnchorLimits {
+    AnchorLimits {
+        max_devices: DEFAULT_MAX_DEVICES_PER_ANCHOR,
+        variable_fields_limit: DEFAULT_VARIABLE_FIELDS_LIMIT,
+    }
+}
+
+/// Checks that anchors within the given limits are guaranteed to fit into an entry of the given size.
+pub fn check_anchor_limits(limits: &AnchorLimits, entry_size_limit: usize) -> Result<(), String> {
+    if limits.max_devices == 0 {
+        return Err("anchor limits must allow at least one device".to_string());
+    }
+    let required_size = ANCHOR_ENCODING_OVERHEAD
+        .saturating_add(SECURITY_EVENTS_ENCODING_OVERHEAD)
+        .saturating_add(limits.max_devices.saturating_mul(DEVICE_ENCODING_OVERHEAD))
+        .saturating_add(limits.variable_fields_limit);
+    if required_size > entry_size_limit as u64 {
+        return Err(format!(
+            "anchor limits (max devices {}, variable fields limit {}) require up to {required_size} bytes per anchor, but entries are limited to {entry_size_limit} bytes",
+            limits.max_devices, limits.variable_fields_limit
+        ));
+    }
+    Ok(())
+}
+
+/// Checks whether the given devices can be added to an anchor with the `existing` devices:
+///   * No device key is used twice
+///   * All new devices fulfill the device invariants
+///   * All devices together fulfill the anchor invariants
+pub fn check_new_devices(existing: &[Device], new: &[Device]) -> Result<(), AnchorError> {
+    for (index, device) in new.iter().enumerate() {
+        if existing
+            .iter()
+            .chain(&new[..index])
+            .any(|e| e.pubkey == device.pubkey)
+        {
+            return Err(AnchorError::DuplicateDevice {
+                device_key: device.pubkey.clone(),
+            });
+        }
+        check_device_invariants(device)?;
+    }
+    check_anchor_invariants(&existing.iter().chain(new).collect(), &anchor_limits())
+}
+
+/// This checks anchor invariants with respect to the given limits, in particular:
 ///   * Max number of devices
 ///   * Sum of sizes of all variable length fields does not exceed limit
 ///   * There can only be one recovery phrase
@@ -333,28 +626,17 @@ fn caller() -> Principal {
 /// In order to not break those anchors, they need to have a path back to satisfying the invariants.
 /// To allow that transition, [remove_device](Anchor::remove_device) does _not_ check the invariants based on the assumption
 /// that the state of an anchor cannot get worse by removing a device.
-fn check_anchor_invariants(devices: &Vec<&Device>) -> Result<(), AnchorError> {
-    /// The number of devices is limited. The front-end limits the devices further
-    /// by only allowing 8 devices with purpose `authentication` to make sure there is always
-    /// a slot for the recovery devices.
-    /// Note however, that a free device slot does not guarantee that it will fit the the anchor
-    /// due to the `VARIABLE_FIELDS_LIMIT`.
-    const MAX_DEVICES_PER_ANCHOR: usize = 10;
-
-    /// Single devices can use up to 564 bytes for the variable length fields alone.
-    /// In order to not give away all the anchor space to the device vector, we limit the sum of the
-    /// size of all variable fields of all devices. This ensures that we have the flexibility to expand
-    /// or change anchors in the future.
-    /// The value 2048 was chosen because it is the max anchor size before the stable memory migration.
-    /// This means that all pre-existing anchors are below this limit. And after the migration, the
-    /// candid encoded `vec devices` will stay far below 4KB in size (testing showed anchors of
-    /// ~2500 bytes).
-    const VARIABLE_FIELDS_LIMIT: usize = 2348;
-
-    if devices.len() > MAX_DEVICES_PER_ANCHOR {
+fn check_anchor_invariants(
+    devices: &Vec<&Device>,
+    limits: &AnchorLimits,
+) -> Result<(), AnchorError> {
+    let max_devices = limits.max_devices as usize;
+    let variable_fields_limit = limits.variable_fields_limit as usize;
+
+    if devices.len() > max_devices {
         return Err(AnchorError::TooManyDevices {
             num_devices: devices.len(),
-            limit: MAX_DEVICES_PER_ANCHOR,
+            limit: max_devices,
         });
     }
 
@@ -364,10 +646,10 @@ fn check_anchor_invariants(devices: &Vec<&Device>) -> Result<(), AnchorError> {
         .map(|device| device.variable_fields_len())
         .sum();
 
-    if existing_variable_size > VARIABLE_FIELDS_LIMIT {
+    if existing_variable_size > variable_fields_limit {
         return Err(AnchorError::CumulativeDataLimitExceeded {
             length: existing_variable_size,
-            limit: VARIABLE_FIELDS_LIMIT,
+            limit: variable_fields_limit,
         });
     }
 
@@ -1,7 +1,12 @@
-use crate::storage::anchor::{Anchor, AnchorError, Device};
+use crate::storage::anchor::{
+    check_anchor_invariants, check_anchor_limits, Anchor, AnchorError, Device, DeviceRepair,
+    InvariantChecks, DEFAULT_MAX_DEVICES_PER_ANCHOR, DEFAULT_VARIABLE_FIELDS_LIMIT,
+    MAX_SECURITY_EVENTS,
+};
 use candid::Principal;
 use internet_identity_interface::internet_identity::types::{
-    DeviceData, DeviceProtection, KeyType, Purpose, Timestamp,
+    AnchorLimits, AnchorRepairPolicy, DeviceData, DeviceDataPatch, DeviceProtection, KeyType,
+    Purpose, RecoveryStatus, SecurityEvent, SecurityEventKind, Timestamp, ViolationPolicy,
 };
 use serde_bytes::ByteBuf;
 
@@ -53,6 +58,47 @@ fn should_enforce_max_number_of_devices() {
     assert_eq!(anchor.devices().len(), 10);
 }
 
+#[test]
+fn should_enforce_configured_max_number_of_devices() {
+    let limits = AnchorLimits {
+        max_devices: 11,
+        variable_fields_limit: DEFAULT_VARIABLE_FIELDS_LIMIT,
+    };
+    let devices: Vec<Device> = (0..12).map(device).collect();
+
+    assert_eq!(
+        check_anchor_invariants(&devices[..11].iter().collect(), &limits),
+        Ok(())
+    );
+    assert_eq!(
+        check_anchor_invariants(&devices.iter().collect(), &limits),
+        Err(AnchorError::TooManyDevices {
+            num_devices: 12,
+            limit: 11
+        })
+    );
+}
+
+#[test]
+fn should_reject_anchor_limits_exceeding_entry_size() {
+    let default_limits = AnchorLimits {
+        max_devices: DEFAULT_MAX_DEVICES_PER_ANCHOR,
+        variable_fields_limit: DEFAULT_VARIABLE_FIELDS_LIMIT,
+    };
+    assert_eq!(check_anchor_limits(&default_limits, 4094), Ok(()));
+
+    let too_many_devices = AnchorLimits {
+        max_devices: 100,
+        variable_fields_limit: DEFAULT_VARIABLE_FIELDS_LIMIT,
+    };
+    assert!(check_anchor_limits(&too_many_devices, 4094).is_err());
+    let no_devices = AnchorLimits {
+        max_devices: 0,
+        variable_fields_limit: DEFAULT_VARIABLE_FIELDS_LIMIT,
+    };
+    assert!(check_anchor_limits(&no_devices, 4094).is_err());
+}
+
 #[test]
 fn should_enforce_pubkey_limit() {
     let mut anchor = Anchor::new();
@@ -109,6 +155,40 @@ fn should_enforce_unique_device_keys() {
     assert_eq!(anchor.devices().len(), 1);
 }
 
+#[test]
+fn should_add_devices_at_once() {
+    let mut anchor = Anchor::new();
+    anchor
+        .add_devices(vec![
+            device(0),
+            recovery_phrase(1, DeviceProtection::Protected),
+        ])
+        .unwrap();
+
+    assert_eq!(
+        anchor.devices,
+        vec![device(0), recovery_phrase(1, DeviceProtection::Protected)]
+    );
+}
+
+#[test]
+fn should_check_devices_added_at_once_together() {
+    let mut anchor = Anchor::new();
+
+    let result = anchor.add_devices(vec![device(0), device(0)]);
+    assert!(matches!(result, Err(AnchorError::DuplicateDevice { .. })));
+
+    let result = anchor.add_devices(vec![
+        recovery_phrase(0, DeviceProtection::Unprotected),
+        recovery_phrase(1, DeviceProtection::Unprotected),
+    ]);
+    assert!(matches!(
+        result,
+        Err(AnchorError::MultipleRecoveryPhrases { .. })
+    ));
+    assert!(anchor.devices().is_empty());
+}
+
 #[test]
 fn should_enforce_cumulative_device_limit() {
     let mut anchor = Anchor::new();
@@ -152,6 +232,27 @@ fn should_enforce_single_recovery_phrase() {
     assert_eq!(anchor.devices().len(), 1);
 }
 
+#[test]
+fn should_defer_anchor_invariant_checks() {
+    let mut anchor = Anchor::new();
+    anchor
+        .add_device(recovery_phrase(0, DeviceProtection::Unprotected))
+        .unwrap();
+
+    anchor
+        .add_device_with_checks(
+            recovery_phrase(1, DeviceProtection::Unprotected),
+            InvariantChecks::Deferred,
+        )
+        .unwrap();
+
+    assert_eq!(anchor.devices().len(), 2);
+    assert_eq!(
+        anchor.check_anchor_invariants(),
+        Err(AnchorError::MultipleRecoveryPhrases)
+    );
+}
+
 #[test]
 fn should_allow_protection_only_on_recovery_phrases() {
     let mut anchor = Anchor::new();
@@ -182,6 +283,7 @@ fn should_prevent_mutation_when_invariants_are_violated() {
             device1.clone(),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        flagged_dormant: None,
     };
 
     device1.alias = "new alias".to_string();
@@ -197,6 +299,7 @@ fn should_prevent_addition_when_invariants_are_violated() {
             recovery_phrase(1, DeviceProtection::Unprotected),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        flagged_dormant: None,
     };
 
     let result = anchor.add_device(sample_device());
@@ -212,6 +315,7 @@ fn should_allow_removal_when_invariants_are_violated() {
             device1.clone(),
             recovery_phrase(2, DeviceProtection::Unprotected),
         ],
+        flagged_dormant: None,
     };
 
     anchor.remove_device(&device1.pubkey).unwrap();
@@ -352,6 +456,145 @@ fn should_apply_all_fields() {
     assert_eq!(DeviceData::from(device), device_data);
 }
 
+#[test]
+fn should_check_invariants_of_stored_anchor() {
+    let mut anchor = Anchor::new();
+    anchor.add_device(sample_device()).unwrap();
+    assert_eq!(anchor.check_invariants(), Ok(()));
+
+    // anchors that were written before the invariants were introduced can be in violation
+    anchor
+        .devices
+        .push(recovery_phrase(1, DeviceProtection::Unprotected));
+    anchor
+        .devices
+        .push(recovery_phrase(2, DeviceProtection::Unprotected));
+    assert_eq!(
+        anchor.check_invariants(),
+        Err(AnchorError::MultipleRecoveryPhrases)
+    );
+}
+
+#[test]
+fn should_only_repair_invariant_violations_without_removing_devices() {
+    let mut anchor = Anchor::new();
+    let mut protected_device = sample_device();
+    protected_device.protection = DeviceProtection::Protected;
+    let mut recovery_1 = recovery_phrase(1, DeviceProtection::Protected);
+    recovery_1.last_usage_timestamp = Some(10);
+    let mut recovery_2 = recovery_phrase(2, DeviceProtection::Unprotected);
+    recovery_2.last_usage_timestamp = Some(20);
+    anchor.devices = vec![
+        protected_device.clone(),
+        recovery_1.clone(),
+        recovery_2.clone(),
+    ];
+    assert_eq!(
+        anchor.invariant_violations(),
+        vec![
+            AnchorError::InvalidDeviceProtection {
+                key_type: KeyType::Platform
+            },
+            AnchorError::MultipleRecoveryPhrases
+        ]
+    );
+
+    let report_policy = AnchorRepairPolicy {
+        invalid_device_protection: ViolationPolicy::Report,
+    };
+    assert_eq!(anchor.repair_invariant_violations(&report_policy), vec![]);
+
+    let repair_policy = AnchorRepairPolicy {
+        invalid_device_protection: ViolationPolicy::Repair,
+    };
+    let repairs = anchor.repair_invariant_violations(&repair_policy);
+
+    let mut unprotected_device = protected_device.clone();
+    unprotected_device.protection = DeviceProtection::Unprotected;
+    assert_eq!(
+        repairs,
+        vec![DeviceRepair::Modified {
+            old: protected_device,
+            new: unprotected_device.clone()
+        }]
+    );
+    // the recovery phrases are kept, as either of them might be the one the user relies on
+    assert_eq!(
+        anchor.devices,
+        vec![unprotected_device, recovery_1, recovery_2]
+    );
+    assert_eq!(
+        anchor.invariant_violations(),
+        vec![AnchorError::MultipleRecoveryPhrases]
+    );
+}
+
+#[test]
+fn should_apply_only_patched_fields() {
+    let mut device = sample_device();
+
+    device.apply_device_patch(DeviceDataPatch {
+        alias: Some("new alias".to_string()),
+        origin: Some(None),
+        ..DeviceDataPatch::default()
+    });
+
+    let mut expected = sample_device();
+    expected.alias = "new alias".to_string();
+    expected.origin = None;
+    assert_eq!(device, expected);
+}
+
+#[test]
+fn should_summarize_recovery_status() {
+    let mut anchor = Anchor::new();
+    anchor.add_device(device(1)).unwrap();
+    anchor
+        .add_device(recovery_phrase(2, DeviceProtection::Unprotected))
+        .unwrap();
+    anchor
+        .add_device(Device {
+            purpose: Purpose::Recovery,
+            key_type: KeyType::CrossPlatform,
+            ..device(3)
+        })
+        .unwrap();
+
+    assert_eq!(
+        anchor.recovery_status(),
+        RecoveryStatus {
+            has_recovery_phrase: true,
+            has_recovery_device: true,
+            recovery_phrase_used: false,
+            recovery_device_used: true,
+            authentication_devices_share_origin: true,
+        }
+    );
+
+    anchor.add_device(device(4)).unwrap();
+    assert!(!anchor.recovery_status().authentication_devices_share_origin);
+}
+
+#[test]
+fn should_keep_only_most_recent_security_events() {
+    let mut anchor = Anchor::new();
+    let event = |timestamp: Timestamp| SecurityEvent {
+        timestamp,
+        kind: SecurityEventKind::DeviceAdded,
+        purpose: Purpose::Authentication,
+        key_type: KeyType::Unknown,
+    };
+    for timestamp in 0..(MAX_SECURITY_EVENTS as u64 + 3) {
+        anchor.record_security_event(event(timestamp));
+    }
+
+    let expected: Vec<SecurityEvent> = (3..(MAX_SECURITY_EVENTS as u64 + 3))
+        .rev()
+        .map(event)
+        .collect();
+    assert_eq!(anchor.security_events(), expected);
+}
+
 fn sample_device() -> Device {
     Device {
         pubkey: ByteBuf::from("public key of some sample device"),
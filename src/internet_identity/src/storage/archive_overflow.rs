@@ -0,0 +1,193 @@
+//! Bounded FIFO queue of buffered archive entries in a region of (virtual) stable memory.
+//!
+//! This queue is used to spill archive entries that do not fit into the (heap) archive entries
+//! buffer anymore, if II is configured to do so (see [BufferOverflowPolicy]). The region does not
+//! need to be initialized: as long as the magic is missing, the queue is considered empty.
+//!
+//! [BufferOverflowPolicy]: internet_identity_interface::internet_identity::types::BufferOverflowPolicy
+//!
+//! ## Region Layout
+//!
+//! ```text
+//! ------------------------------------------- <- base address
+//! Magic "IIAO"                ↕ 4 bytes
+//! -------------------------------------------
+//! Index of the first slot     ↕ 8 bytes
+//! -------------------------------------------
+//! Number of entries           ↕ 8 bytes
+//! -------------------------------------------
+//! Unused                      ↕ (SLOT_SIZE - 20) bytes
+//! ------------------------------------------- <- base address + SLOT_SIZE (slot 0)
+//! Entry size                  ↕ 2 bytes
+//! -------------------------------------------
+//! Candid encoded entry        ↕ entry size bytes
+//! -------------------------------------------
+//! Unused                      ↕ (SLOT_SIZE - entry size - 2) bytes
+//! ------------------------------------------- <- base address + 2 * SLOT_SIZE (slot 1)
+//! ...
+//! ```
+//!
+//! The slots are used as a ring buffer, i.e. the slot following the last slot is slot 0.
+
+use ic_stable_structures::reader::Reader;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::Memory;
+use internet_identity_interface::archive::types::BufferedEntry;
+use std::fmt;
+use std::io::{Read, Write};
+
+#[cfg(test)]
+mod tests;
+
+const MAGIC: [u8; 4] = *b"IIAO"; // II Archive Overflow
+const HEADER_SIZE: usize = 20;
+pub const SLOT_SIZE: u64 = 2048;
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// Archive overflow queue located at a fixed address in the given memory.
+#[derive(Clone, Copy, Debug)]
+pub struct ArchiveOverflow {
+    base: u64,
+    capacity: u64,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct QueueHeader {
+    first_slot: u64,
+    len: u64,
+}
+
+#[derive(Debug)]
+pub enum ArchiveOverflowError {
+    /// All slots of the region are in use.
+    Full { capacity: u64 },
+    /// The encoded entry does not fit into a single slot.
+    EntryTooLarge(usize),
+}
+
+impl fmt::Display for ArchiveOverflowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full { capacity } => {
+                write!(f, "archive overflow region is full ({capacity} entries)")
+            }
+            Self::EntryTooLarge(n) => write!(
+                f,
+                "attempted to spill an archive entry of size {n} \
+                 which is larger than the archive overflow slot size"
+            ),
+        }
+    }
+}
+
+impl ArchiveOverflow {
+    /// Creates the queue for the region starting at `base` and spanning `region_size` bytes.
+    pub fn new(base: u64, region_size: u64) -> Self {
+        Self {
+            base,
+            // the first slot is used for the header
+            capacity: region_size / SLOT_SIZE - 1,
+        }
+    }
+
+    /// Maximum number of entries the queue can hold.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Number of entries currently in the queue.
+    pub fn len<M: Memory>(&self, memory: &M) -> u64 {
+        self.read_header(memory).len
+    }
+
+    /// Appends an entry to the end of the queue.
+    pub fn push<M: Memory>(
+        &self,
+        memory: &mut M,
+        entry: &BufferedEntry,
+    ) -> Result<(), ArchiveOverflowError> {
+        let mut header = self.read_header(memory);
+        if header.len >= self.capacity {
+            return Err(ArchiveOverflowError::Full {
+                capacity: self.capacity,
+            });
+        }
+
+        // In practice, candid encoding is infallible. The Result is an artifact of the serde API.
+        let buf = candid::encode_one(entry).unwrap();
+        if buf.len() > SLOT_SIZE as usize - std::mem::size_of::<u16>() {
+            return Err(ArchiveOverflowError::EntryTooLarge(buf.len()));
+        }
+
+        let slot = (header.first_slot + header.len) % self.capacity;
+        let mut writer = Writer::new(memory, self.slot_address(slot));
+        writer
+            .write_all(&(buf.len() as u16).to_le_bytes())
+            .expect("memory write failed");
+        writer.write_all(&buf).expect("memory write failed");
+
+        header.len += 1;
+        self.write_header(memory, &header);
+        Ok(())
+    }
+
+    /// Removes the first entry from the queue and returns it, if any.
+    pub fn pop<M: Memory>(&self, memory: &mut M) -> Option<BufferedEntry> {
+        let mut header = self.read_header(memory);
+        if header.len == 0 {
+            return None;
+        }
+
+        let mut reader = Reader::new(memory, self.slot_address(header.first_slot));
+        let mut len_buf = [0u8; 2];
+        reader
+            .read_exact(&mut len_buf)
+            .expect("failed to read memory");
+        let mut data_buf = vec![0; u16::from_le_bytes(len_buf) as usize];
+        reader
+            .read_exact(&mut data_buf)
+            .expect("failed to read memory");
+        let entry = candid::decode_one(&data_buf).expect("failed to decode archive overflow entry");
+
+        header.first_slot = (header.first_slot + 1) % self.capacity;
+        header.len -= 1;
+        self.write_header(memory, &header);
+        Some(entry)
+    }
+
+    fn slot_address(&self, slot: u64) -> u64 {
+        self.base + (slot + 1) * SLOT_SIZE
+    }
+
+    fn read_header<M: Memory>(&self, memory: &M) -> QueueHeader {
+        const EMPTY: QueueHeader = QueueHeader {
+            first_slot: 0,
+            len: 0,
+        };
+
+        // do not grow the memory just to find out that the region has never been used
+        if memory.size() * WASM_PAGE_SIZE < self.base + HEADER_SIZE as u64 {
+            return EMPTY;
+        }
+
+        let mut buf = [0u8; HEADER_SIZE];
+        memory.read(self.base, &mut buf);
+        if buf[0..4] != MAGIC {
+            return EMPTY;
+        }
+        QueueHeader {
+            first_slot: u64::from_le_bytes(buf[4..12].try_into().unwrap()),
+            len: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
+        }
+    }
+
+    fn write_header<M: Memory>(&self, memory: &mut M, header: &QueueHeader) {
+        let mut buf = Vec::with_capacity(HEADER_SIZE);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&header.first_slot.to_le_bytes());
+        buf.extend_from_slice(&header.len.to_le_bytes());
+
+        let mut writer = Writer::new(memory, self.base);
+        writer.write_all(&buf).expect("memory write failed");
+    }
+}
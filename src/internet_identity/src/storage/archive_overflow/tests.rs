@@ -0,0 +1,104 @@
+use crate::storage::archive_overflow::{ArchiveOverflow, ArchiveOverflowError, SLOT_SIZE};
+use ic_stable_structures::{Memory, VectorMemory};
+use internet_identity_interface::archive::types::BufferedEntry;
+use serde_bytes::ByteBuf;
+
+const BASE: u64 = 1024;
+
+#[test]
+fn should_be_empty_on_fresh_memory() {
+    let memory = VectorMemory::default();
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+
+    assert_eq!(overflow.len(&memory), 0);
+    assert_eq!(overflow.capacity(), 3);
+    // reading the (empty) queue must not grow the memory
+    assert_eq!(memory.size(), 0);
+}
+
+#[test]
+fn should_pop_entries_in_insertion_order() {
+    let mut memory = VectorMemory::default();
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+
+    overflow.push(&mut memory, &sample_entry(0)).unwrap();
+    overflow.push(&mut memory, &sample_entry(1)).unwrap();
+    assert_eq!(overflow.len(&memory), 2);
+
+    assert_eq!(overflow.pop(&mut memory), Some(sample_entry(0)));
+    assert_eq!(overflow.pop(&mut memory), Some(sample_entry(1)));
+    assert_eq!(overflow.pop(&mut memory), None);
+    assert_eq!(overflow.len(&memory), 0);
+}
+
+#[test]
+fn should_wrap_around() {
+    let mut memory = VectorMemory::default();
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+
+    for i in 0..3 {
+        overflow.push(&mut memory, &sample_entry(i)).unwrap();
+    }
+    for i in 3..10 {
+        assert_eq!(overflow.pop(&mut memory), Some(sample_entry(i - 3)));
+        overflow.push(&mut memory, &sample_entry(i)).unwrap();
+    }
+
+    assert_eq!(overflow.len(&memory), 3);
+    for i in 7..10 {
+        assert_eq!(overflow.pop(&mut memory), Some(sample_entry(i)));
+    }
+}
+
+#[test]
+fn should_refuse_entries_when_full() {
+    let mut memory = VectorMemory::default();
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+
+    for i in 0..3 {
+        overflow.push(&mut memory, &sample_entry(i)).unwrap();
+    }
+
+    let result = overflow.push(&mut memory, &sample_entry(3));
+    assert!(matches!(
+        result,
+        Err(ArchiveOverflowError::Full { capacity: 3 })
+    ));
+    assert_eq!(overflow.len(&memory), 3);
+}
+
+#[test]
+fn should_refuse_entries_larger_than_slot() {
+    let mut memory = VectorMemory::default();
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+    let mut entry = sample_entry(0);
+    entry.entry = ByteBuf::from(vec![7u8; SLOT_SIZE as usize]);
+
+    let result = overflow.push(&mut memory, &entry);
+    assert!(matches!(
+        result,
+        Err(ArchiveOverflowError::EntryTooLarge(_))
+    ));
+    assert_eq!(overflow.len(&memory), 0);
+}
+
+#[test]
+fn should_keep_entries_in_memory() {
+    let mut memory = VectorMemory::default();
+    ArchiveOverflow::new(BASE, 4 * SLOT_SIZE)
+        .push(&mut memory, &sample_entry(0))
+        .unwrap();
+
+    let overflow = ArchiveOverflow::new(BASE, 4 * SLOT_SIZE);
+    assert_eq!(overflow.len(&memory), 1);
+    assert_eq!(overflow.pop(&mut memory), Some(sample_entry(0)));
+}
+
+fn sample_entry(sequence_number: u64) -> BufferedEntry {
+    BufferedEntry {
+        anchor_number: 10_000 + sequence_number,
+        timestamp: 1_000 * sequence_number,
+        entry: ByteBuf::from(vec![sequence_number as u8; 500]),
+        sequence_number,
+    }
+}
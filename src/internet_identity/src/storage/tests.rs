@@ -5,6 +5,7 @@ use crate::storage::{Header, PersistentStateError, StorageError};
 use crate::Storage;
 use candid::Principal;
 use ic_stable_structures::{Memory, VectorMemory};
+use internet_identity_interface::archive::types::BufferedEntry;
 use internet_identity_interface::internet_identity::types::{
     ActiveAnchorCounter, ActiveAnchorStatistics, ArchiveConfig, CompletedActiveAnchorStats,
     DeviceProtection, KeyType, OngoingActiveAnchorStats, Purpose,
@@ -106,6 +107,57 @@ fn should_serialize_subsequent_record_to_expected_memory_location() {
     assert_eq!(decoded_from_memory, anchor);
 }
 
+#[test]
+fn should_place_archive_overflow_next_to_anchors() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory.clone());
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor.clone()).unwrap();
+    assert_eq!(storage.version(), 6);
+
+    storage
+        .push_archive_overflow_entry(&sample_buffered_entry())
+        .unwrap();
+
+    // one bucket of 128 pages for each memory
+    assert_eq!(memory.size(), 2 + 2 * 128);
+    assert_eq!(storage.version(), 7);
+
+    let mut storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.version(), 7);
+    assert_eq!(storage.read(anchor_number).unwrap(), anchor);
+    assert_eq!(
+        storage.pop_archive_overflow_entry(),
+        Some(sample_buffered_entry())
+    );
+}
+
+#[test]
+fn should_read_anchors_written_without_memory_manager() {
+    const EXPECTED_LENGTH: usize = 217;
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory.clone());
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor).unwrap();
+
+    // simulate a version supporting only layout 6 writing a record beyond the anchor memory
+    let mut buf = [0u8; EXPECTED_LENGTH];
+    memory.read(RESERVED_HEADER_BYTES, &mut buf);
+    let other_record_address = RESERVED_HEADER_BYTES + 200 * 4096;
+    memory.grow(other_record_address / WASM_PAGE_SIZE + 1 - memory.size());
+    memory.write(other_record_address, &buf);
+    memory.write(0, &hex::decode("49494306c9000000").unwrap());
+
+    let storage = Storage::from_memory(memory).unwrap();
+    assert_eq!(storage.anchor_count(), 201);
+    assert_eq!(
+        storage.read(anchor_number + 200).unwrap(),
+        storage.read(anchor_number).unwrap()
+    );
+}
+
 #[test]
 fn should_not_write_using_anchor_number_outside_allocated_range() {
     let memory = VectorMemory::default();
@@ -167,6 +219,66 @@ fn should_not_read_using_anchor_number_outside_allocated_range() {
     assert!(matches!(result, Err(StorageError::BadAnchorNumber(_))))
 }
 
+#[test]
+fn should_allocate_anchor_number_out_of_order() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let mut anchor = storage.allocate_anchor_number(126).unwrap();
+    assert_eq!(storage.anchor_count(), 4);
+
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(126, anchor.clone()).unwrap();
+    assert_eq!(storage.read(126).unwrap(), anchor);
+
+    // skipped anchor numbers are not allocated but can be allocated later
+    assert!(matches!(
+        storage.read(124),
+        Err(StorageError::BadAnchorNumber(124))
+    ));
+    storage.allocate_anchor_number(124).unwrap();
+    assert_eq!(storage.anchor_count(), 4);
+}
+
+#[test]
+fn should_not_allocate_anchor_number_twice() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let (anchor_number, mut anchor) = storage.allocate_anchor().unwrap();
+    anchor.add_device(sample_device()).unwrap();
+    storage.write(anchor_number, anchor).unwrap();
+
+    let result = storage.allocate_anchor_number(anchor_number);
+    assert!(matches!(result, Err(StorageError::AnchorNumberTaken(123))));
+}
+
+#[test]
+fn should_release_last_anchor_number() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory);
+    let (first, _) = storage.allocate_anchor().unwrap();
+    let (second, _) = storage.allocate_anchor().unwrap();
+
+    let result = storage.release_last_anchor_number(first);
+    assert!(matches!(result, Err(StorageError::BadAnchorNumber(123))));
+
+    storage.release_last_anchor_number(second).unwrap();
+    assert_eq!(storage.anchor_count(), 1);
+    assert_eq!(storage.allocate_anchor().unwrap().0, second);
+}
+
+#[test]
+fn should_clear_persistent_state_leftovers_of_skipped_anchor_numbers() {
+    let memory = VectorMemory::default();
+    let mut storage = Storage::new((123, 456), memory.clone());
+    storage.write_persistent_state(&sample_persistent_state());
+
+    storage.allocate_anchor_number(125).unwrap();
+    assert!(matches!(
+        storage.read(123),
+        Err(StorageError::BadAnchorNumber(123))
+    ));
+}
+
 #[test]
 fn should_save_and_restore_persistent_state() {
     let memory = VectorMemory::default();
@@ -346,6 +458,9 @@ fn sample_persistent_state() -> PersistentState {
                 entries_buffer_limit: 10_000,
                 polling_interval_ns: 60_000_000_000,
                 entries_fetch_limit: 1_000,
+                archive_integration: None,
+                buffer_overflow_policy: None,
+                retention_period_ns: None,
             },
         },
         canister_creation_cycles_cost: 12_346_000_000,
@@ -372,5 +487,28 @@ fn sample_persistent_state() -> PersistentState {
         domain_active_anchor_stats: None,
         latest_delegation_origins: None,
         max_num_latest_delegation_origins: None,
+        controllers: None,
+        shards: None,
+        anchor_repair: None,
+        anchor_limits: None,
+        dormancy_policy: None,
+        inactivity_scan: None,
+        device_registration: None,
+        rate_limits: None,
+        anchor_allocation: None,
+        anchor_bitmap: None,
+        anchor_reservations: None,
+        allocated_anchors: None,
+        adaptive_captcha: None,
+        temp_keys: None,
+    }
+}
+
+fn sample_buffered_entry() -> BufferedEntry {
+    BufferedEntry {
+        anchor_number: 10_000,
+        timestamp: 1_000,
+        entry: ByteBuf::from(vec![1u8; 500]),
+        sequence_number: 0,
     }
 }
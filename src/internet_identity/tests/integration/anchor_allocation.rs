@@ -0,0 +1,157 @@
+//! Tests for the anchor number allocation strategies and anchor number reservations.
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, device_data_2, env, expect_user_error_with_message,
+    install_ii_canister_with_arg, principal_1, principal_2, upgrade_ii_canister, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    AnchorAllocationStrategy, AnchorReservation, ClaimReservedAnchorResponse, InternetIdentityInit,
+};
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Verifies that randomized anchor numbers are unique, within the window and survive upgrades.
+#[test]
+fn should_allocate_random_anchor_numbers() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            anchor_allocation: Some(AnchorAllocationStrategy::Randomized { window_size: 200 }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+
+    let mut anchor_numbers = HashSet::new();
+    for i in 0..20 {
+        if i == 10 {
+            upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+        }
+        let anchor_number = flows::register_anchor(&env, canister_id);
+        assert!((10_000..10_000 + (i + 1) * 200).contains(&anchor_number));
+        assert!(anchor_numbers.insert(anchor_number));
+        assert_eq!(
+            api::lookup(&env, canister_id, anchor_number)?[0].pubkey,
+            device_data_1().pubkey
+        );
+    }
+    assert_ne!(
+        anchor_numbers,
+        (10_000..10_020).collect::<HashSet<_>>(),
+        "anchor numbers were allocated sequentially"
+    );
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 20);
+    Ok(())
+}
+
+/// Verifies that a reserved anchor number is skipped and can be claimed by the given device only.
+#[test]
+fn should_claim_reserved_anchor_number() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            controllers: Some(vec![principal_2()]),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    api::reserve_anchor(
+        &env,
+        canister_id,
+        principal_2(),
+        10_002,
+        device_data_2().pubkey,
+    )?;
+    assert_eq!(
+        api::anchor_reservations(&env, canister_id, principal_2())?,
+        vec![AnchorReservation {
+            anchor_number: 10_002,
+            device_key: device_data_2().pubkey,
+        }]
+    );
+
+    let anchor_numbers: Vec<_> = (0..3)
+        .map(|_| flows::register_anchor(&env, canister_id))
+        .collect();
+    assert_eq!(anchor_numbers, vec![10_000, 10_001, 10_003]);
+
+    let response =
+        api::claim_reserved_anchor(&env, canister_id, principal_1(), 10_002, &device_data_1())?;
+    assert_eq!(response, ClaimReservedAnchorResponse::NotReserved);
+
+    let response =
+        api::claim_reserved_anchor(&env, canister_id, principal_2(), 10_002, &device_data_2())?;
+    assert_eq!(
+        response,
+        ClaimReservedAnchorResponse::Claimed {
+            user_number: 10_002
+        }
+    );
+    assert_eq!(
+        api::lookup(&env, canister_id, 10_002)?[0].pubkey,
+        device_data_2().pubkey
+    );
+    assert!(api::anchor_reservations(&env, canister_id, principal_2())?.is_empty());
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 4);
+
+    let response =
+        api::claim_reserved_anchor(&env, canister_id, principal_2(), 10_002, &device_data_2())?;
+    assert_eq!(response, ClaimReservedAnchorResponse::NotReserved);
+    Ok(())
+}
+
+/// Verifies that only controllers can reserve anchor numbers and only free ones.
+#[test]
+fn should_not_reserve_taken_anchor_number() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            controllers: Some(vec![principal_2()]),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    let anchor_number = flows::register_anchor(&env, canister_id);
+
+    expect_user_error_with_message(
+        api::reserve_anchor(
+            &env,
+            canister_id,
+            principal_1(),
+            10_005,
+            device_data_2().pubkey,
+        ),
+        CanisterCalledTrap,
+        Regex::new("is not a controller").unwrap(),
+    );
+    expect_user_error_with_message(
+        api::reserve_anchor(
+            &env,
+            canister_id,
+            principal_2(),
+            anchor_number,
+            device_data_2().pubkey,
+        ),
+        CanisterCalledTrap,
+        Regex::new("anchor 10000 is already taken").unwrap(),
+    );
+    expect_user_error_with_message(
+        api::reserve_anchor(
+            &env,
+            canister_id,
+            principal_2(),
+            20_001,
+            device_data_2().pubkey,
+        ),
+        CanisterCalledTrap,
+        Regex::new("more than 10000 anchor numbers beyond").unwrap(),
+    );
+    Ok(())
+}
@@ -210,6 +210,76 @@ fn should_respect_total_size_limit() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Verifies that add_v2 returns an error if the configured number of devices is exceeded.
+#[test]
+fn should_respect_configured_device_limit() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            anchor_limits: Some(AnchorLimits {
+                max_devices: 2,
+                variable_fields_limit: 2348,
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::add_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_2(),
+    )?;
+    assert_eq!(result, AddResponse::Ok);
+
+    let result = api::add_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        recovery_device_data_1(),
+    )?;
+    assert_eq!(
+        result,
+        AddResponse::TooManyDevices {
+            num_devices: 3,
+            limit: 2
+        }
+    );
+    assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 2);
+    Ok(())
+}
+
+/// Verifies that anchor limits exceeding the space available per anchor are rejected.
+#[test]
+fn should_reject_anchor_limits_exceeding_storage() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            anchor_limits: Some(AnchorLimits {
+                max_devices: 100,
+                variable_fields_limit: 2348,
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("anchor limits \\(max devices 100, variable fields limit 2348\\) require up to \\d+ bytes per anchor").unwrap(),
+    );
+}
+
 /// Verifies that a device can be updated
 #[test]
 fn should_update_device() -> Result<(), CallError> {
@@ -711,3 +781,311 @@ fn should_replace_device() -> Result<(), CallError> {
     assert_eq!(anchor_info.into_device_data(), vec![device_data_2()]);
     Ok(())
 }
+
+/// Verifies that add_v2 returns an error for duplicate devices.
+#[test]
+fn should_return_error_on_duplicate_device_v2() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::add_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_1(),
+    )?;
+
+    assert_eq!(
+        result,
+        AddResponse::DuplicateDevice {
+            device_key: device_data_1().pubkey
+        }
+    );
+    Ok(())
+}
+
+/// Verifies that update_v2 updates the device and returns an error for unknown devices.
+#[test]
+fn should_update_device_v2() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let mut device = device_data_1();
+    device.alias = "new alias".to_string();
+
+    let result = api::update_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device.pubkey.clone(),
+        device.clone(),
+    )?;
+    assert_eq!(result, UpdateResponse::Ok);
+    let devices =
+        api::get_anchor_info(&env, canister_id, principal_1(), user_number)?.into_device_data();
+    assert_eq!(devices, vec![device]);
+
+    let result = api::update_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_2().pubkey,
+        device_data_2(),
+    )?;
+    assert_eq!(
+        result,
+        UpdateResponse::NotFound {
+            device_key: device_data_2().pubkey
+        }
+    );
+    Ok(())
+}
+
+/// Verifies that a failed replace_v2 leaves the anchor unchanged.
+#[test]
+fn should_not_remove_device_on_failed_replace_v2() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_2(),
+    )?;
+
+    // replacing device 2 with device 1 fails because device 1 already exists
+    let result = api::replace_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_2().pubkey,
+        device_data_1(),
+    )?;
+
+    assert_eq!(
+        result,
+        ReplaceResponse::DuplicateDevice {
+            device_key: device_data_1().pubkey
+        }
+    );
+    assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 2);
+    Ok(())
+}
+
+/// Verifies that remove_v2 returns an error if a protected device is removed using another device.
+#[test]
+fn should_return_error_on_removing_protected_device_v2() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let mut device2 = device_data_2();
+    device2.protection = DeviceProtection::Protected;
+    device2.key_type = KeyType::SeedPhrase;
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device2.clone(),
+    )?;
+
+    let result = api::remove_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device2.pubkey,
+    )?;
+
+    assert_eq!(
+        result,
+        RemoveResponse::MutationNotAllowed {
+            authorized_principal: principal_2(),
+            actual_principal: principal_1()
+        }
+    );
+    assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 2);
+    Ok(())
+}
+
+/// Verifies that multiple operations can be applied in a single call.
+#[test]
+fn should_apply_operations() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let mut device2 = device_data_2();
+    device2.alias = "renamed".to_string();
+
+    let result = api::apply_operations(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        vec![
+            AnchorOp::Add {
+                device_data: device_data_2(),
+            },
+            AnchorOp::Update {
+                device_key: device2.pubkey.clone(),
+                device_data: device2.clone(),
+            },
+            AnchorOp::Remove {
+                device_key: device_data_1().pubkey,
+            },
+        ],
+    )?;
+
+    assert_eq!(result, ApplyOperationsResponse::Ok);
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_2(), user_number)?;
+    assert_eq!(anchor_info.into_device_data(), vec![device2]);
+    Ok(())
+}
+
+/// Verifies that no operation is applied if one of them fails.
+#[test]
+fn should_not_apply_any_operation_on_failure() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::apply_operations(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        vec![
+            AnchorOp::Add {
+                device_data: device_data_2(),
+            },
+            AnchorOp::Add {
+                device_data: device_data_2(),
+            },
+        ],
+    )?;
+
+    assert_eq!(
+        result,
+        ApplyOperationsResponse::Failed {
+            index: 1,
+            error: AnchorOpError::Add(AddResponse::DuplicateDevice {
+                device_key: device_data_2().pubkey
+            })
+        }
+    );
+    assert_eq!(
+        api::lookup(&env, canister_id, user_number)?
+            .into_iter()
+            .map(|device| device.pubkey)
+            .collect::<Vec<_>>(),
+        vec![device_data_1().pubkey]
+    );
+    Ok(())
+}
+
+/// Verifies that the anchor invariants are only checked for the result of all operations, i.e. that a
+/// recovery phrase can be replaced by adding the new one before removing the old one.
+#[test]
+fn should_check_invariants_only_for_result_of_operations() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        recovery_device_data_1(),
+    )?;
+
+    let result = api::apply_operations(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        vec![
+            AnchorOp::Add {
+                device_data: recovery_device_data_2(),
+            },
+            AnchorOp::Remove {
+                device_key: recovery_device_data_1().pubkey,
+            },
+        ],
+    )?;
+
+    assert_eq!(result, ApplyOperationsResponse::Ok);
+    assert_eq!(
+        api::lookup(&env, canister_id, user_number)?
+            .into_iter()
+            .map(|device| device.pubkey)
+            .collect::<Vec<_>>(),
+        vec![device_data_1().pubkey, recovery_device_data_2().pubkey]
+    );
+    Ok(())
+}
+
+/// Verifies that no operation is applied if the result of all operations violates the anchor invariants.
+#[test]
+fn should_not_apply_operations_resulting_in_invariant_violation() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::apply_operations(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        vec![
+            AnchorOp::Add {
+                device_data: recovery_device_data_1(),
+            },
+            AnchorOp::Add {
+                device_data: recovery_device_data_2(),
+            },
+        ],
+    )?;
+
+    assert_eq!(
+        result,
+        ApplyOperationsResponse::InvariantViolation(
+            AnchorInvariantViolation::MultipleRecoveryPhrases
+        )
+    );
+    assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 1);
+    Ok(())
+}
+
+/// Verifies that update_device_fields only changes the given fields.
+#[test]
+fn should_update_device_fields() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    let result = api::update_device_fields(
+        &env,
+        canister_id,
+        principal_1(),
+        user_number,
+        device_data_1().pubkey,
+        DeviceDataPatch {
+            alias: Some("renamed".to_string()),
+            ..DeviceDataPatch::default()
+        },
+    )?;
+
+    assert_eq!(result, UpdateResponse::Ok);
+    let mut expected = device_data_1();
+    expected.alias = "renamed".to_string();
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(anchor_info.into_device_data(), vec![expected]);
+    Ok(())
+}
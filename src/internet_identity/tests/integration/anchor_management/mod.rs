@@ -4,3 +4,4 @@ mod device_management;
 mod last_usage_timestamp;
 mod registration;
 mod remote_device_registration;
+mod security_events;
@@ -2,12 +2,15 @@
 //! 1. create_challenge: retrieve a captcha
 //! 2. register: submit the captcha solution and device information to create a new anchor
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use candid::Principal;
 use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
 use canister_tests::framework::*;
-use ic_test_state_machine_client::CallError;
+use ic_cdk::api::management_canister::main::CanisterId;
 use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
 use internet_identity_interface::internet_identity::types::*;
 use regex::Regex;
 use std::time::Duration;
@@ -156,8 +159,97 @@ fn should_not_allow_wrong_captcha() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that an audio challenge contains a WAV clip and can be solved like an image challenge.
+#[test]
+fn should_register_with_audio_challenge() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    assert_eq!(challenge.audio_wav_base64, None);
+
+    let challenge = api::create_audio_challenge(&env, canister_id, principal_1())?;
+    let wav = BASE64
+        .decode(challenge.audio_wav_base64.expect("no audio captcha"))
+        .expect("invalid base64");
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+    )?;
+    assert!(matches!(result, RegisterResponse::Registered { .. }));
+    Ok(())
+}
+
+/// Tests that audio challenges are rate limited per caller and in total, independently of image
+/// challenges.
+#[test]
+fn should_rate_limit_audio_challenges() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            rate_limits: Some(RateLimits {
+                audio_challenge: Some(RateLimitConfig {
+                    time_per_token_ns: Duration::from_secs(60).as_nanos() as u64,
+                    max_tokens: 1,
+                }),
+                audio_challenge_total: Some(RateLimitConfig {
+                    time_per_token_ns: Duration::from_secs(60).as_nanos() as u64,
+                    max_tokens: 2,
+                }),
+                ..RateLimits::default()
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+
+    api::create_audio_challenge(&env, canister_id, principal_1())?;
+    expect_user_error_with_message(
+        api::create_audio_challenge(&env, canister_id, principal_1()),
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+    api::create_challenge(&env, canister_id)?;
+    api::create_challenge_as(&env, canister_id, principal_1())?;
+
+    // other callers are only limited by the total number of audio challenges
+    api::create_audio_challenge(&env, canister_id, principal_2())?;
+    expect_user_error_with_message(
+        api::create_audio_challenge(&env, canister_id, test_principal(3)),
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+
+    env.advance_time(Duration::from_secs(60));
+    api::create_audio_challenge(&env, canister_id, principal_1())?;
+    Ok(())
+}
+
+/// Tests that audio challenges cannot be requested anonymously, as anonymous callers would share a
+/// single per-caller rate limit.
+#[test]
+fn should_not_create_anonymous_audio_challenges() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    expect_user_error_with_message(
+        api::create_audio_challenge(&env, canister_id, Principal::anonymous()),
+        CanisterCalledTrap,
+        Regex::new("audio challenges cannot be requested anonymously").unwrap(),
+    );
+}
+
 /// Tests that there is a time limit for captchas.
-/// Currently only checked by create_challenge, see L2-766.
 #[test]
 fn should_not_allow_expired_captcha() -> Result<(), CallError> {
     let env = env();
@@ -166,8 +258,6 @@ fn should_not_allow_expired_captcha() -> Result<(), CallError> {
     let challenge = api::create_challenge(&env, canister_id)?;
     env.advance_time(Duration::from_secs(301)); // one second longer than captcha validity
 
-    // required because register does not check captcha expiry
-    api::create_challenge(&env, canister_id)?;
     let result = api::register(
         &env,
         canister_id,
@@ -183,22 +273,119 @@ fn should_not_allow_expired_captcha() -> Result<(), CallError> {
     Ok(())
 }
 
-/// Tests that there is a maximum number of captchas that can be created in a given timeframe.
+/// Tests that the oldest anonymous captchas are evicted when the maximum number of inflight
+/// anonymous captchas is reached.
 #[test]
-fn should_limit_captcha_creation() -> Result<(), CallError> {
+fn should_evict_oldest_captcha() -> Result<(), CallError> {
     let env = env();
     let canister_id = install_ii_canister(&env, II_WASM.clone());
 
-    for _ in 0..500 {
+    let oldest = api::create_challenge(&env, canister_id)?;
+    env.advance_time(Duration::from_secs(1));
+    for _ in 0..249 {
         api::create_challenge(&env, canister_id)?;
     }
-    let result = api::create_challenge(&env, canister_id);
+    api::create_challenge(&env, canister_id)?;
 
+    let metrics = get_metrics(&env, canister_id);
+    assert_metric(&metrics, "internet_identity_inflight_challenges", 250f64);
+    assert_metric(
+        &metrics,
+        "internet_identity_challenges{outcome=\"evicted\"}",
+        1f64,
+    );
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: oldest.challenge_key,
+        },
+    )?;
+    assert!(matches!(result, RegisterResponse::BadChallenge));
+    Ok(())
+}
+
+/// Tests that the oldest captchas of a caller are evicted when the caller has too many inflight
+/// captchas and that anonymous callers cannot evict the captchas of other callers.
+#[test]
+fn should_evict_oldest_captcha_per_partition() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let oldest = api::create_challenge_as(&env, canister_id, principal_1())?;
+    env.advance_time(Duration::from_secs(1));
+    let newest = api::create_challenge_as(&env, canister_id, principal_2())?;
+    for _ in 0..5 {
+        api::create_challenge_as(&env, canister_id, principal_1())?;
+    }
+    for _ in 0..251 {
+        api::create_challenge(&env, canister_id)?;
+    }
+
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: oldest.challenge_key,
+        },
+    )?;
+    assert!(matches!(result, RegisterResponse::BadChallenge));
+    let result = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: newest.challenge_key,
+        },
+    )?;
+    assert!(matches!(result, RegisterResponse::Registered { .. }));
+    Ok(())
+}
+
+/// Tests that the number of captchas created per caller can be rate limited and that anonymous
+/// callers (i.e. the frontend) are not rate limited.
+#[test]
+fn should_rate_limit_captcha_creation_per_caller() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            rate_limits: Some(RateLimits {
+                create_challenge: Some(RateLimitConfig {
+                    time_per_token_ns: Duration::from_secs(1).as_nanos() as u64,
+                    max_tokens: 2,
+                }),
+                ..RateLimits::default()
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+
+    for _ in 0..2 {
+        api::create_challenge_as(&env, canister_id, principal_1())?;
+    }
+    let result = api::create_challenge_as(&env, canister_id, principal_1());
     expect_user_error_with_message(
         result,
         CanisterCalledTrap,
-        Regex::new("too many inflight captchas").unwrap(),
+        Regex::new("rate limit reached, try again later").unwrap(),
     );
+    // anonymous callers cannot be told apart and are not rate limited
+    for _ in 0..3 {
+        api::create_challenge(&env, canister_id)?;
+    }
+
+    env.advance_time(Duration::from_secs(1));
+    api::create_challenge_as(&env, canister_id, principal_1())?;
     Ok(())
 }
 
@@ -314,3 +501,234 @@ fn should_report_max_rate_limit_tokens() -> Result<(), CallError> {
     );
     Ok(())
 }
+
+/// Tests that an anchor can be registered together with a recovery phrase.
+#[test]
+fn should_register_anchor_with_recovery_phrase() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let result = api::register_v2(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        Some(&recovery_device_data_1()),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+    )?;
+    let RegisterV2Response::Registered { user_number } = result else {
+        panic!("could not register: {result:?}");
+    };
+
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(
+        anchor_info.into_device_data(),
+        vec![device_data_1(), recovery_device_data_1()]
+    );
+    Ok(())
+}
+
+/// Tests that invalid devices are rejected by register_v2 without consuming the challenge.
+#[test]
+fn should_reject_invalid_recovery_device() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let register = |recovery_device: &DeviceData| {
+        api::register_v2(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            Some(recovery_device),
+            ChallengeAttempt {
+                chars: "a".to_string(),
+                key: challenge.challenge_key.clone(),
+            },
+        )
+    };
+
+    assert_eq!(
+        register(&device_data_2())?,
+        RegisterV2Response::InvalidRecoveryDevice
+    );
+    assert_eq!(
+        register(&DeviceData {
+            purpose: Purpose::Recovery,
+            ..device_data_1()
+        })?,
+        RegisterV2Response::DuplicateDevice {
+            device_key: device_data_1().pubkey
+        }
+    );
+    assert!(matches!(
+        register(&recovery_device_data_1())?,
+        RegisterV2Response::Registered { .. }
+    ));
+    Ok(())
+}
+
+/// Tests that a temporary key can add devices to its anchor until it expires.
+#[test]
+fn should_register_anchor_with_temp_key() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let temp_key = device_data_2().pubkey;
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let result = api::register_with_temp_key(
+        &env,
+        canister_id,
+        principal_2(),
+        temp_key,
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+    )?;
+    let RegisterWithTempKeyResponse::Registered {
+        user_number,
+        temp_key_expiration,
+    } = result
+    else {
+        panic!("could not register: {result:?}");
+    };
+    assert_eq!(
+        temp_key_expiration,
+        time(&env) + Duration::from_secs(600).as_nanos() as u64
+    );
+
+    let response = api::add_v2(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_1(),
+    )?;
+    assert_eq!(response, AddResponse::Ok);
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(anchor_info.into_device_data(), vec![device_data_1()]);
+
+    env.advance_time(Duration::from_secs(601));
+    env.tick();
+    expect_user_error_with_message(
+        api::add_v2(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        ),
+        CanisterCalledTrap,
+        Regex::new("could not be authenticated").unwrap(),
+    );
+    Ok(())
+}
+
+/// Tests that the temporary key can only be used to add devices.
+#[test]
+fn should_only_allow_temp_key_to_add_devices() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = register_with_temp_key(&env, canister_id)?;
+    let response = api::add_v2(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_1(),
+    )?;
+    assert_eq!(response, AddResponse::Ok);
+
+    expect_user_error_with_message(
+        api::remove_v2(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_1().pubkey,
+        ),
+        CanisterCalledTrap,
+        Regex::new("could not be authenticated").unwrap(),
+    );
+    Ok(())
+}
+
+/// Tests that anchors registered with a temporary key are released if no device is added before the
+/// key expires and that anchors are still allocated sequentially afterwards.
+#[test]
+fn should_release_anchor_of_unused_temp_key() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let user_number = register_with_temp_key(&env, canister_id)?;
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 1);
+    env.advance_time(Duration::from_secs(601));
+    env.tick();
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 0);
+    assert_eq!(flows::register_anchor(&env, canister_id), user_number);
+    assert_eq!(flows::register_anchor(&env, canister_id), user_number + 1);
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 2);
+    Ok(())
+}
+
+/// Tests that temporary keys survive upgrades.
+#[test]
+fn should_keep_temp_keys_across_upgrades() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let user_number = register_with_temp_key(&env, canister_id)?;
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    let response = api::add_v2(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_1(),
+    )?;
+    assert_eq!(response, AddResponse::Ok);
+    Ok(())
+}
+
+/// Tests that anchors of unused temporary keys are still released once the keys expire after an
+/// upgrade.
+#[test]
+fn should_release_anchor_of_unused_temp_key_after_upgrade() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    register_with_temp_key(&env, canister_id)?;
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 1);
+    env.advance_time(Duration::from_secs(601));
+    env.tick();
+    assert_eq!(api::stats(&env, canister_id)?.users_registered, 0);
+    Ok(())
+}
+
+/// Registers an anchor with the temporary key of `principal_2`.
+fn register_with_temp_key(
+    env: &StateMachine,
+    canister_id: CanisterId,
+) -> Result<AnchorNumber, CallError> {
+    let challenge = api::create_challenge(env, canister_id)?;
+    let result = api::register_with_temp_key(
+        env,
+        canister_id,
+        principal_2(),
+        device_data_2().pubkey,
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+    )?;
+    match result {
+        RegisterWithTempKeyResponse::Registered { user_number, .. } => Ok(user_number),
+        result => panic!("could not register: {result:?}"),
+    }
+}
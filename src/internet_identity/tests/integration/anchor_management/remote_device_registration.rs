@@ -7,6 +7,9 @@
 //! Additionally, there are the following bounds on the registration flow:
 //! 1. registration mode expires after 15 minutes
 //! 2. there is a limit of 3 attempts for step 3 in the above process
+//!
+//! Both bounds as well as the verification code format are configurable. Alternatively, device 2 can
+//! prove possession of its key by signing a nonce, in which case device 1 only confirms the device.
 
 use canister_tests::api::internet_identity as api;
 use canister_tests::flows;
@@ -15,6 +18,7 @@ use ic_test_state_machine_client::CallError;
 use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
 use internet_identity_interface::internet_identity::types::*;
 use regex::Regex;
+use serde_bytes::ByteBuf;
 use std::ops::Add;
 use std::time::{Duration, UNIX_EPOCH};
 
@@ -168,6 +172,105 @@ fn anchor_info_should_return_tentative_device() -> Result<(), CallError> {
     Ok(())
 }
 
+/// Tests that multiple devices can be added tentatively and that unwanted devices can be rejected.
+#[test]
+fn can_reject_one_of_multiple_tentative_devices() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+    let squatter = DeviceData {
+        pubkey: ByteBuf::from("squatter public key"),
+        alias: "squatter".to_string(),
+        ..device_data_2()
+    };
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        squatter.clone(),
+    )?;
+    let add_response = api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_2(),
+    )?;
+    let AddTentativeDeviceResponse::AddedTentatively { verification_code, .. } = add_response else {
+        panic!("failed to add tentative device: {add_response:?}");
+    };
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(
+        anchor_info.device_registration.unwrap().tentative_devices,
+        Some(vec![squatter.clone(), device_data_2()])
+    );
+
+    assert_eq!(
+        api::reject_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            squatter.pubkey.clone()
+        )?,
+        RejectTentativeDeviceResponse::Rejected
+    );
+    assert_eq!(
+        api::reject_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            squatter.pubkey
+        )?,
+        RejectTentativeDeviceResponse::NotFound
+    );
+    assert!(matches!(
+        api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            verification_code
+        )?,
+        VerifyTentativeDeviceResponse::Verified
+    ));
+    Ok(())
+}
+
+/// Tests that the number of tentative devices is limited.
+#[test]
+fn should_limit_tentative_devices() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    for i in 0..3 {
+        let device = DeviceData {
+            pubkey: ByteBuf::from(format!("tentative device {i}")),
+            ..device_data_2()
+        };
+        api::add_tentative_device(&env, canister_id, principal_2(), user_number, device)?;
+    }
+    let result = api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_2(),
+    )?;
+
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AnotherDeviceTentativelyAdded
+    ));
+    Ok(())
+}
+
 /// Tests that devices cannot be registered tentatively if the registration mode is not enabled.
 #[test]
 fn reject_tentative_device_if_not_in_registration_mode() -> Result<(), CallError> {
@@ -192,6 +295,56 @@ fn reject_tentative_device_if_not_in_registration_mode() -> Result<(), CallError
     Ok(())
 }
 
+/// Verifies that calls to add_tentative_device for anchors not in registration mode are not charged
+/// to the rate limit of the anchor.
+#[test]
+fn should_not_rate_limit_anchors_not_in_registration_mode() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            rate_limits: Some(RateLimits {
+                add_tentative_device: Some(RateLimitConfig {
+                    time_per_token_ns: Duration::from_secs(60).as_nanos() as u64,
+                    max_tokens: 1,
+                }),
+                ..RateLimits::default()
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    for _ in 0..3 {
+        let result = api::add_tentative_device(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            device_data_2(),
+        )?;
+        assert!(matches!(
+            result,
+            AddTentativeDeviceResponse::DeviceRegistrationModeOff
+        ));
+    }
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let result = api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_2(),
+    )?;
+    assert!(matches!(
+        result,
+        AddTentativeDeviceResponse::AddedTentatively { .. }
+    ));
+    Ok(())
+}
+
 /// Tests device registration mode expiration.
 #[test]
 fn reject_tentative_device_if_registration_mode_is_expired() -> Result<(), CallError> {
@@ -284,3 +437,156 @@ fn reject_verification_with_wrong_code() -> Result<(), CallError> {
     ));
     Ok(())
 }
+
+/// Tests that the verification code format and attempt limit can be configured.
+#[test]
+fn should_use_configured_verification_codes() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_device_registration(DeviceRegistrationConfig {
+            possession_proof: false,
+            ..long_code_config()
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let add_response = api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_2(),
+    )?;
+    let AddTentativeDeviceResponse::AddedTentatively { verification_code, .. } = add_response else {
+        panic!("failed to add tentative device: {add_response:?}");
+    };
+    assert_eq!(verification_code.len(), 10);
+    assert!(verification_code.chars().all(|c| c.is_ascii_uppercase()));
+
+    assert!(matches!(
+        api::verify_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            "invalid code".to_string()
+        )?,
+        VerifyTentativeDeviceResponse::WrongCode { retries_left: 4 }
+    ));
+    Ok(())
+}
+
+/// Tests that a remote device can be added by proving possession of its key.
+#[test]
+fn can_register_remote_device_with_possession_proof() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        arg_with_device_registration(long_code_config()),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), user_number)?;
+    let add_response = api::add_tentative_device(
+        &env,
+        canister_id,
+        principal_2(),
+        user_number,
+        device_data_2(),
+    )?;
+    let AddTentativeDeviceResponse::PossessionChallenge { nonce, .. } = add_response else {
+        panic!("failed to add tentative device: {add_response:?}");
+    };
+    assert!(matches!(
+        api::confirm_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey
+        )?,
+        VerifyTentativeDeviceResponse::PossessionNotProven
+    ));
+
+    // the proof must be signed by the tentative device
+    assert_eq!(
+        api::prove_tentative_device_possession(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            nonce.clone()
+        )?,
+        ProveDevicePossessionResponse::NotSignedByTentativeDevice
+    );
+    assert_eq!(
+        api::prove_tentative_device_possession(
+            &env,
+            canister_id,
+            principal_2(),
+            user_number,
+            nonce
+        )?,
+        ProveDevicePossessionResponse::Proven
+    );
+
+    assert!(matches!(
+        api::confirm_tentative_device(
+            &env,
+            canister_id,
+            principal_1(),
+            user_number,
+            device_data_2().pubkey
+        )?,
+        VerifyTentativeDeviceResponse::Verified
+    ));
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(anchor_info.devices.len(), 2);
+    Ok(())
+}
+
+/// Tests that verification codes that are too easy to guess are rejected.
+#[test]
+fn should_reject_weak_verification_code_config() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        arg_with_device_registration(DeviceRegistrationConfig {
+            verification_code_length: 4,
+            verification_code_alphabet: "0123456789".to_string(),
+            ..long_code_config()
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("verification codes must have at least 1000000 possible values").unwrap(),
+    );
+}
+
+fn long_code_config() -> DeviceRegistrationConfig {
+    DeviceRegistrationConfig {
+        verification_code_length: 10,
+        verification_code_alphabet: "ABCDEFGHIJKLMNOPQRSTUVWXYZ".to_string(),
+        max_verification_attempts: 5,
+        max_tentative_devices: 2,
+        registration_mode_duration_ns: 600_000_000_000,
+        possession_proof: true,
+    }
+}
+
+fn arg_with_device_registration(config: DeviceRegistrationConfig) -> Option<InternetIdentityInit> {
+    Some(InternetIdentityInit {
+        device_registration: Some(config),
+        ..InternetIdentityInit::default()
+    })
+}
@@ -0,0 +1,116 @@
+//! Tests for the per-anchor feed of security events.
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::*;
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::*;
+use regex::Regex;
+
+/// Verifies that device changes, registration mode entries and recovery device usage are reported
+/// to all devices of the anchor (newest first) and survive upgrades.
+#[test]
+fn should_report_security_events() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, canister_id);
+    assert!(api::get_security_events(&env, canister_id, principal_1(), anchor_number)?.is_empty());
+
+    api::add(
+        &env,
+        canister_id,
+        principal_1(),
+        anchor_number,
+        recovery_device_data_1(),
+    )?;
+    api::enter_device_registration_mode(&env, canister_id, principal_recovery_1(), anchor_number)?;
+    api::remove(
+        &env,
+        canister_id,
+        principal_1(),
+        anchor_number,
+        recovery_device_data_1().pubkey,
+    )?;
+    upgrade_ii_canister(&env, canister_id, II_WASM.clone());
+
+    let events = api::get_security_events(&env, canister_id, principal_1(), anchor_number)?;
+    let events: Vec<_> = events
+        .into_iter()
+        .map(|event| (event.kind, event.purpose, event.key_type))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (
+                SecurityEventKind::DeviceRemoved,
+                Purpose::Recovery,
+                KeyType::SeedPhrase
+            ),
+            (
+                SecurityEventKind::RegistrationModeEntered,
+                Purpose::Recovery,
+                KeyType::SeedPhrase
+            ),
+            (
+                SecurityEventKind::RecoveryUsed,
+                Purpose::Recovery,
+                KeyType::SeedPhrase
+            ),
+            (
+                SecurityEventKind::DeviceAdded,
+                Purpose::Recovery,
+                KeyType::SeedPhrase
+            ),
+        ]
+    );
+    Ok(())
+}
+
+/// Verifies that the security events of an anchor are not revealed to other anchors.
+#[test]
+fn should_not_report_security_events_to_other_anchors() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, canister_id);
+    let other_anchor_number =
+        flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), anchor_number)?;
+
+    expect_user_error_with_message(
+        api::get_security_events(&env, canister_id, principal_2(), anchor_number),
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ could not be authenticated.").unwrap(),
+    );
+    assert!(
+        api::get_security_events(&env, canister_id, principal_2(), other_anchor_number)?.is_empty()
+    );
+    Ok(())
+}
+
+/// Verifies that only the most recent events of an anchor are kept and that the events of an anchor
+/// are not dropped in favour of the events of other anchors.
+#[test]
+fn should_keep_bounded_security_events_per_anchor() -> Result<(), CallError> {
+    const MAX_SECURITY_EVENTS: usize = 8;
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+    let anchor_number = flows::register_anchor(&env, canister_id);
+    let other_anchor_number =
+        flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+    api::enter_device_registration_mode(&env, canister_id, principal_1(), anchor_number)?;
+
+    for _ in 0..MAX_SECURITY_EVENTS + 2 {
+        api::enter_device_registration_mode(&env, canister_id, principal_2(), other_anchor_number)?;
+    }
+
+    assert_eq!(
+        api::get_security_events(&env, canister_id, principal_2(), other_anchor_number)?.len(),
+        MAX_SECURITY_EVENTS
+    );
+    assert_eq!(
+        api::get_security_events(&env, canister_id, principal_1(), anchor_number)?.len(),
+        1
+    );
+    Ok(())
+}
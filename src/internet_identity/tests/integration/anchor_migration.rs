@@ -0,0 +1,158 @@
+//! Tests for the export and import of anchors.
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, env, expect_user_error_with_message, install_ii_canister_with_arg, principal_1,
+    principal_2, II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::InternetIdentityInit;
+use regex::Regex;
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+
+const ANCHOR_RANGE: (u64, u64) = (10_000, 20_000);
+
+/// Verifies that anchors can be exported from one canister and imported into another.
+#[test]
+fn should_migrate_anchors() -> Result<(), CallError> {
+    let env = env();
+    let source = install_ii_with_controller(&env, principal_2());
+    let target = install_ii_with_controller(&env, principal_2());
+
+    let anchors: Vec<_> = (0..3)
+        .map(|_| {
+            env.advance_time(Duration::from_secs(1));
+            flows::register_anchor(&env, source)
+        })
+        .collect();
+    // record some activity, so that the last usage timestamp differs from the registration
+    env.advance_time(Duration::from_secs(60));
+    api::get_anchor_info(&env, source, principal_1(), anchors[1])?;
+
+    let export = api::export_anchors(&env, source, principal_2(), None, None)?;
+    assert_eq!(export.anchors.len(), 3);
+    assert_eq!(export.next_cursor, None);
+    api::import_anchors(&env, target, principal_2(), export.anchors.clone())?;
+
+    let imported = api::export_anchors(&env, target, principal_2(), None, None)?;
+    assert_eq!(imported, export);
+    assert_eq!(api::stats(&env, target)?.users_registered, 3);
+    assert_eq!(
+        api::lookup(&env, target, anchors[1])?,
+        api::lookup(&env, source, anchors[1])?
+    );
+    Ok(())
+}
+
+/// Verifies that the export of a range can be resumed using the returned cursor.
+#[test]
+fn should_export_range_with_cursor() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_controller(&env, principal_2());
+    for _ in 0..5 {
+        flows::register_anchor(&env, canister_id);
+    }
+
+    let range = Some((ANCHOR_RANGE.0 + 1, ANCHOR_RANGE.0 + 4));
+    let batch = api::export_anchors(&env, canister_id, principal_2(), range, None)?;
+    let anchor_numbers: Vec<_> = batch.anchors.iter().map(|a| a.anchor_number).collect();
+    assert_eq!(anchor_numbers, vec![10_001, 10_002, 10_003]);
+    assert_eq!(batch.next_cursor, None);
+
+    let batch = api::export_anchors(&env, canister_id, principal_2(), range, Some(10_003))?;
+    let anchor_numbers: Vec<_> = batch.anchors.iter().map(|a| a.anchor_number).collect();
+    assert_eq!(anchor_numbers, vec![10_003]);
+    Ok(())
+}
+
+/// Verifies that anchors with an invalid checksum are rejected.
+#[test]
+fn should_reject_checksum_mismatch() -> Result<(), CallError> {
+    let env = env();
+    let source = install_ii_with_controller(&env, principal_2());
+    let target = install_ii_with_controller(&env, principal_2());
+    flows::register_anchor(&env, source);
+
+    let mut anchors = api::export_anchors(&env, source, principal_2(), None, None)?.anchors;
+    anchors[0].sha256 = ByteBuf::from(vec![0; 32]);
+    let result = api::import_anchors(&env, target, principal_2(), anchors);
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("checksum mismatch for anchor 10000").unwrap(),
+    );
+    assert_eq!(api::stats(&env, target)?.users_registered, 0);
+    Ok(())
+}
+
+/// Verifies that anchors cannot be imported out of order.
+#[test]
+fn should_reject_gaps_in_imported_anchors() -> Result<(), CallError> {
+    let env = env();
+    let source = install_ii_with_controller(&env, principal_2());
+    let target = install_ii_with_controller(&env, principal_2());
+    flows::register_anchor(&env, source);
+    flows::register_anchor(&env, source);
+
+    let range = Some((ANCHOR_RANGE.0 + 1, ANCHOR_RANGE.1));
+    let anchors = api::export_anchors(&env, source, principal_2(), range, None)?.anchors;
+    let result = api::import_anchors(&env, target, principal_2(), anchors);
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("anchor 10001 cannot be imported before anchor 10000").unwrap(),
+    );
+    Ok(())
+}
+
+/// Verifies that only the configured controllers can export and import anchors.
+#[test]
+fn should_only_allow_controllers() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_controller(&env, principal_2());
+    let user_number = flows::register_anchor(&env, canister_id);
+    assert_eq!(
+        api::lookup(&env, canister_id, user_number)?[0].pubkey,
+        device_data_1().pubkey
+    );
+
+    let result = api::export_anchors(&env, canister_id, principal_1(), None, None);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ is not a controller.").unwrap(),
+    );
+    let result = api::import_anchors(&env, canister_id, principal_1(), vec![]);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ is not a controller.").unwrap(),
+    );
+    // the canister controllers must also be configured in the install arg
+    let result = api::import_anchors(&env, canister_id, Principal::anonymous(), vec![]);
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ is not a controller.").unwrap(),
+    );
+    Ok(())
+}
+
+fn install_ii_with_controller(env: &StateMachine, controller: Principal) -> CanisterId {
+    install_ii_canister_with_arg(
+        env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            assigned_user_number_range: Some(ANCHOR_RANGE),
+            controllers: Some(vec![controller]),
+            ..InternetIdentityInit::default()
+        }),
+    )
+}
@@ -0,0 +1,121 @@
+//! Tests for the sweep finding and repairing anchors that violate the anchor invariants.
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister, principal_1, principal_2,
+    restore_compressed_stable_memory, upgrade_ii_canister_with_arg, EMPTY_WASM, II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    AnchorRepairFinding, AnchorRepairPolicy, InternetIdentityInit, KeyType, ViolationPolicy,
+};
+use regex::Regex;
+
+/// Verifies that the report policy lists non-conforming anchors without modifying them.
+#[test]
+fn should_report_non_conforming_anchors() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_multiple_recovery_phrases(&env);
+
+    api::start_anchor_repair(
+        &env,
+        canister_id,
+        principal_2(),
+        policy(ViolationPolicy::Report),
+    )?;
+    env.tick();
+
+    let status = api::anchor_repair_status(&env, canister_id, principal_2())?
+        .expect("anchor repair status missing");
+    assert!(status.completed.is_some());
+    assert_eq!(status.anchors_with_violations, 1);
+    assert_eq!(
+        status.findings,
+        vec![AnchorRepairFinding {
+            anchor_number: 10_000,
+            violations: vec![
+                "There is already a recovery phrase and only one is allowed.".to_string()
+            ],
+            repaired: false,
+        }]
+    );
+    assert_eq!(recovery_phrase_count(&env, canister_id)?, 2);
+    Ok(())
+}
+
+/// Verifies that surplus recovery phrases are only reported, even if repairs are requested.
+#[test]
+fn should_not_remove_surplus_recovery_phrases() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_multiple_recovery_phrases(&env);
+
+    api::start_anchor_repair(
+        &env,
+        canister_id,
+        principal_2(),
+        policy(ViolationPolicy::Repair),
+    )?;
+    env.tick();
+
+    let status = api::anchor_repair_status(&env, canister_id, principal_2())?
+        .expect("anchor repair status missing");
+    assert_eq!(status.anchors_with_violations, 1);
+    assert!(!status.findings[0].repaired);
+    assert_eq!(recovery_phrase_count(&env, canister_id)?, 2);
+    Ok(())
+}
+
+/// Verifies that only the configured controllers can start the sweep.
+#[test]
+fn should_only_allow_controllers_to_start_anchor_repair() {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let result = api::start_anchor_repair(
+        &env,
+        canister_id,
+        principal_1(),
+        policy(ViolationPolicy::Repair),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ is not a controller.").unwrap(),
+    );
+}
+
+fn install_ii_with_multiple_recovery_phrases(env: &StateMachine) -> CanisterId {
+    let canister_id = install_ii_canister(env, EMPTY_WASM.clone());
+    restore_compressed_stable_memory(
+        env,
+        canister_id,
+        "stable_memory/multiple-recovery-phrases-v6.bin.gz",
+    );
+    upgrade_ii_canister_with_arg(
+        env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            controllers: Some(vec![principal_2()]),
+            ..InternetIdentityInit::default()
+        }),
+    )
+    .unwrap();
+    canister_id
+}
+
+fn policy(invalid_device_protection: ViolationPolicy) -> AnchorRepairPolicy {
+    AnchorRepairPolicy {
+        invalid_device_protection,
+    }
+}
+
+fn recovery_phrase_count(env: &StateMachine, canister_id: CanisterId) -> Result<usize, CallError> {
+    Ok(api::lookup(env, canister_id, 10_000)?
+        .iter()
+        .filter(|device| device.key_type == KeyType::SeedPhrase)
+        .count())
+}
@@ -46,10 +46,21 @@ mod deployment_tests {
                     entries_buffer_limit: 0,
                     polling_interval_ns: 0,
                     entries_fetch_limit: 0,
+                    archive_integration: None,
+                    buffer_overflow_policy: None,
+                    retention_period_ns: None,
                 }),
                 canister_creation_cycles_cost: Some(100_000_000_000), // current cost in application subnets
                 register_rate_limit: None,
                 max_num_latest_delegation_origins: None,
+                controllers: None,
+                shards: None,
+                anchor_limits: None,
+                dormancy_policy: None,
+                device_registration: None,
+                rate_limits: None,
+                anchor_allocation: None,
+                adaptive_captcha: None,
             }),
         );
         env.add_cycles(ii_canister, 150_000_000_000);
@@ -185,10 +196,21 @@ mod deployment_tests {
                     entries_buffer_limit: 10,
                     polling_interval_ns: 5_000,
                     entries_fetch_limit: 10,
+                    archive_integration: None,
+                    buffer_overflow_policy: None,
+                    retention_period_ns: None,
                 }),
                 canister_creation_cycles_cost: None, // current cost in application subnets
                 register_rate_limit: None,
                 max_num_latest_delegation_origins: None,
+                controllers: None,
+                shards: None,
+                anchor_limits: None,
+                dormancy_policy: None,
+                device_registration: None,
+                rate_limits: None,
+                anchor_allocation: None,
+                adaptive_captcha: None,
             }),
         )
         .unwrap();
@@ -628,3 +650,189 @@ mod pull_entries_tests {
         );
     }
 }
+
+/// Test the functionality of pushing entries from II to the archive.
+#[cfg(test)]
+mod push_entries_tests {
+    use super::*;
+
+    fn arg_with_push_integration() -> Option<InternetIdentityInit> {
+        let mut arg = arg_with_wasm_hash(ARCHIVE_WASM.clone()).unwrap();
+        arg.archive_config.as_mut().unwrap().archive_integration = Some(ArchiveIntegration::Push);
+        Some(arg)
+    }
+
+    /// Test to verify that II pushes the anchor operations to the archive.
+    #[test]
+    fn should_push_anchor_operations() -> Result<(), CallError> {
+        let env = env();
+        let ii_canister =
+            install_ii_canister_with_arg(&env, II_WASM.clone(), arg_with_push_integration());
+        let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+
+        let anchor = flows::register_anchor(&env, ii_canister);
+        ii_api::add(&env, ii_canister, principal_1(), anchor, device_data_2())?;
+
+        // II pushes entries once per second
+        env.advance_time(Duration::from_secs(2));
+        // execute the timer
+        env.tick();
+        env.tick();
+
+        let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
+        assert_eq!(entries.entries.len(), 2);
+        assert_eq!(
+            entries.entries.get(1).unwrap().as_ref().unwrap().operation,
+            Operation::AddDevice {
+                device: DeviceDataWithoutAlias::from(device_data_2()),
+            }
+        );
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_buffered_archive_entries",
+            0f64,
+        );
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_archive_last_successful_push_timestamp_seconds",
+            env.time()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64(),
+        );
+
+        // the archive must not poll II when entries are pushed
+        let status = archive_api::status(&env, archive_canister)?;
+        assert_eq!(
+            status.init.archive_integration,
+            Some(ArchiveIntegration::Push)
+        );
+        assert_eq!(status.call_info.last_successful_fetch, None);
+        Ok(())
+    }
+
+    /// Test to verify that II backs off exponentially if the archive is unavailable and recovers afterwards.
+    #[test]
+    fn should_back_off_on_push_failures() -> Result<(), CallError> {
+        let env = env();
+        let ii_canister =
+            install_ii_canister_with_arg(&env, II_WASM.clone(), arg_with_push_integration());
+        let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+
+        flows::register_anchor(&env, ii_canister);
+        // stop the archive canister to provoke failures
+        env.stop_canister(archive_canister)?;
+
+        env.advance_time(Duration::from_secs(1));
+        env.tick();
+        env.tick();
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_archive_push_consecutive_failures",
+            1f64,
+        );
+
+        // the next attempt is only made after 2 seconds
+        env.advance_time(Duration::from_secs(1));
+        env.tick();
+        env.tick();
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_archive_push_consecutive_failures",
+            1f64,
+        );
+        env.advance_time(Duration::from_secs(1));
+        env.tick();
+        env.tick();
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_archive_push_consecutive_failures",
+            2f64,
+        );
+
+        // start the archive again to resolve the issue
+        env.start_canister(archive_canister)?;
+        env.advance_time(Duration::from_secs(5));
+        env.tick();
+        env.tick();
+
+        let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
+        assert_eq!(entries.entries.len(), 1);
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_archive_push_consecutive_failures",
+            0f64,
+        );
+        Ok(())
+    }
+
+    /// Test to verify that II spills entries to stable memory instead of trapping if configured to do so.
+    #[test]
+    fn should_spill_entries_to_stable_memory() -> Result<(), CallError> {
+        let env = env();
+        let mut arg = arg_with_wasm_hash(ARCHIVE_WASM.clone()).unwrap();
+        let config = arg.archive_config.as_mut().unwrap();
+        config.entries_buffer_limit = 2;
+        config.buffer_overflow_policy = Some(BufferOverflowPolicy::SpillToStableMemory);
+        let ii_canister = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+        let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+
+        // stop the archive canister so that entries accumulate
+        env.stop_canister(archive_canister)?;
+        for _ in 0..5 {
+            flows::register_anchor(&env, ii_canister);
+        }
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_buffered_archive_entries",
+            2f64,
+        );
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_spilled_archive_entries",
+            3f64,
+        );
+
+        // spilled entries survive upgrades
+        upgrade_ii_canister(&env, ii_canister, II_WASM.clone());
+        env.start_canister(archive_canister)?;
+
+        // each fetch moves spilled entries back into the buffer
+        for _ in 0..3 {
+            env.advance_time(Duration::from_secs(2));
+            env.tick();
+            env.tick();
+        }
+
+        let entries = archive_api::get_entries(&env, archive_canister, None, None)?;
+        assert_eq!(entries.entries.len(), 5);
+        for (i, entry) in entries.entries.iter().enumerate() {
+            assert_eq!(entry.as_ref().unwrap().sequence_number, i as u64);
+        }
+        assert_metric(
+            &get_metrics(&env, ii_canister),
+            "internet_identity_spilled_archive_entries",
+            0f64,
+        );
+        Ok(())
+    }
+
+    /// Test to verify that II still traps on a full buffer by default.
+    #[test]
+    fn should_trap_on_full_buffer_by_default() {
+        let env = env();
+        let mut arg = arg_with_wasm_hash(ARCHIVE_WASM.clone()).unwrap();
+        arg.archive_config.as_mut().unwrap().entries_buffer_limit = 1;
+        let ii_canister = install_ii_canister_with_arg(&env, II_WASM.clone(), Some(arg));
+        let archive_canister = deploy_archive_via_ii(&env, ii_canister);
+        env.stop_canister(archive_canister).unwrap();
+
+        let anchor = flows::register_anchor(&env, ii_canister);
+        let result = ii_api::add(&env, ii_canister, principal_1(), anchor, device_data_2());
+        expect_user_error_with_message(
+            result,
+            CanisterCalledTrap,
+            Regex::new("cannot archive operation, archive entries buffer limit reached").unwrap(),
+        );
+    }
+}
@@ -6,7 +6,9 @@ use canister_tests::flows;
 use canister_tests::framework::*;
 use ic_test_state_machine_client::CallError;
 use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
-use internet_identity_interface::internet_identity::types::GetDelegationResponse;
+use internet_identity_interface::internet_identity::types::{
+    GetDelegationResponse, InternetIdentityInit, RateLimitConfig, RateLimits,
+};
 use regex::Regex;
 use serde_bytes::ByteBuf;
 use std::ops::Add;
@@ -423,6 +425,56 @@ fn can_not_prepare_delegation_for_different_user() {
     );
 }
 
+/// Verifies that prepare_delegation is rate limited per anchor.
+#[test]
+fn should_rate_limit_prepare_delegation_per_anchor() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            rate_limits: Some(RateLimits {
+                prepare_delegation: Some(RateLimitConfig {
+                    time_per_token_ns: Duration::from_secs(1).as_nanos() as u64,
+                    max_tokens: 2,
+                }),
+                ..RateLimits::default()
+            }),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    let user_number_1 =
+        flows::register_anchor_with(&env, canister_id, principal_1(), &device_data_1());
+    let user_number_2 =
+        flows::register_anchor_with(&env, canister_id, principal_2(), &device_data_2());
+    let prepare = |sender, user_number| {
+        api::prepare_delegation(
+            &env,
+            canister_id,
+            sender,
+            user_number,
+            "https://some-dapp.com".to_string(),
+            ByteBuf::from("session key"),
+            None,
+        )
+    };
+
+    for _ in 0..2 {
+        prepare(principal_1(), user_number_1)?;
+    }
+    expect_user_error_with_message(
+        prepare(principal_1(), user_number_1),
+        CanisterCalledTrap,
+        Regex::new("rate limit reached, try again later").unwrap(),
+    );
+    // other anchors are not affected
+    prepare(principal_2(), user_number_2)?;
+
+    env.advance_time(Duration::from_secs(1));
+    prepare(principal_1(), user_number_1)?;
+    Ok(())
+}
+
 /// Verifies that get_delegation can only be called by the matching user.
 #[test]
 fn can_not_get_delegation_for_different_user() -> Result<(), CallError> {
@@ -298,6 +298,71 @@ fn metrics_inflight_challenges() -> Result<(), CallError> {
     let (challenge_count, _) = parse_metric(&metrics, "internet_identity_inflight_challenges");
     assert_eq!(challenge_count, 1f64); // 1 pruned due to expiry, but also one created
 
+    for (outcome, count) in [
+        ("created", 3f64),
+        ("solved", 1f64),
+        ("failed", 0f64),
+        ("expired", 1f64),
+        ("evicted", 0f64),
+    ] {
+        assert_metric(
+            &metrics,
+            &format!("internet_identity_challenges{{outcome=\"{outcome}\"}}"),
+            count,
+        );
+    }
+    Ok(())
+}
+
+/// Verifies that failed challenge attempts are counted by reason.
+#[test]
+fn metrics_challenge_failures() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister(&env, II_WASM.clone());
+
+    let wrong_solution = api::create_challenge(&env, canister_id)?;
+    let expired = api::create_challenge(&env, canister_id)?;
+    for (chars, key) in [
+        ("wrong", wrong_solution.challenge_key),
+        ("a", "unknown key".to_string()),
+    ] {
+        api::register(
+            &env,
+            canister_id,
+            principal_1(),
+            &device_data_1(),
+            ChallengeAttempt {
+                chars: chars.to_string(),
+                key,
+            },
+        )?;
+    }
+    // one second longer than captcha validity
+    env.advance_time(Duration::from_secs(301));
+    api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: expired.challenge_key,
+        },
+    )?;
+
+    let metrics = get_metrics(&env, canister_id);
+    assert_metric(
+        &metrics,
+        "internet_identity_challenges{outcome=\"failed\"}",
+        3f64,
+    );
+    for reason in ["bad_solution", "unknown_key", "expired"] {
+        assert_metric(
+            &metrics,
+            &format!("internet_identity_challenge_failures{{reason=\"{reason}\"}}"),
+            1f64,
+        );
+    }
     Ok(())
 }
 
@@ -0,0 +1,116 @@
+//! Tests for the inactivity scan and the dormant anchor flag.
+
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    env, expect_user_error_with_message, install_ii_canister_with_arg, principal_1, principal_2,
+    II_WASM,
+};
+use ic_cdk::api::management_canister::main::CanisterId;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use ic_test_state_machine_client::{CallError, StateMachine};
+use internet_identity_interface::internet_identity::types::{
+    DormancyPolicy, InactivityBucket, InternetIdentityInit,
+};
+use regex::Regex;
+use std::time::Duration;
+
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+const YEAR_NS: u64 = 365 * DAY_SECONDS * 1_000_000_000;
+
+/// Verifies that anchors are counted by inactivity.
+#[test]
+fn should_report_anchors_by_inactivity() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_dormancy_policy(&env, None);
+    flows::register_anchor(&env, canister_id);
+    env.advance_time(Duration::from_secs(100 * DAY_SECONDS));
+    flows::register_anchor(&env, canister_id);
+
+    api::start_inactivity_scan(&env, canister_id, principal_2())?;
+    env.tick();
+
+    let report = api::inactivity_report(&env, canister_id, principal_2())?
+        .expect("inactivity report missing");
+    assert!(report.completed.is_some());
+    assert_eq!(report.next_anchor_number, 10_002);
+    assert_eq!(
+        report.buckets,
+        vec![
+            bucket(0, 1),
+            bucket(30, 0),
+            bucket(90, 1),
+            bucket(365, 0),
+            bucket(730, 0)
+        ]
+    );
+    assert_eq!(report.unknown_activity, 0);
+    assert_eq!(report.flagged_dormant, 0);
+    Ok(())
+}
+
+/// Verifies that dormant anchors are flagged and that the flag is cleared when acknowledged.
+#[test]
+fn should_flag_dormant_anchors() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_with_dormancy_policy(
+        &env,
+        Some(DormancyPolicy {
+            dormant_after_ns: 2 * YEAR_NS,
+        }),
+    );
+    let user_number = flows::register_anchor(&env, canister_id);
+    env.advance_time(Duration::from_secs(3 * 365 * DAY_SECONDS));
+
+    api::start_inactivity_scan(&env, canister_id, principal_2())?;
+    env.tick();
+
+    let report = api::inactivity_report(&env, canister_id, principal_2())?
+        .expect("inactivity report missing");
+    assert_eq!(report.flagged_dormant, 1);
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(anchor_info.flagged_dormant, Some(report.started));
+
+    api::acknowledge_dormancy(&env, canister_id, principal_1(), user_number)?;
+
+    let anchor_info = api::get_anchor_info(&env, canister_id, principal_1(), user_number)?;
+    assert_eq!(anchor_info.flagged_dormant, None);
+    Ok(())
+}
+
+/// Verifies that only the configured controllers can start the scan.
+#[test]
+fn should_only_allow_controllers_to_start_inactivity_scan() {
+    let env = env();
+    let canister_id = install_ii_with_dormancy_policy(&env, None);
+
+    let result = api::start_inactivity_scan(&env, canister_id, principal_1());
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("[a-z0-9-]+ is not a controller.").unwrap(),
+    );
+}
+
+fn install_ii_with_dormancy_policy(
+    env: &StateMachine,
+    dormancy_policy: Option<DormancyPolicy>,
+) -> CanisterId {
+    install_ii_canister_with_arg(
+        env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            controllers: Some(vec![principal_2()]),
+            dormancy_policy,
+            ..InternetIdentityInit::default()
+        }),
+    )
+}
+
+fn bucket(inactive_for_days: u64, anchors: u64) -> InactivityBucket {
+    InactivityBucket {
+        inactive_for_days,
+        anchors,
+    }
+}
@@ -111,6 +111,14 @@ fn install_ii_with_latest_origin_limit(env: &StateMachine, limit: u64) -> Canist
         II_WASM.clone(),
         Some(InternetIdentityInit {
             max_num_latest_delegation_origins: Some(limit),
+            controllers: None,
+            shards: None,
+            anchor_limits: None,
+            dormancy_policy: None,
+            device_registration: None,
+            rate_limits: None,
+            anchor_allocation: None,
+            adaptive_captcha: None,
             ..Default::default()
         }),
     )
@@ -5,11 +5,16 @@
 //! See https://matklad.github.io/2021/02/27/delete-cargo-integration-tests.html#Implications for more details.
 
 mod active_anchor_stats;
+mod anchor_allocation;
 mod anchor_management;
+mod anchor_migration;
+mod anchor_repair;
 mod archive_integration;
 mod delegation;
 mod http;
+mod inactivity;
 mod latest_delegation_origins;
 mod rollback;
+mod shards;
 mod stable_memory;
 mod upgrade;
@@ -0,0 +1,154 @@
+//! Tests for routing anchors to sibling II canisters.
+
+use candid::Principal;
+use canister_tests::api::internet_identity as api;
+use canister_tests::flows;
+use canister_tests::framework::{
+    device_data_1, env, expect_user_error_with_message, install_ii_canister_with_arg, principal_1,
+    upgrade_ii_canister_with_arg, II_WASM,
+};
+use ic_test_state_machine_client::CallError;
+use ic_test_state_machine_client::ErrorCode::CanisterCalledTrap;
+use internet_identity_interface::internet_identity::types::{
+    ChallengeAttempt, GetAnchorCredentialsResponse, InternetIdentityInit, LookupResponse,
+    RegisterResponse, RouteResponse, ShardInfo,
+};
+use regex::Regex;
+
+const OWN_RANGE: (u64, u64) = (10_000, 20_000);
+const SHARD_RANGE: (u64, u64) = (20_000, 30_000);
+
+/// Verifies that anchor numbers are routed to the canister serving the respective range.
+#[test]
+fn should_route_anchor_numbers() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), arg_with_shards());
+
+    assert_eq!(api::route(&env, canister_id, 10_000)?, RouteResponse::Local);
+    assert_eq!(
+        api::route(&env, canister_id, 20_000)?,
+        RouteResponse::Shard(shard_canister())
+    );
+    assert_eq!(
+        api::route(&env, canister_id, 30_000)?,
+        RouteResponse::Unknown
+    );
+    assert_eq!(
+        api::stats(&env, canister_id)?.shards,
+        Some(vec![shard_info()])
+    );
+    Ok(())
+}
+
+/// Verifies that lookups of anchors served by another shard are redirected by the v2 endpoints
+/// while the legacy endpoints keep returning empty results.
+#[test]
+fn should_redirect_lookups_to_shard() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), arg_with_shards());
+    let user_number = flows::register_anchor(&env, canister_id);
+    assert_eq!(api::lookup(&env, canister_id, user_number)?.len(), 1);
+
+    assert!(api::lookup(&env, canister_id, 25_000)?.is_empty());
+    let credentials = api::get_anchor_credentials(&env, canister_id, 25_000)?;
+    assert!(credentials.credentials.is_empty());
+    assert!(credentials.recovery_credentials.is_empty());
+    assert!(credentials.recovery_phrases.is_empty());
+
+    assert_eq!(
+        api::lookup_v2(&env, canister_id, 25_000)?,
+        LookupResponse::Redirect(shard_canister())
+    );
+    assert!(matches!(
+        api::lookup_v2(&env, canister_id, user_number)?,
+        LookupResponse::Ok(devices) if devices.len() == 1
+    ));
+    assert!(matches!(
+        api::get_anchor_credentials_v2(&env, canister_id, 25_000)?,
+        GetAnchorCredentialsResponse::Redirect(canister_id) if canister_id == shard_canister()
+    ));
+    assert!(matches!(
+        api::get_anchor_credentials_v2(&env, canister_id, user_number)?,
+        GetAnchorCredentialsResponse::Ok(credentials) if credentials.credentials.len() == 1
+    ));
+    Ok(())
+}
+
+/// Verifies that registrations are redirected to the next shard once the canister is full.
+#[test]
+fn should_redirect_registrations_to_next_shard() -> Result<(), CallError> {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(
+        &env,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            assigned_user_number_range: Some((10_000, 10_001)),
+            shards: Some(vec![shard_info()]),
+            ..InternetIdentityInit::default()
+        }),
+    );
+    flows::register_anchor(&env, canister_id);
+
+    let challenge = api::create_challenge(&env, canister_id)?;
+    let response = api::register(
+        &env,
+        canister_id,
+        principal_1(),
+        &device_data_1(),
+        ChallengeAttempt {
+            chars: "a".to_string(),
+            key: challenge.challenge_key,
+        },
+    )?;
+    assert!(matches!(
+        response,
+        RegisterResponse::Redirect(canister_id) if canister_id == shard_canister()
+    ));
+    Ok(())
+}
+
+/// Verifies that shard ranges overlapping with the own range are rejected.
+#[test]
+fn should_reject_overlapping_shard_ranges() {
+    let env = env();
+    let canister_id = install_ii_canister_with_arg(&env, II_WASM.clone(), arg_with_shards());
+
+    let result = upgrade_ii_canister_with_arg(
+        &env,
+        canister_id,
+        II_WASM.clone(),
+        Some(InternetIdentityInit {
+            shards: Some(vec![ShardInfo {
+                canister_id: shard_canister(),
+                assigned_user_number_range: (15_000, 25_000),
+            }]),
+            ..InternetIdentityInit::default()
+        }),
+    );
+
+    expect_user_error_with_message(
+        result,
+        CanisterCalledTrap,
+        Regex::new("shard range \\[10000, 20000\\) overlaps with range \\[15000, 25000\\)")
+            .unwrap(),
+    );
+}
+
+fn shard_canister() -> Principal {
+    Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap()
+}
+
+fn shard_info() -> ShardInfo {
+    ShardInfo {
+        canister_id: shard_canister(),
+        assigned_user_number_range: SHARD_RANGE,
+    }
+}
+
+fn arg_with_shards() -> Option<InternetIdentityInit> {
+    Some(InternetIdentityInit {
+        assigned_user_number_range: Some(OWN_RANGE),
+        shards: Some(vec![shard_info()]),
+        ..InternetIdentityInit::default()
+    })
+}
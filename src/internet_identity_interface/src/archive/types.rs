@@ -1,11 +1,13 @@
 use crate::internet_identity::types::{
-    AnchorNumber, CredentialId, DeviceKey, DeviceProtection, KeyType, PublicKey, Purpose, Timestamp,
+    AnchorNumber, ArchiveIntegration, CredentialId, DeviceKey, DeviceProtection, KeyType,
+    PublicKey, Purpose, Timestamp,
 };
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::management_canister::main::CanisterStatusResponse;
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum Operation {
     #[serde(rename = "register_anchor")]
     RegisterAnchor { device: DeviceDataWithoutAlias },
@@ -23,9 +25,19 @@ pub enum Operation {
     },
     #[serde(rename = "remove_device")]
     RemoveDevice { device: PublicKey },
+    #[serde(rename = "reject_tentative_device")]
+    RejectTentativeDevice { device: DeviceDataWithoutAlias },
+    #[serde(rename = "import_anchor")]
+    ImportAnchor {
+        devices: Vec<DeviceDataWithoutAlias>,
+    },
+    #[serde(rename = "register_anchor_with_temp_key")]
+    RegisterAnchorWithTempKey,
+    #[serde(rename = "release_anchor")]
+    ReleaseAnchor,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Entry {
     // store anchor in LogEntry, such that anchor operations can be attributed to an anchor without consulting the index.
     pub anchor: AnchorNumber,
@@ -36,7 +48,7 @@ pub struct Entry {
     pub sequence_number: u64,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct DeviceDataWithoutAlias {
     pub pubkey: DeviceKey,
     pub credential_id: Option<CredentialId>,
@@ -48,7 +60,7 @@ pub struct DeviceDataWithoutAlias {
 
 // If present, the attribute has been changed to the value given.
 // Does not include the pubkey because it cannot be changed.
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct DeviceDataUpdate {
     pub alias: Option<Private>,
     pub credential_id: Option<CredentialId>,
@@ -59,7 +71,7 @@ pub struct DeviceDataUpdate {
 }
 
 // Placeholder for information that has been hidden for privacy reasons.
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum Private {
     #[serde(rename = "redacted")]
     Redacted,
@@ -96,6 +108,10 @@ pub struct ArchiveInit {
     pub max_entries_per_call: u16,
     pub polling_interval_ns: u64,
     pub error_buffer_limit: u16,
+    // If set to push, the archive does not poll II for new entries. Defaults to pull if not set.
+    pub archive_integration: Option<ArchiveIntegration>,
+    // Period after which entries are pruned (in nanoseconds). Entries are kept forever if not set.
+    pub retention_period_ns: Option<u64>,
 }
 
 /// Encoded entry as buffered on the II side (until acknowledged by the archive).
@@ -107,12 +123,52 @@ pub struct BufferedEntry {
     pub sequence_number: u64,
 }
 
+/// Format of the archive export served on `/export`.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, CandidType, Deserialize)]
+pub enum ExportFormat {
+    /// One JSON encoded entry per line.
+    #[serde(rename = "jsonl")]
+    JsonLines,
+    /// Sequence of CBOR encoded entries (RFC 8742).
+    #[serde(rename = "cbor")]
+    Cbor,
+}
+
+/// Streaming token of the archive export, used to fetch the next chunk of entries.
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+pub struct ExportToken {
+    /// Log index of the first entry of the next chunk.
+    pub next_index: u64,
+    /// Log index of the first entry not included in the export anymore.
+    pub end_index: u64,
+    pub format: ExportFormat,
+}
+
 /// Information about the archive canister (i.e. useful for debugging).
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct ArchiveStatus {
     pub call_info: CallInfo,
     pub init: ArchiveInit,
     pub canister_status: CanisterStatusResponse,
+    /// Entries created before this timestamp have been pruned (only set if a retention period is configured).
+    pub retention_cutoff: Option<Timestamp>,
+    /// Result of the last anchor index check (see `verify_and_rebuild_index`), if any.
+    pub index_check: Option<IndexCheckReport>,
+}
+
+/// Inconsistencies between the log and the anchor index found (and repaired) by an index check.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct IndexCheckReport {
+    /// Timestamp when the check was started.
+    pub started: Timestamp,
+    /// Timestamp when the check was completed. Not set while the check is in progress.
+    pub completed: Option<Timestamp>,
+    /// Number of log entries that could not be decoded and thus not be checked.
+    pub undecodable_entries: u64,
+    /// Number of log entries without index row. The missing rows have been added.
+    pub missing_index_rows: u64,
+    /// Number of index rows not referring to a matching log entry. These rows have been removed.
+    pub dangling_index_rows: u64,
 }
 
 /// Information about the calls the archive is making to II.
@@ -6,18 +6,21 @@ use serde_bytes::ByteBuf;
 
 pub type HeaderField = (String, String);
 
+/// Default streaming token for canisters that do not make use of response streaming.
+/// Canisters that do stream responses define their own token type and use it as the type parameter
+/// of [HttpResponse], [StreamingStrategy] and [StreamingCallbackHttpResponse].
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct Token {}
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub enum StreamingStrategy {
-    Callback { callback: Func, token: Token },
+pub enum StreamingStrategy<T = Token> {
+    Callback { callback: Func, token: T },
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct StreamingCallbackHttpResponse {
+pub struct StreamingCallbackHttpResponse<T = Token> {
     pub body: ByteBuf,
-    pub token: Option<Token>,
+    pub token: Option<T>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -29,10 +32,10 @@ pub struct HttpRequest {
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
-pub struct HttpResponse {
+pub struct HttpResponse<T = Token> {
     pub status_code: u16,
     pub headers: Vec<HeaderField>,
     pub body: ByteBuf,
     pub upgrade: Option<bool>,
-    pub streaming_strategy: Option<StreamingStrategy>,
+    pub streaming_strategy: Option<StreamingStrategy<T>>,
 }
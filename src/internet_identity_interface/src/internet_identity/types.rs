@@ -1,4 +1,5 @@
 use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
 use serde_bytes::ByteBuf;
 
 pub type AnchorNumber = u64;
@@ -38,7 +39,7 @@ pub struct DeviceWithUsage {
     pub last_usage: Option<Timestamp>,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum Purpose {
     #[serde(rename = "recovery")]
     Recovery,
@@ -46,6 +47,166 @@ pub enum Purpose {
     Authentication,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AddResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "too_many_devices")]
+    TooManyDevices { num_devices: u64, limit: u64 },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    #[serde(rename = "device_field_limit_exceeded")]
+    DeviceFieldLimitExceeded {
+        field: String,
+        length: u64,
+        limit: u64,
+    },
+    #[serde(rename = "invalid_device_protection")]
+    InvalidDeviceProtection { key_type: KeyType },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+    #[serde(rename = "duplicate_device")]
+    DuplicateDevice { device_key: DeviceKey },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum UpdateResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "not_found")]
+    NotFound { device_key: DeviceKey },
+    #[serde(rename = "cannot_modify_device_key")]
+    CannotModifyDeviceKey,
+    #[serde(rename = "mutation_not_allowed")]
+    MutationNotAllowed {
+        authorized_principal: Principal,
+        actual_principal: Principal,
+    },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    #[serde(rename = "device_field_limit_exceeded")]
+    DeviceFieldLimitExceeded {
+        field: String,
+        length: u64,
+        limit: u64,
+    },
+    #[serde(rename = "invalid_device_protection")]
+    InvalidDeviceProtection { key_type: KeyType },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ReplaceResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "not_found")]
+    NotFound { device_key: DeviceKey },
+    #[serde(rename = "mutation_not_allowed")]
+    MutationNotAllowed {
+        authorized_principal: Principal,
+        actual_principal: Principal,
+    },
+    #[serde(rename = "too_many_devices")]
+    TooManyDevices { num_devices: u64, limit: u64 },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    #[serde(rename = "device_field_limit_exceeded")]
+    DeviceFieldLimitExceeded {
+        field: String,
+        length: u64,
+        limit: u64,
+    },
+    #[serde(rename = "invalid_device_protection")]
+    InvalidDeviceProtection { key_type: KeyType },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+    #[serde(rename = "duplicate_device")]
+    DuplicateDevice { device_key: DeviceKey },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RemoveResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "not_found")]
+    NotFound { device_key: DeviceKey },
+    #[serde(rename = "mutation_not_allowed")]
+    MutationNotAllowed {
+        authorized_principal: Principal,
+        actual_principal: Principal,
+    },
+}
+
+/// Partial update of a device. Only the fields that are set are changed.
+/// Mirrors the `DeviceDataUpdate` of the archive, except that the alias is not redacted.
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DeviceDataPatch {
+    pub alias: Option<String>,
+    pub credential_id: Option<CredentialId>,
+    pub purpose: Option<Purpose>,
+    pub key_type: Option<KeyType>,
+    pub protection: Option<DeviceProtection>,
+    /// `Some(None)` removes the origin.
+    pub origin: Option<Option<String>>,
+}
+
+/// Operation on the devices of an anchor, see `apply_operations`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AnchorOp {
+    #[serde(rename = "add")]
+    Add { device_data: DeviceData },
+    #[serde(rename = "update")]
+    Update {
+        device_key: DeviceKey,
+        device_data: DeviceData,
+    },
+    #[serde(rename = "replace")]
+    Replace {
+        device_key: DeviceKey,
+        device_data: DeviceData,
+    },
+    #[serde(rename = "remove")]
+    Remove { device_key: DeviceKey },
+}
+
+/// Error of a single [AnchorOp].
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AnchorOpError {
+    #[serde(rename = "add")]
+    Add(AddResponse),
+    #[serde(rename = "update")]
+    Update(UpdateResponse),
+    #[serde(rename = "replace")]
+    Replace(ReplaceResponse),
+    #[serde(rename = "remove")]
+    Remove(RemoveResponse),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ApplyOperationsResponse {
+    #[serde(rename = "ok")]
+    Ok,
+    /// The operation at the given index failed. None of the operations have been applied.
+    #[serde(rename = "failed")]
+    Failed { index: u64, error: AnchorOpError },
+    /// The anchor resulting from applying all operations violates the anchor invariants. None of the
+    /// operations have been applied.
+    #[serde(rename = "invariant_violation")]
+    InvariantViolation(AnchorInvariantViolation),
+}
+
+/// Anchor invariant violated by the result of `apply_operations`.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AnchorInvariantViolation {
+    #[serde(rename = "too_many_devices")]
+    TooManyDevices { num_devices: u64, limit: u64 },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum RegisterResponse {
     #[serde(rename = "registered")]
@@ -54,9 +215,60 @@ pub enum RegisterResponse {
     CanisterFull,
     #[serde(rename = "bad_challenge")]
     BadChallenge,
+    /// This canister is full, new anchors must be registered with the given shard.
+    #[serde(rename = "redirect")]
+    Redirect(Principal),
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RegisterWithTempKeyResponse {
+    #[serde(rename = "registered")]
+    Registered {
+        user_number: AnchorNumber,
+        temp_key_expiration: Timestamp,
+    },
+    #[serde(rename = "canister_full")]
+    CanisterFull,
+    #[serde(rename = "bad_challenge")]
+    BadChallenge,
+    /// This canister is full, new anchors must be registered with the given shard.
+    #[serde(rename = "redirect")]
+    Redirect(Principal),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RegisterV2Response {
+    #[serde(rename = "registered")]
+    Registered { user_number: AnchorNumber },
+    #[serde(rename = "canister_full")]
+    CanisterFull,
+    #[serde(rename = "bad_challenge")]
+    BadChallenge,
+    /// This canister is full, new anchors must be registered with the given shard.
+    #[serde(rename = "redirect")]
+    Redirect(Principal),
+    /// The recovery device does not have the purpose `recovery`.
+    #[serde(rename = "invalid_recovery_device")]
+    InvalidRecoveryDevice,
+    #[serde(rename = "too_many_devices")]
+    TooManyDevices { num_devices: u64, limit: u64 },
+    #[serde(rename = "cumulative_data_limit_exceeded")]
+    CumulativeDataLimitExceeded { length: u64, limit: u64 },
+    #[serde(rename = "device_field_limit_exceeded")]
+    DeviceFieldLimitExceeded {
+        field: String,
+        length: u64,
+        limit: u64,
+    },
+    #[serde(rename = "invalid_device_protection")]
+    InvalidDeviceProtection { key_type: KeyType },
+    #[serde(rename = "multiple_recovery_phrases")]
+    MultipleRecoveryPhrases,
+    #[serde(rename = "duplicate_device")]
+    DuplicateDevice { device_key: DeviceKey },
+}
+
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum KeyType {
     #[serde(rename = "unknown")]
     Unknown,
@@ -68,7 +280,7 @@ pub enum KeyType {
     SeedPhrase,
 }
 
-#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum DeviceProtection {
     #[serde(rename = "protected")]
     Protected,
@@ -80,6 +292,18 @@ pub enum DeviceProtection {
 pub struct Challenge {
     pub png_base64: String,
     pub challenge_key: ChallengeKey,
+    /// WAV clip of the captcha, if an audio challenge was requested. Audio challenges come without
+    /// an image, i.e. `png_base64` is empty.
+    pub audio_wav_base64: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ChallengeKind {
+    #[serde(rename = "image")]
+    Image,
+    /// Audio captcha (restricted to digits) without an image. Cannot be requested anonymously.
+    #[serde(rename = "audio")]
+    Audio,
 }
 
 pub type ChallengeKey = String;
@@ -119,6 +343,11 @@ pub enum AddTentativeDeviceResponse {
         verification_code: DeviceVerificationCode,
         device_registration_timeout: Timestamp,
     },
+    #[serde(rename = "possession_challenge")]
+    PossessionChallenge {
+        nonce: ByteBuf,
+        device_registration_timeout: Timestamp,
+    },
     #[serde(rename = "device_registration_mode_off")]
     DeviceRegistrationModeOff,
     #[serde(rename = "another_device_tentatively_added")]
@@ -135,18 +364,61 @@ pub enum VerifyTentativeDeviceResponse {
     DeviceRegistrationModeOff,
     #[serde(rename = "no_device_to_verify")]
     NoDeviceToVerify,
+    #[serde(rename = "possession_not_proven")]
+    PossessionNotProven,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RejectTentativeDeviceResponse {
+    #[serde(rename = "rejected")]
+    Rejected,
+    #[serde(rename = "device_registration_mode_off")]
+    DeviceRegistrationModeOff,
+    #[serde(rename = "not_found")]
+    NotFound,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ProveDevicePossessionResponse {
+    #[serde(rename = "proven")]
+    Proven,
+    #[serde(rename = "wrong_nonce")]
+    WrongNonce,
+    #[serde(rename = "not_signed_by_tentative_device")]
+    NotSignedByTentativeDevice,
+    #[serde(rename = "device_registration_mode_off")]
+    DeviceRegistrationModeOff,
+    #[serde(rename = "no_device_to_verify")]
+    NoDeviceToVerify,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct DeviceRegistrationInfo {
     pub expiration: Timestamp,
     pub tentative_device: Option<DeviceData>,
+    pub tentative_devices: Option<Vec<DeviceData>>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub struct IdentityAnchorInfo {
     pub devices: Vec<DeviceWithUsage>,
     pub device_registration: Option<DeviceRegistrationInfo>,
+    /// Time at which the anchor was flagged as dormant, if it has not been acknowledged yet.
+    pub flagged_dormant: Option<Timestamp>,
+    pub recovery_status: Option<RecoveryStatus>,
+}
+
+/// Summary of the recovery setup of an anchor.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct RecoveryStatus {
+    pub has_recovery_phrase: bool,
+    pub has_recovery_device: bool,
+    /// Whether any recovery phrase has ever been used to authenticate.
+    pub recovery_phrase_used: bool,
+    /// Whether any recovery device has ever been used to authenticate.
+    pub recovery_device_used: bool,
+    /// Whether all authentication devices (at least one) were registered on the same origin.
+    pub authentication_devices_share_origin: bool,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
@@ -162,6 +434,24 @@ pub struct AnchorCredentials {
     pub recovery_phrases: Vec<PublicKey>,
 }
 
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum LookupResponse {
+    #[serde(rename = "ok")]
+    Ok(Vec<DeviceData>),
+    /// The anchor is served by the given shard.
+    #[serde(rename = "redirect")]
+    Redirect(Principal),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum GetAnchorCredentialsResponse {
+    #[serde(rename = "ok")]
+    Ok(AnchorCredentials),
+    /// The anchor is served by the given shard.
+    #[serde(rename = "redirect")]
+    Redirect(Principal),
+}
+
 #[derive(Clone, Debug, CandidType, Deserialize, Default)]
 pub struct InternetIdentityInit {
     pub assigned_user_number_range: Option<(AnchorNumber, AnchorNumber)>,
@@ -169,6 +459,14 @@ pub struct InternetIdentityInit {
     pub canister_creation_cycles_cost: Option<u64>,
     pub register_rate_limit: Option<RateLimitConfig>,
     pub max_num_latest_delegation_origins: Option<u64>,
+    pub controllers: Option<Vec<Principal>>,
+    pub shards: Option<Vec<ShardInfo>>,
+    pub anchor_limits: Option<AnchorLimits>,
+    pub dormancy_policy: Option<DormancyPolicy>,
+    pub device_registration: Option<DeviceRegistrationConfig>,
+    pub rate_limits: Option<RateLimits>,
+    pub anchor_allocation: Option<AnchorAllocationStrategy>,
+    pub adaptive_captcha: Option<bool>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
@@ -182,6 +480,7 @@ pub struct InternetIdentityStats {
     pub domain_active_anchor_stats: Option<ActiveAnchorStatistics<DomainActiveAnchorCounter>>,
     pub max_num_latest_delegation_origins: u64,
     pub latest_delegation_origins: Vec<FrontendHostname>,
+    pub shards: Option<Vec<ShardInfo>>,
 }
 
 /// Information about the archive.
@@ -213,6 +512,36 @@ pub struct ArchiveConfig {
     pub polling_interval_ns: u64,
     // Max number of archive entries to be fetched in a single call.
     pub entries_fetch_limit: u16,
+    // How the entries are transferred from II to the archive. Defaults to pull if not set.
+    pub archive_integration: Option<ArchiveIntegration>,
+    // What happens if the buffered archive entries limit is reached. Defaults to trap if not set.
+    pub buffer_overflow_policy: Option<BufferOverflowPolicy>,
+    // Period after which archived entries are pruned by the archive (in nanoseconds).
+    // Entries are kept forever if not set.
+    pub retention_period_ns: Option<u64>,
+}
+
+/// Mode of transferring the buffered archive entries from II to the archive.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ArchiveIntegration {
+    /// The archive periodically fetches (and acknowledges) the entries from II.
+    #[serde(rename = "pull")]
+    Pull,
+    /// II periodically sends the entries to the archive.
+    #[serde(rename = "push")]
+    Push,
+}
+
+/// Behaviour of II if the buffered archive entries limit is reached.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum BufferOverflowPolicy {
+    /// Refuse (i.e. trap) any further anchor operations until entries have been archived.
+    #[serde(rename = "trap")]
+    Trap,
+    /// Keep accepting anchor operations and spill the entries to a bounded region in stable
+    /// memory. Anchor operations are only refused if that region is full as well.
+    #[serde(rename = "spill_to_stable_memory")]
+    SpillToStableMemory,
 }
 
 #[derive(Clone, CandidType, Deserialize, Eq, PartialEq, Debug)]
@@ -262,3 +591,205 @@ pub enum DeployArchiveResult {
     #[serde(rename = "failed")]
     Failed(String),
 }
+
+/// Anchor as stored in stable memory, used to transfer anchors between II canisters.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ExportedAnchor {
+    pub anchor_number: AnchorNumber,
+    /// Candid encoded anchor (including the device usage timestamps).
+    pub anchor: ByteBuf,
+    /// SHA-256 hash of `anchor`.
+    pub sha256: ByteBuf,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorExportBatch {
+    pub anchors: Vec<ExportedAnchor>,
+    /// Cursor to fetch the next batch with, if there are more anchors to export.
+    pub next_cursor: Option<AnchorNumber>,
+}
+
+/// Sibling II canister serving a separate range of anchors.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct ShardInfo {
+    pub canister_id: Principal,
+    pub assigned_user_number_range: (AnchorNumber, AnchorNumber),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum RouteResponse {
+    /// The anchor number belongs to the range of this canister.
+    #[serde(rename = "local")]
+    Local,
+    /// The anchor number belongs to the range of the given shard.
+    #[serde(rename = "shard")]
+    Shard(Principal),
+    /// The anchor number does not belong to any known range.
+    #[serde(rename = "unknown")]
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ViolationPolicy {
+    /// Only report anchors with the violation.
+    #[serde(rename = "report")]
+    Report,
+    /// Report and repair anchors with the violation.
+    #[serde(rename = "repair")]
+    Repair,
+}
+
+/// Policy of an anchor repair sweep per type of invariant violation. Only violations that can be
+/// repaired without removing devices have a policy, all other violations (e.g. multiple recovery
+/// phrases) are only reported.
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorRepairPolicy {
+    /// Protected devices that are not recovery phrases. Repaired by removing the protection.
+    pub invalid_device_protection: ViolationPolicy,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorRepairFinding {
+    pub anchor_number: AnchorNumber,
+    /// Invariant violations found on the anchor (before repairing it).
+    pub violations: Vec<String>,
+    /// Whether changes have been made to the anchor.
+    pub repaired: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorRepairStatus {
+    pub policy: AnchorRepairPolicy,
+    /// Controller that started the sweep. Repairs are archived with this principal as the caller.
+    pub initiated_by: Principal,
+    pub started: Timestamp,
+    pub completed: Option<Timestamp>,
+    /// Anchor number the sweep continues from.
+    pub next_anchor_number: AnchorNumber,
+    pub anchors_checked: u64,
+    pub anchors_with_violations: u64,
+    /// Findings (limited to the first 1000 non-conforming anchors).
+    pub findings: Vec<AnchorRepairFinding>,
+}
+
+/// Limits on the number and size of devices of a single anchor.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorLimits {
+    pub max_devices: u64,
+    /// Limit on the sum of the sizes of the variable length fields (alias, pubkey, credential_id, origin) of all devices.
+    pub variable_fields_limit: u64,
+}
+
+/// Anchors without activity for longer than `dormant_after_ns` are flagged as dormant by the
+/// inactivity scan.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DormancyPolicy {
+    pub dormant_after_ns: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct InactivityBucket {
+    /// Lower bound (inclusive) of the inactivity of the anchors in this bucket.
+    pub inactive_for_days: u64,
+    pub anchors: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct InactivityReport {
+    /// Time the scan was started, inactivity is measured relative to this time.
+    pub started: Timestamp,
+    pub completed: Option<Timestamp>,
+    /// Anchor number the scan continues from.
+    pub next_anchor_number: AnchorNumber,
+    /// Anchor counts by inactivity, ordered by increasing inactivity.
+    pub buckets: Vec<InactivityBucket>,
+    /// Number of anchors without any recorded activity.
+    pub unknown_activity: u64,
+    /// Number of anchors flagged as dormant by this scan.
+    pub flagged_dormant: u64,
+}
+
+/// Configuration of the remote device registration flow (`add_tentative_device`).
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct DeviceRegistrationConfig {
+    pub verification_code_length: u8,
+    /// Characters the verification code is made of.
+    pub verification_code_alphabet: String,
+    pub max_verification_attempts: u8,
+    /// Maximum number of devices that can be added tentatively at the same time.
+    pub max_tentative_devices: u8,
+    pub registration_mode_duration_ns: u64,
+    /// If true, the new device proves possession of its key by signing a nonce (see
+    /// `prove_tentative_device_possession`) instead of the user copying a verification code.
+    pub possession_proof: bool,
+}
+
+/// Per-anchor (and per-caller) rate limits. Rate limits that are not set are disabled (except for
+/// `audio_challenge` and `audio_challenge_total`).
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Eq, PartialEq)]
+pub struct RateLimits {
+    pub prepare_delegation: Option<RateLimitConfig>,
+    /// Calls modifying the devices of an anchor (e.g. `add`, `update`, `remove`).
+    pub device_mutation: Option<RateLimitConfig>,
+    pub add_tentative_device: Option<RateLimitConfig>,
+    /// Challenges created per (non-anonymous) caller. Anonymous calls are not rate limited, as they
+    /// cannot be attributed to a user.
+    pub create_challenge: Option<RateLimitConfig>,
+    /// Audio challenges created per caller. A default applies if not set.
+    pub audio_challenge: Option<RateLimitConfig>,
+    /// Audio challenges created by all callers together. Audio captchas are easier to solve
+    /// automatically than image captchas, so a default applies if not set.
+    pub audio_challenge_total: Option<RateLimitConfig>,
+}
+
+/// Strategy to pick the anchor number of newly registered anchors.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum AnchorAllocationStrategy {
+    /// Anchor numbers are handed out in order.
+    #[serde(rename = "sequential")]
+    Sequential,
+    /// Anchor numbers are picked at random among the free anchor numbers below the next
+    /// `window_size` sequential anchor numbers.
+    #[serde(rename = "randomized")]
+    Randomized { window_size: u64 },
+}
+
+/// Anchor number set aside by a controller that can only be claimed by the given device.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct AnchorReservation {
+    pub anchor_number: AnchorNumber,
+    pub device_key: DeviceKey,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum ClaimReservedAnchorResponse {
+    #[serde(rename = "claimed")]
+    Claimed { user_number: AnchorNumber },
+    /// The anchor number is not reserved for the given device.
+    #[serde(rename = "not_reserved")]
+    NotReserved,
+}
+
+/// Security-relevant event on an anchor, shown to all devices of the anchor.
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub struct SecurityEvent {
+    pub timestamp: Timestamp,
+    pub kind: SecurityEventKind,
+    /// Purpose and key type of the device that caused the event (for `registration_mode_entered` and
+    /// `recovery_used`) or of the device that was added or removed.
+    pub purpose: Purpose,
+    pub key_type: KeyType,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Eq, PartialEq)]
+pub enum SecurityEventKind {
+    #[serde(rename = "registration_mode_entered")]
+    RegistrationModeEntered,
+    #[serde(rename = "device_added")]
+    DeviceAdded,
+    #[serde(rename = "device_removed")]
+    DeviceRemoved,
+    /// A recovery device was used to authenticate.
+    #[serde(rename = "recovery_used")]
+    RecoveryUsed,
+}